@@ -0,0 +1,345 @@
+//! PostScript calculator functions
+//!
+//! Evaluates the PostScript subset allowed in Type 4 function streams
+//! (ISO 32000-1, 7.10.5): arithmetic, relational, boolean and stack
+//! operators, plus `if` and `ifelse` over `{ ... }` procedures.
+
+/// Operand stack entry; the calculator language only has numbers and booleans
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Number(f64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Push(Value),
+    Operator(&'static str),
+    If(Vec<Op>),
+    IfElse(Vec<Op>, Vec<Op>),
+}
+
+const OPERATORS: &[&str] = &[
+    "abs", "add", "atan", "ceiling", "cos", "cvi", "cvr", "div", "exp", "floor", "idiv", "ln",
+    "log", "mod", "mul", "neg", "round", "sin", "sqrt", "sub", "truncate", "and", "bitshift",
+    "eq", "ge", "gt", "le", "lt", "ne", "not", "or", "xor", "copy", "dup", "exch", "index", "pop",
+    "roll",
+];
+
+/// Operand stack limit, from the specification's implementation limits
+const MAX_STACK: usize = 100;
+
+/// A parsed Type 4 function program
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Calculator {
+    program: Vec<Op>,
+}
+
+impl Calculator {
+    /// Parse a program of the form `{ ... }`
+    ///
+    /// Returns None on syntax errors and operators outside the calculator subset.
+    pub(crate) fn parse(source: &[u8]) -> Option<Self> {
+        let mut words = split_words(source).into_iter();
+        if words.next()? != "{" {
+            return None;
+        }
+        let program = parse_procedure(&mut words)?;
+        Some(Calculator { program })
+    }
+
+    /// Run the program with `inputs` on the stack, returning the stack bottom to top
+    ///
+    /// Returns None if the program fails (stack underflow, a type mismatch,
+    /// division by zero) or leaves a boolean on the stack.
+    pub(crate) fn eval(&self, inputs: &[f32]) -> Option<Vec<f32>> {
+        let mut stack: Vec<Value> = inputs.iter().map(|&v| Value::Number(v as f64)).collect();
+        run(&self.program, &mut stack)?;
+        stack
+            .into_iter()
+            .map(|value| match value {
+                Value::Number(n) => Some(n as f32),
+                Value::Bool(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Split a program into braces and whitespace-separated words, dropping comments
+fn split_words(source: &[u8]) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_comment = false;
+    for &b in source {
+        if in_comment {
+            in_comment = !matches!(b, b'\n' | b'\r');
+            continue;
+        }
+        let ends_word = b.is_ascii_whitespace() || matches!(b, b'\0' | b'{' | b'}' | b'%');
+        if ends_word && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        match b {
+            b'{' => words.push("{".to_string()),
+            b'}' => words.push("}".to_string()),
+            b'%' => in_comment = true,
+            _ if ends_word => {}
+            _ => word.push(b as char),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Parse the body of a procedure whose `{` has been consumed, up to its `}`
+fn parse_procedure(words: &mut impl Iterator<Item = String>) -> Option<Vec<Op>> {
+    let mut ops = Vec::new();
+    while let Some(word) = words.next() {
+        let op = match word.as_str() {
+            "}" => return Some(ops),
+            // Procedures only appear as operands of `if` and `ifelse`
+            "{" => {
+                let first = parse_procedure(words)?;
+                match words.next()?.as_str() {
+                    "if" => Op::If(first),
+                    "{" => {
+                        let second = parse_procedure(words)?;
+                        if words.next()? != "ifelse" {
+                            return None;
+                        }
+                        Op::IfElse(first, second)
+                    }
+                    _ => return None,
+                }
+            }
+            "true" => Op::Push(Value::Bool(true)),
+            "false" => Op::Push(Value::Bool(false)),
+            word if word.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c)) => {
+                Op::Push(Value::Number(word.parse().ok()?))
+            }
+            word => Op::Operator(OPERATORS.iter().find(|&&name| name == word)?),
+        };
+        ops.push(op);
+    }
+    // Missing the closing brace
+    None
+}
+
+fn run(program: &[Op], stack: &mut Vec<Value>) -> Option<()> {
+    for op in program {
+        match op {
+            Op::Push(value) => stack.push(*value),
+            Op::If(procedure) => {
+                if pop_bool(stack)? {
+                    run(procedure, stack)?;
+                }
+            }
+            Op::IfElse(if_true, if_false) => {
+                let procedure = if pop_bool(stack)? { if_true } else { if_false };
+                run(procedure, stack)?;
+            }
+            Op::Operator(name) => apply(name, stack)?,
+        }
+        if stack.len() > MAX_STACK {
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn pop_bool(stack: &mut Vec<Value>) -> Option<bool> {
+    match stack.pop()? {
+        Value::Bool(b) => Some(b),
+        Value::Number(_) => None,
+    }
+}
+
+fn pop_number(stack: &mut Vec<Value>) -> Option<f64> {
+    match stack.pop()? {
+        Value::Number(n) => Some(n),
+        Value::Bool(_) => None,
+    }
+}
+
+/// Operands of integer operators; reals are truncated
+fn pop_int(stack: &mut Vec<Value>) -> Option<i64> {
+    pop_number(stack).map(|n| n as i64)
+}
+
+fn apply(name: &str, stack: &mut Vec<Value>) -> Option<()> {
+    let result = match name {
+        "abs" | "ceiling" | "cos" | "cvi" | "cvr" | "floor" | "ln" | "log" | "neg" | "round"
+        | "sin" | "sqrt" | "truncate" => {
+            let x = pop_number(stack)?;
+            Value::Number(match name {
+                "abs" => x.abs(),
+                "ceiling" => x.ceil(),
+                // Angles are in degrees
+                "cos" => x.to_radians().cos(),
+                "cvi" | "truncate" => x.trunc(),
+                "cvr" => x,
+                "floor" => x.floor(),
+                "ln" => x.ln(),
+                "log" => x.log10(),
+                "neg" => -x,
+                // Halves round up, as in PostScript
+                "round" => (x + 0.5).floor(),
+                "sin" => x.to_radians().sin(),
+                _ => x.sqrt(),
+            })
+        }
+        "add" | "sub" | "mul" | "div" | "atan" | "exp" => {
+            let b = pop_number(stack)?;
+            let a = pop_number(stack)?;
+            Value::Number(match name {
+                "add" => a + b,
+                "sub" => a - b,
+                "mul" => a * b,
+                "div" if b == 0.0 => return None,
+                "div" => a / b,
+                // `num den atan` gives degrees in 0..360
+                "atan" => a.atan2(b).to_degrees().rem_euclid(360.0),
+                _ => a.powf(b),
+            })
+        }
+        "idiv" | "mod" => {
+            let b = pop_int(stack)?;
+            let a = pop_int(stack)?;
+            let result = if name == "idiv" { a.checked_div(b)? } else { a.checked_rem(b)? };
+            Value::Number(result as f64)
+        }
+        "eq" | "ne" => {
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            Value::Bool((a == b) == (name == "eq"))
+        }
+        "ge" | "gt" | "le" | "lt" => {
+            let b = pop_number(stack)?;
+            let a = pop_number(stack)?;
+            Value::Bool(match name {
+                "ge" => a >= b,
+                "gt" => a > b,
+                "le" => a <= b,
+                _ => a < b,
+            })
+        }
+        // Logical on booleans, bitwise on integers
+        "and" | "or" | "xor" => match (stack.pop()?, stack.pop()?) {
+            (Value::Bool(b), Value::Bool(a)) => Value::Bool(match name {
+                "and" => a & b,
+                "or" => a | b,
+                _ => a ^ b,
+            }),
+            (Value::Number(b), Value::Number(a)) => {
+                let (a, b) = (a as i64, b as i64);
+                Value::Number(match name {
+                    "and" => a & b,
+                    "or" => a | b,
+                    _ => a ^ b,
+                } as f64)
+            }
+            _ => return None,
+        },
+        "not" => match stack.pop()? {
+            Value::Bool(b) => Value::Bool(!b),
+            Value::Number(n) => Value::Number(!(n as i64) as f64),
+        },
+        "bitshift" => {
+            let shift = pop_int(stack)?;
+            let value = pop_int(stack)?;
+            let shifted = if shift >= 0 {
+                value.checked_shl(shift as u32).unwrap_or(0)
+            } else {
+                value.checked_shr(shift.unsigned_abs() as u32).unwrap_or(0)
+            };
+            Value::Number(shifted as f64)
+        }
+        "copy" => {
+            let n = usize::try_from(pop_int(stack)?).ok()?;
+            let start = stack.len().checked_sub(n)?;
+            stack.extend_from_within(start..);
+            return Some(());
+        }
+        "dup" => *stack.last()?,
+        "exch" => {
+            let len = stack.len();
+            if len < 2 {
+                return None;
+            }
+            stack.swap(len - 1, len - 2);
+            return Some(());
+        }
+        "index" => {
+            let n = usize::try_from(pop_int(stack)?).ok()?;
+            *stack.iter().rev().nth(n)?
+        }
+        "pop" => {
+            stack.pop()?;
+            return Some(());
+        }
+        "roll" => {
+            let j = pop_int(stack)?;
+            let n = usize::try_from(pop_int(stack)?).ok()?;
+            let start = stack.len().checked_sub(n)?;
+            if n > 0 {
+                // Positive j moves elements up, toward the top of the stack
+                stack[start..].rotate_right(j.rem_euclid(n as i64) as usize);
+            }
+            return Some(());
+        }
+        _ => return None,
+    };
+    stack.push(result);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, inputs: &[f32]) -> Option<Vec<f32>> {
+        Calculator::parse(source.as_bytes())?.eval(inputs)
+    }
+
+    #[test]
+    fn devicen_to_cmyk_program_maps_each_input() {
+        // Two spot inks mixed into CMYK: cyan, magenta, no yellow, black at half the second ink
+        let program = "{ dup 0.5 mul 0 exch }";
+        assert_eq!(eval(program, &[0.4, 1.0]), Some(vec![0.4, 1.0, 0.0, 0.5]));
+    }
+
+    #[test]
+    fn conditionals_pick_a_branch() {
+        let program = "{ dup 0.5 gt { 1 } { 0 } ifelse exch 0 eq { 7 add } if }";
+        assert_eq!(eval(program, &[0.75]), Some(vec![1.0]));
+        assert_eq!(eval(program, &[0.0]), Some(vec![7.0]));
+        assert_eq!(eval(program, &[0.25]), Some(vec![0.0]));
+    }
+
+    #[test]
+    fn comments_and_tight_braces_parse() {
+        assert_eq!(eval("{% invert\n1 exch sub}", &[0.25]), Some(vec![0.75]));
+        assert_eq!(eval("{dup 0.5 lt{1 exch sub}if}", &[0.25]), Some(vec![0.75]));
+    }
+
+    #[test]
+    fn runtime_errors_and_unknown_operators_fail() {
+        assert_eq!(eval("{ 0 div }", &[1.0]), None);
+        assert_eq!(eval("{ pop pop }", &[1.0]), None);
+        assert_eq!(eval("{ 0 gt }", &[1.0]), None);
+        assert_eq!(Calculator::parse(b"{ 1 moveto }"), None);
+        assert_eq!(Calculator::parse(b"{ 1 2 add"), None);
+    }
+
+    #[test]
+    fn integer_and_trig_operators_follow_postscript() {
+        assert_eq!(
+            eval("{ 7 2 idiv -7 2 mod 1 3 bitshift 0 1 atan 90 sin }", &[]),
+            Some(vec![3.0, -1.0, 8.0, 0.0, 1.0])
+        );
+        assert_eq!(eval("{ 1 2 3 3 1 roll }", &[]), Some(vec![3.0, 1.0, 2.0]));
+        assert_eq!(eval("{ 1 2 3 2 index -2.5 round }", &[]), Some(vec![1.0, 2.0, 3.0, 1.0, -2.0]));
+    }
+}
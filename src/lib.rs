@@ -8,23 +8,139 @@
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
 
+mod calculator;
+mod tokenizer;
+
+/// Re-exported so callers of [`resample_document`] use the same lopdf version
+pub use lopdf;
+/// Re-exported so callers of [`encode_image_for_pdf`] use the same image version
+pub use image;
+
 use flate2::read::ZlibDecoder;
 use image::{DynamicImage, ImageFormat, RgbImage};
 use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Read;
+use calculator::Calculator;
+use tokenizer::{Lexer, Token};
 
 /// Options for PDF resampling
 #[derive(Debug, Clone)]
 pub struct ResampleOptions {
     /// Target DPI for images (based on display dimensions)
     pub target_dpi: f32,
+    /// Target DPI for grayscale images (DeviceGray, CalGray or one-component
+    /// ICCBased), which are often scanned text that blurs at photo resolutions;
+    /// `target_dpi` when None
+    pub gray_target_dpi: Option<f32>,
+    /// Scale at which the output will be reproduced, e.g. 0.8 when printing at 80%;
+    /// display sizes are multiplied by it before DPI is computed
+    pub output_scale: f32,
     /// JPEG quality (1-100, only affects images without alpha)
     pub quality: u8,
     /// Minimum DPI threshold - only resample images above this DPI
     pub min_dpi: f32,
     /// Compress PDF streams (reduces file size)
     pub compress_streams: bool,
+    /// Re-encode already-JPEG images at target DPI when their estimated quality exceeds this value
+    pub recompress_jpeg_below_quality: Option<u8>,
+    /// Re-encode at `quality` even when no downsampling is needed: already-JPEG
+    /// or losslessly compressed (Flate, LZW, RunLength) images at target DPI
+    /// and images whose target size is not smaller keep their dimensions but
+    /// are still re-encoded
+    pub recompress_even_if_not_smaller: bool,
+    /// Also enlarge images drawn below the target DPI up to it (Lanczos3), for
+    /// workflows that need a minimum resolution; images whose display size is
+    /// unknown are never enlarged
+    pub allow_upscaling: bool,
+    /// When an image shrinks by more than this factor, first average it down in
+    /// boxes to about twice the target size and only then apply Lanczos3, which
+    /// is much faster and avoids ringing on large reductions (`None` always uses
+    /// Lanczos3 alone)
+    pub area_average_above_ratio: Option<f32>,
+    /// Downsample any image whose total pixel count exceeds this cap, regardless of DPI
+    pub max_pixels: Option<u32>,
+    /// Downsample any image whose longest side exceeds this many pixels, regardless of DPI
+    pub max_long_edge: Option<u32>,
+    /// How to treat images whose display size could not be determined
+    pub on_unknown_dpi: UnknownDpiPolicy,
+    /// Leave images untouched when their effective DPI exceeds this value, since such
+    /// an extreme estimate usually means the display size was computed wrongly
+    pub sanity_max_dpi: Option<f32>,
+    /// Skip images whose encoded stream is smaller than this many bytes
+    pub min_image_bytes: Option<usize>,
+    /// Skip images with fewer than this many pixels
+    pub min_image_pixels: Option<u32>,
+    /// Keep the original image unless the re-encoded one (with its soft mask) is
+    /// at least this fraction smaller, e.g. 0.1 for 10%; 0 keeps every re-encode,
+    /// even a larger one. Upscaled and color-converted images are always replaced
+    pub min_savings_ratio: f32,
+    /// Raise the JPEG quality of grayscale images (likely scanned text) to `gray_quality_floor`
+    pub adaptive_quality: bool,
+    /// Minimum JPEG quality for grayscale images when `adaptive_quality` is set
+    pub gray_quality_floor: u8,
+    /// Added to the JPEG quality of images that were already JPEG, offsetting the
+    /// artifacts a second lossy generation compounds (capped at 100)
+    pub jpeg_source_quality_bonus: u8,
+    /// Chroma subsampling used when encoding JPEGs
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Write progressive rather than baseline JPEGs, which viewers can display
+    /// incrementally; most handle progressive DCTDecode
+    pub progressive_jpeg: bool,
+    /// Compute Huffman tables for each JPEG instead of using the standard ones,
+    /// a second pass that typically saves a few percent losslessly. Builds with
+    /// the `mozjpeg` feature always do this
+    pub optimize_huffman: bool,
+    /// Tag re-encoded color images with a bundled sRGB ICC profile instead of
+    /// bare DeviceRGB, so color-managed viewers render them consistently
+    pub embed_srgb: bool,
+    /// Order objects so the first page and its resources come first in the output,
+    /// letting viewers render it sooner when the file is streamed
+    pub linearize: bool,
+    /// Password for encrypted PDFs; the output is written decrypted
+    pub password: Option<String>,
+    /// Pack non-stream objects into compressed object streams and write an xref stream
+    pub use_object_streams: bool,
+    /// PDF version written in the output header, e.g. "1.4" for older RIPs;
+    /// object streams need 1.5 and are not used below it (`None` keeps the
+    /// input's version)
+    pub output_version: Option<String>,
+    /// Give an image drawn both large and small a separate copy for its small
+    /// placements, so those can be downsampled further than the large one
+    pub split_multiuse_images: bool,
+    /// Keep the /Info dictionary and the catalog's XMP /Metadata stream;
+    /// when false both are removed from the output
+    pub preserve_metadata: bool,
+    /// What to do with the page thumbnail images (/Thumb) some producers embed
+    pub thumbnails: ThumbPolicy,
+    /// Remove image and soft mask objects that nothing reachable from the
+    /// trailer refers to anymore, such as masks left behind by re-encoding
+    pub gc: bool,
+    /// Store images whose pixels are all near-neutral as DeviceGray, for color
+    /// scans of black-and-white pages
+    pub detect_grayscale: bool,
+    /// Convert every image not already in this color space, re-encoding it even
+    /// when it needs no downsampling, e.g. to rid a print file of CMYK images;
+    /// None keeps each image's own space. Soft masks stay DeviceGray, as PDF
+    /// requires. Takes precedence over `detect_grayscale`
+    pub force_color_space: Option<ForcedCs>,
+    /// Replace images that belong only to optional content (layers) hidden in the
+    /// document's default configuration with an empty placeholder
+    pub drop_hidden_oc: bool,
+    /// Composite images that have transparency over this RGB background and
+    /// store them opaque, without a soft mask, for printers that mishandle
+    /// SMask; None keeps the transparency
+    pub flatten_alpha: Option<[u8; 3]>,
+    /// Stop following Form XObjects and tiling patterns nested deeper than this,
+    /// so a pathologically deep chain cannot overflow the stack; images below the
+    /// limit are treated as having no known display size
+    pub max_form_depth: usize,
+    /// Fail on any Flate stream that inflates past this many bytes, so a small
+    /// crafted stream cannot exhaust memory
+    pub max_decompressed_bytes: usize,
+    /// Record what happened to each image in [`ResampleResult::per_image`];
+    /// off by default so large files do not pay for the bookkeeping
+    pub collect_per_image: bool,
     /// Verbose output
     pub verbose: bool,
 }
@@ -33,20 +149,295 @@ impl Default for ResampleOptions {
     fn default() -> Self {
         Self {
             target_dpi: 150.0,
+            gray_target_dpi: None,
+            output_scale: 1.0,
             quality: 75,
             min_dpi: 0.0,
             compress_streams: true,
+            recompress_jpeg_below_quality: None,
+            recompress_even_if_not_smaller: false,
+            allow_upscaling: false,
+            area_average_above_ratio: Some(4.0),
+            max_pixels: None,
+            max_long_edge: None,
+            on_unknown_dpi: UnknownDpiPolicy::AssumeLow,
+            // Photos placed as small thumbnails legitimately reach several thousand DPI
+            sanity_max_dpi: Some(9600.0),
+            min_image_bytes: None,
+            min_image_pixels: None,
+            min_savings_ratio: 0.0,
+            adaptive_quality: false,
+            gray_quality_floor: 60,
+            jpeg_source_quality_bonus: 0,
+            chroma_subsampling: ChromaSubsampling::S420,
+            progressive_jpeg: false,
+            optimize_huffman: false,
+            embed_srgb: false,
+            linearize: false,
+            password: None,
+            use_object_streams: false,
+            output_version: None,
+            split_multiuse_images: false,
+            preserve_metadata: true,
+            thumbnails: ThumbPolicy::Keep,
+            gc: true,
+            detect_grayscale: false,
+            force_color_space: None,
+            drop_hidden_oc: false,
+            flatten_alpha: None,
+            max_form_depth: DEFAULT_MAX_FORM_DEPTH,
+            max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
+            collect_per_image: false,
             verbose: false,
         }
     }
 }
 
+/// Policy for images that were not found in any content stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownDpiPolicy {
+    /// Assume 72 DPI (1 pixel = 1 point), which usually leaves the image unresized
+    #[default]
+    AssumeLow,
+    /// Leave the image untouched and count it as skipped
+    Skip,
+    /// Assume the image is already at target DPI (re-encode without resizing)
+    AssumeTarget,
+}
+
+impl std::str::FromStr for UnknownDpiPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "assume-low" => Ok(UnknownDpiPolicy::AssumeLow),
+            "skip" => Ok(UnknownDpiPolicy::Skip),
+            "assume-target" => Ok(UnknownDpiPolicy::AssumeTarget),
+            other => Err(format!("Unknown DPI policy: {}", other)),
+        }
+    }
+}
+
+/// Handling of page thumbnail images (/Thumb)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThumbPolicy {
+    /// Leave thumbnails as they are
+    #[default]
+    Keep,
+    /// Delete them; viewers render their own page previews
+    Remove,
+    /// Render new thumbnails from the resampled pages (not supported yet; thumbnails are kept)
+    Regenerate,
+}
+
+impl std::str::FromStr for ThumbPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(ThumbPolicy::Keep),
+            "remove" => Ok(ThumbPolicy::Remove),
+            "regenerate" => Ok(ThumbPolicy::Regenerate),
+            other => Err(format!("Unknown thumbnail policy: {}", other)),
+        }
+    }
+}
+
+/// Chroma subsampling for JPEG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// 4:4:4 for small or nearly colorless images, 4:2:0 otherwise
+    Auto,
+    /// Full chroma resolution; keeps fine colored detail such as red text sharp
+    S444,
+    /// Chroma halved horizontally
+    S422,
+    /// Chroma halved in both directions (smallest output)
+    #[default]
+    S420,
+}
+
+impl std::str::FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ChromaSubsampling::Auto),
+            "444" | "4:4:4" => Ok(ChromaSubsampling::S444),
+            "422" | "4:2:2" => Ok(ChromaSubsampling::S422),
+            "420" | "4:2:0" => Ok(ChromaSubsampling::S420),
+            other => Err(format!("Unknown chroma subsampling: {}", other)),
+        }
+    }
+}
+
+/// Color space every image is converted to with `force_color_space`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcedCs {
+    /// DeviceRGB; gray and CMYK sources are converted
+    Rgb,
+    /// DeviceGray, by luminance
+    Gray,
+}
+
+impl ForcedCs {
+    /// Whether an image dictionary is already stored in this color space
+    ///
+    /// Stencil masks have no color space of their own and always match.
+    fn matches(self, doc: &Document, dict: &Dictionary) -> bool {
+        if matches!(dict.get(b"ImageMask"), Ok(Object::Boolean(true))) {
+            return true;
+        }
+        let color_space = dict
+            .get(b"ColorSpace")
+            .ok()
+            .map(|cs| get_color_space_name(cs, doc))
+            .unwrap_or_default();
+        match self {
+            ForcedCs::Rgb => color_space == "DeviceRGB",
+            ForcedCs::Gray => color_space == "DeviceGray",
+        }
+    }
+}
+
+impl std::str::FromStr for ForcedCs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" | "DeviceRGB" => Ok(ForcedCs::Rgb),
+            "gray" | "DeviceGray" => Ok(ForcedCs::Gray),
+            other => Err(format!("Unknown color space: {}", other)),
+        }
+    }
+}
+
 /// Result of PDF resampling operation
 #[derive(Debug, Clone)]
 pub struct ResampleResult {
     pub total_images: usize,
     pub resampled_images: usize,
     pub skipped_images: usize,
+    /// Images left unchanged because re-encoding failed
+    pub failed_images: usize,
+    /// Images in hidden layers replaced by an empty placeholder (`drop_hidden_oc`)
+    pub dropped_images: usize,
+    /// Why images were skipped; the counts add up to `skipped_images`
+    pub skip_reasons: HashMap<SkipReason, usize>,
+    /// One entry per image, ordered by object id; empty unless
+    /// `collect_per_image` is set
+    pub per_image: Vec<PerImageResult>,
+}
+
+/// What happened to one image, see [`ResampleOptions::collect_per_image`]
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PerImageResult {
+    pub object_id: ObjectId,
+    pub original_width: u32,
+    pub original_height: u32,
+    /// Size written to the output; the original size unless resampled
+    pub target_width: u32,
+    pub target_height: u32,
+    /// Encoded size of the image and its soft mask before; the mask also has
+    /// a record of its own, skipped as "soft_mask"
+    pub original_bytes: usize,
+    /// Encoded size of the image and its soft mask afterwards
+    pub new_bytes: usize,
+    /// "resampled", "reencoded" (same size, new encoding), "skipped",
+    /// "failed" or "dropped"
+    pub decision: &'static str,
+    /// Why the image is skipped, as in [`SkipReason::as_str`]
+    pub reason: Option<&'static str>,
+    /// Decoder or encoder message for an image skipped as "decode_failed" or "failed"
+    pub error: Option<String>,
+}
+
+/// Why an image was left untouched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    /// Width or height missing or zero
+    InvalidDimensions,
+    /// Below `min_image_bytes` or `min_image_pixels`
+    TooSmall,
+    /// Encoded with a filter that cannot be decoded, such as JBIG2
+    UnsupportedFilter,
+    /// Not drawn anywhere and `on_unknown_dpi` is `Skip`
+    NoDisplayInfo,
+    /// Effective DPI above `sanity_max_dpi`
+    ImplausibleDpi,
+    /// Already a JPEG, or losslessly compressed, at or below the target DPI
+    AlreadyAtTargetDpi,
+    /// Downsampling to the target DPI would not reduce its size
+    TargetNotSmaller,
+    /// The re-encoded image saved less than `min_savings_ratio`
+    InsufficientSavings,
+    /// Masked by a /Mask color key array, which lossy samples would no longer match
+    ColorKeyMask,
+    /// The pixel data could not be decoded
+    DecodeFailed,
+    /// A soft mask, handled together with the image that uses it
+    SoftMask,
+}
+
+impl SkipReason {
+    /// Stable snake_case name, used as the key in JSON output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::InvalidDimensions => "invalid_dimensions",
+            SkipReason::TooSmall => "too_small",
+            SkipReason::UnsupportedFilter => "unsupported_filter",
+            SkipReason::NoDisplayInfo => "no_display_info",
+            SkipReason::ImplausibleDpi => "implausible_dpi",
+            SkipReason::AlreadyAtTargetDpi => "already_at_target_dpi",
+            SkipReason::TargetNotSmaller => "target_not_smaller",
+            SkipReason::InsufficientSavings => "insufficient_savings",
+            SkipReason::ColorKeyMask => "color_key_mask",
+            SkipReason::DecodeFailed => "decode_failed",
+            SkipReason::SoftMask => "soft_mask",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Convert skip reason counts to a JSON object keyed by [`SkipReason::as_str`]
+pub fn skip_reasons_to_json(skip_reasons: &HashMap<SkipReason, usize>) -> serde_json::Value {
+    skip_reasons
+        .iter()
+        .map(|(reason, count)| (reason.as_str().to_string(), serde_json::json!(count)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Projected effect of resampling a PDF, computed without re-encoding any image
+///
+/// Re-encoded sizes are approximated from the target pixel count and JPEG
+/// quality rather than by encoding, so `projected_image_bytes` is a rough
+/// figure. For photographic content it is usually within about 40% of the
+/// real output per file, and closer when summed over many files. Flat
+/// graphics and screenshots compress far better than the model assumes and
+/// can be overestimated by up to 3x. Alpha channels written as separate
+/// SMasks are not included in the projection.
+#[derive(Debug, Clone, Default)]
+pub struct SavingsEstimate {
+    /// Total encoded size of all image streams in the input
+    pub current_image_bytes: usize,
+    /// Approximate total image size after resampling
+    pub projected_image_bytes: usize,
+    /// Images that would be resampled or re-encoded
+    pub resamplable_images: usize,
+}
+
+impl SavingsEstimate {
+    /// Approximate number of image bytes saved
+    pub fn saved_bytes(&self) -> usize {
+        self.current_image_bytes.saturating_sub(self.projected_image_bytes)
+    }
 }
 
 /// Information about a single image in the PDF
@@ -72,8 +463,29 @@ pub struct ImageInfo {
     pub dpi_x: Option<f32>,
     /// Effective DPI Y (if display info available)
     pub dpi_y: Option<f32>,
+    /// Why the image cannot be resampled, if its encoding is unsupported
+    pub skip_reason: Option<String>,
+    /// Displayed below the upscale threshold in either direction, i.e. stretched
+    /// so far that the source image itself is too small; resampling cannot help
+    pub is_upscaled: bool,
 }
 
+/// Deepest /Contents nesting (arrays and references) the scanner follows
+const MAX_CONTENTS_NESTING: usize = 8;
+
+/// Default for [`ResampleOptions::max_form_depth`]
+pub const DEFAULT_MAX_FORM_DEPTH: usize = 32;
+
+/// Most Form XObject and pattern uses scanned per document; forms that each
+/// draw a nested form several times would otherwise multiply the work per level
+const MAX_FORM_SCANS: usize = 100_000;
+
+/// Default for [`ResampleOptions::max_decompressed_bytes`]
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 512 * 1024 * 1024;
+
+/// Effective DPI below which [`extract_pdf_images_info`] flags an image as upscaled
+pub const DEFAULT_UPSCALED_BELOW_DPI: f32 = 72.0;
+
 /// Images grouped by page
 #[derive(Debug, Clone)]
 pub struct PageImages {
@@ -81,6 +493,63 @@ pub struct PageImages {
     pub images: Vec<ImageInfo>,
 }
 
+/// Document-wide image statistics, see [`summarize_pdf_images`]
+///
+/// Each image counts once however many pages draw it; soft masks are counted
+/// separately and left out of every other figure.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentImageSummary {
+    /// Distinct images, soft masks excluded
+    pub image_count: usize,
+    /// Distinct soft mask images
+    pub smask_count: usize,
+    /// Images whose display size was found, so their DPI is known
+    pub images_with_dpi: usize,
+    /// Images never seen drawn by the content scanner
+    pub images_without_dpi: usize,
+    /// Lowest effective DPI (the larger of the X and Y DPI of an image)
+    pub min_dpi: Option<f32>,
+    /// Highest effective DPI
+    pub max_dpi: Option<f32>,
+    /// Median effective DPI
+    pub median_dpi: Option<f32>,
+    /// Encoded size of all images and soft masks
+    pub total_image_bytes: usize,
+    /// Image count per color space name
+    pub by_color_space: BTreeMap<String, usize>,
+    /// Image count per filter name
+    pub by_filter: BTreeMap<String, usize>,
+}
+
+/// Result of [`validate_pdf`]
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationReport {
+    pub page_count: usize,
+    /// Every image XObject in the file, soft masks included, in object order
+    pub images: Vec<ImageValidation>,
+    /// Images with `supported == false`
+    pub unsupported_count: usize,
+}
+
+/// Whether one image could be decoded for resampling, judged from its dictionary
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageValidation {
+    pub object_id: (u32, u16),
+    /// "image" or "smask"
+    pub image_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub color_space: String,
+    pub bits_per_component: u32,
+    pub filter: String,
+    pub supported: bool,
+    /// Why the image will be left untouched, when it is not supported
+    pub reason: Option<String>,
+}
+
 /// Error type for PDF resampling operations
 #[derive(Debug)]
 pub enum ResampleError {
@@ -88,6 +557,10 @@ pub enum ResampleError {
     LoadError(String),
     SaveError(String),
     ProcessingError(String),
+    /// Failed to decode or re-encode a specific image
+    DecodeError { object_id: ObjectId, reason: String },
+    /// The caller's cancellation check asked to stop
+    Cancelled,
 }
 
 impl std::fmt::Display for ResampleError {
@@ -97,6 +570,10 @@ impl std::fmt::Display for ResampleError {
             ResampleError::LoadError(msg) => write!(f, "Failed to load PDF: {}", msg),
             ResampleError::SaveError(msg) => write!(f, "Failed to save PDF: {}", msg),
             ResampleError::ProcessingError(msg) => write!(f, "Processing error: {}", msg),
+            ResampleError::DecodeError { object_id, reason } => {
+                write!(f, "Image {} {}: {}", object_id.0, object_id.1, reason)
+            }
+            ResampleError::Cancelled => write!(f, "Resampling was cancelled"),
         }
     }
 }
@@ -150,6 +627,18 @@ impl ImageDisplayInfo {
 
         (target_width.max(1), target_height.max(1))
     }
+
+    /// The same image reproduced at `scale` times its display size
+    ///
+    /// Scales that are not positive leave the size unchanged.
+    pub fn scaled(&self, scale: f32) -> Self {
+        let scale = if scale > 0.0 { scale } else { 1.0 };
+        ImageDisplayInfo {
+            display_width_points: self.display_width_points * scale,
+            display_height_points: self.display_height_points * scale,
+            ..self.clone()
+        }
+    }
 }
 
 /// 2D transformation matrix [a, b, c, d, e, f]
@@ -178,7 +667,19 @@ impl Matrix {
         }
     }
 
-    /// Concatenate another matrix: self * other
+    /// Uniform scaling matrix
+    fn scale(s: f32) -> Self {
+        Matrix {
+            a: s,
+            d: s,
+            ..Matrix::identity()
+        }
+    }
+
+    /// Concatenate another matrix: self * other, i.e. apply `self`, then `other`
+    ///
+    /// PDF pre-multiplies: `cm` sets CTM' = M × CTM, and a form or pattern
+    /// draws in /Matrix × CTM, so callers write `new.concat(&ctm)`.
     fn concat(&self, other: &Matrix) -> Matrix {
         Matrix {
             a: self.a * other.a + self.b * other.c,
@@ -191,6 +692,9 @@ impl Matrix {
     }
 
     /// Get the scaling factors (approximate display size)
+    ///
+    /// These are lengths of the transformed unit vectors, so they stay positive
+    /// for mirrored matrices such as the common `1 0 0 -1 0 H` y-flip.
     fn scale_x(&self) -> f32 {
         (self.a * self.a + self.b * self.b).sqrt()
     }
@@ -198,10 +702,189 @@ impl Matrix {
     fn scale_y(&self) -> f32 {
         (self.c * self.c + self.d * self.d).sqrt()
     }
+
+    /// Inverse transformation, or None for a degenerate matrix
+    fn invert(&self) -> Option<Matrix> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+        Some(Matrix {
+            a: self.d / det,
+            b: -self.b / det,
+            c: -self.c / det,
+            d: self.a / det,
+            e: (self.c * self.f - self.d * self.e) / det,
+            f: (self.b * self.e - self.a * self.f) / det,
+        })
+    }
+
+    /// Transform a point
+    fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (x * self.a + y * self.c + self.e, x * self.b + y * self.d + self.f)
+    }
+}
+
+/// Axis-aligned rectangle
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl Rect {
+    /// The unit square an image is drawn into
+    fn unit() -> Self {
+        Rect { x0: 0.0, y0: 0.0, x1: 1.0, y1: 1.0 }
+    }
+
+    /// Parse a rectangle array such as /BBox, normalizing the corner order
+    fn from_array(obj: &Object) -> Option<Self> {
+        let Object::Array(arr) = obj else {
+            return None;
+        };
+        let mut values = arr.iter().map(|v| match v {
+            Object::Integer(n) => Some(*n as f32),
+            Object::Real(n) => Some(*n),
+            _ => None,
+        });
+        let mut next = || values.next().flatten();
+        let (x0, y0, x1, y1) = (next()?, next()?, next()?, next()?);
+        Some(Rect { x0: x0.min(x1), y0: y0.min(y1), x1: x0.max(x1), y1: y0.max(y1) })
+    }
+
+    /// Bounding box of this rectangle after transforming it by `m`
+    ///
+    /// All four corners are mapped and sorted, so flipped and rotated matrices
+    /// (negative determinant or components) still give x0 <= x1 and y0 <= y1.
+    fn transformed(&self, m: &Matrix) -> Rect {
+        let corners = [
+            m.apply(self.x0, self.y0),
+            m.apply(self.x1, self.y0),
+            m.apply(self.x0, self.y1),
+            m.apply(self.x1, self.y1),
+        ];
+        corners.iter().skip(1).fold(
+            Rect { x0: corners[0].0, y0: corners[0].1, x1: corners[0].0, y1: corners[0].1 },
+            |r, &(x, y)| Rect { x0: r.x0.min(x), y0: r.y0.min(y), x1: r.x1.max(x), y1: r.y1.max(y) },
+        )
+    }
+
+    /// Overlap of two rectangles; empty if they do not overlap
+    fn intersect(&self, other: &Rect) -> Rect {
+        Rect {
+            x0: self.x0.max(other.x0),
+            y0: self.y0.max(other.y0),
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.x1 <= self.x0 || self.y1 <= self.y0
+    }
+
+    fn contains(&self, other: &Rect) -> bool {
+        self.x0 <= other.x0 && self.y0 <= other.y0 && self.x1 >= other.x1 && self.y1 >= other.y1
+    }
+}
+
+/// Reason an image filter cannot be decoded, for filters that are recognized but unsupported
+fn unsupported_filter_reason(filter: &str) -> Option<&'static str> {
+    match filter {
+        "JBIG2Decode" => Some("JBIG2 (unsupported)"),
+        "CCITTFaxDecode" => Some("CCITT fax (unsupported)"),
+        #[cfg(not(feature = "jpeg2000"))]
+        "JPXDecode" => Some("JPEG2000 (needs the jpeg2000 feature)"),
+        _ => None,
+    }
+}
+
+/// Decode ASCIIHexDecode data; whitespace is ignored and `>` marks the end
+fn decode_ascii_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(data.len() / 2);
+    let mut high: Option<u8> = None;
+
+    for &b in data {
+        if b == b'>' {
+            break;
+        }
+        if b.is_ascii_whitespace() || b == b'\0' {
+            continue;
+        }
+        let value = match b {
+            b'0'..=b'9' => b - b'0',
+            b'a'..=b'f' => b - b'a' + 10,
+            b'A'..=b'F' => b - b'A' + 10,
+            _ => return Err(format!("Invalid ASCIIHex character: {:?}", b as char)),
+        };
+        match high.take() {
+            Some(h) => result.push((h << 4) | value),
+            None => high = Some(value),
+        }
+    }
+
+    // An odd final digit is treated as if followed by 0
+    if let Some(h) = high {
+        result.push(h << 4);
+    }
+
+    Ok(result)
+}
+
+/// Decode ASCII85Decode data; whitespace is ignored and `~>` marks the end
+fn decode_ascii85(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(data.len() * 4 / 5);
+    let mut group = [0u8; 5];
+    let mut count = 0;
+
+    for &b in data {
+        match b {
+            b'~' => break,
+            b'z' if count == 0 => result.extend_from_slice(&[0, 0, 0, 0]),
+            b'!'..=b'u' => {
+                group[count] = b - b'!';
+                count += 1;
+                if count == 5 {
+                    let value = group.iter().fold(0u64, |acc, &d| acc * 85 + d as u64);
+                    if value > u32::MAX as u64 {
+                        return Err("ASCII85 group out of range".to_string());
+                    }
+                    result.extend_from_slice(&(value as u32).to_be_bytes());
+                    count = 0;
+                }
+            }
+            _ if b.is_ascii_whitespace() || b == b'\0' => {}
+            _ => return Err(format!("Invalid ASCII85 character: {:?}", b as char)),
+        }
+    }
+
+    // A final partial group of n digits is padded with 'u' and yields n - 1 bytes
+    if count == 1 {
+        return Err("ASCII85 data ends with a single digit".to_string());
+    }
+    if count > 1 {
+        for digit in group.iter_mut().skip(count) {
+            *digit = 84;
+        }
+        let value = group.iter().fold(0u64, |acc, &d| acc * 85 + d as u64);
+        if value > u32::MAX as u64 {
+            return Err("ASCII85 group out of range".to_string());
+        }
+        result.extend_from_slice(&(value as u32).to_be_bytes()[..count - 1]);
+    }
+
+    Ok(result)
 }
 
 /// Decompress a stream's content
-fn decompress_stream(stream: &Stream) -> Vec<u8> {
+///
+/// Fails on filters other than Flate, ASCIIHex and ASCII85 and on data that
+/// does not decode, or inflates past `max_bytes`. A Flate stream that breaks
+/// off partway (a common kind of damage) yields what was decoded before the break.
+fn decompress_stream(stream: &Stream, max_bytes: usize) -> Result<Vec<u8>, String> {
     let filter = stream.dict.get(b"Filter").ok().and_then(|f| match f {
         Object::Name(n) => Some(vec![String::from_utf8_lossy(n).to_string()]),
         Object::Array(arr) => Some(
@@ -221,28 +904,227 @@ fn decompress_stream(stream: &Stream) -> Vec<u8> {
         for filter_name in filters {
             match filter_name.as_str() {
                 "FlateDecode" => {
-                    let mut decoder = ZlibDecoder::new(&data[..]);
                     let mut decoded = Vec::new();
-                    if decoder.read_to_end(&mut decoded).is_ok() {
-                        data = decoded;
-                    } else {
-                        return stream.content.clone();
+                    match inflate_into(&data, &mut decoded, max_bytes) {
+                        Err(InflateError::TooLarge(e)) => return Err(e),
+                        Err(InflateError::Corrupt(e)) if decoded.is_empty() => {
+                            return Err(format!("FlateDecode failed: {}", e));
+                        }
+                        _ => {}
                     }
+                    data = decoded;
+                }
+                "ASCIIHexDecode" | "AHx" => {
+                    data = decode_ascii_hex(&data)
+                        .map_err(|e| format!("ASCIIHexDecode failed: {}", e))?;
+                }
+                "ASCII85Decode" | "A85" => {
+                    data = decode_ascii85(&data)
+                        .map_err(|e| format!("ASCII85Decode failed: {}", e))?;
+                }
+                other => return Err(format!("Unsupported filter: {}", other)),
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+/// Why [`inflate_into`] stopped
+enum InflateError {
+    /// The output would exceed the size limit
+    TooLarge(String),
+    /// The data is not valid zlib; output decoded before the error is kept
+    Corrupt(std::io::Error),
+}
+
+/// Inflate zlib `data` onto `out`, refusing to produce more than `max_bytes`
+fn inflate_into(data: &[u8], out: &mut Vec<u8>, max_bytes: usize) -> Result<(), InflateError> {
+    // One byte past the limit is enough to tell that the limit was exceeded
+    let result = ZlibDecoder::new(data)
+        .take((max_bytes as u64).saturating_add(1))
+        .read_to_end(out);
+    if out.len() > max_bytes {
+        out.clear();
+        return Err(InflateError::TooLarge(format!(
+            "FlateDecode output exceeds the limit of {} bytes",
+            max_bytes
+        )));
+    }
+    result.map(|_| ()).map_err(InflateError::Corrupt)
+}
+
+/// Inflate zlib `data`, failing on corrupt data or output past `max_bytes`
+fn inflate(data: &[u8], max_bytes: usize) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    match inflate_into(data, &mut decoded, max_bytes) {
+        Ok(()) => Ok(decoded),
+        Err(InflateError::TooLarge(e)) => Err(e),
+        Err(InflateError::Corrupt(e)) => Err(e.to_string()),
+    }
+}
+
+/// Get the /DecodeParms of a stream's first filter, if given directly
+fn first_decode_parms(dict: &Dictionary) -> Option<&Dictionary> {
+    match dict.get(b"DecodeParms").ok()? {
+        Object::Dictionary(params) => Some(params),
+        Object::Array(arr) => match arr.first()? {
+            Object::Dictionary(params) => Some(params),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Reverse the TIFF (2) or PNG (10-15) predictor named in `params`
+///
+/// Image data with a predictor holds differences between neighboring samples
+/// (plus, for PNG, a filter type byte per row) rather than the samples
+/// themselves.
+fn undo_predictor(data: Vec<u8>, params: Option<&Dictionary>) -> Result<Vec<u8>, String> {
+    let Some(params) = params else {
+        return Ok(data);
+    };
+    let int = |key: &[u8], default: i64| match params.get(key) {
+        Ok(Object::Integer(n)) => *n,
+        _ => default,
+    };
+    let predictor = int(b"Predictor", 1);
+    if predictor == 1 {
+        return Ok(data);
+    }
+    let colors = int(b"Colors", 1).clamp(1, 32) as usize;
+    let bits_per_component = int(b"BitsPerComponent", 8).clamp(1, 16) as usize;
+    let columns = int(b"Columns", 1).max(1) as usize;
+    let bytes_per_pixel = (colors * bits_per_component).div_ceil(8);
+    let row_bytes = (colors * bits_per_component * columns).div_ceil(8);
+
+    match predictor {
+        2 => {
+            if bits_per_component != 8 {
+                return Err(format!(
+                    "Unsupported TIFF predictor: {} bits",
+                    bits_per_component
+                ));
+            }
+            let mut data = data;
+            for row in data.chunks_mut(row_bytes) {
+                for i in bytes_per_pixel..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - bytes_per_pixel]);
                 }
-                _ => {
-                    // Unknown filter, return as-is
-                    return data;
+            }
+            Ok(data)
+        }
+        10..=15 => {
+            // Each row names its own filter; the predictor value is only a hint
+            let mut out = Vec::with_capacity(data.len());
+            let mut previous = vec![0u8; row_bytes];
+            for chunk in data.chunks_exact(row_bytes + 1) {
+                let filter = chunk[0];
+                let mut row = chunk[1..].to_vec();
+                for i in 0..row_bytes {
+                    let left = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+                    let up = previous[i];
+                    let up_left = if i >= bytes_per_pixel { previous[i - bytes_per_pixel] } else { 0 };
+                    let prediction = match filter {
+                        0 => 0,
+                        1 => left,
+                        2 => up,
+                        3 => ((left as u16 + up as u16) / 2) as u8,
+                        4 => paeth(left, up, up_left),
+                        other => return Err(format!("Unknown PNG row filter: {}", other)),
+                    };
+                    row[i] = row[i].wrapping_add(prediction);
                 }
+                out.extend_from_slice(&row);
+                previous = row;
             }
+            Ok(out)
         }
+        other => Err(format!("Unsupported predictor: {}", other)),
     }
+}
+
+/// PNG Paeth predictor: whichever neighbor is closest to left + up - up_left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance = |v: u8| (estimate - v as i16).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Get the numeric value of a token
+fn token_number(token: &Token) -> Option<f32> {
+    match token {
+        Token::Number(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Get the name value of a token
+fn token_name(token: &Token) -> Option<&str> {
+    match token {
+        Token::Name(n) => Some(n.as_str()),
+        _ => None,
+    }
+}
+
+/// Callback used to route scanner log messages
+type LogCallback<'a> = Box<dyn Fn(&str) + 'a>;
+
+/// Emit a `tracing` event when the `tracing` feature is enabled
+///
+/// Unlike the verbose log, events are emitted regardless of `verbose`; the
+/// subscriber decides what to keep.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
 
-    data
+/// Every placement of an image found while scanning content streams
+#[derive(Debug, Clone)]
+pub struct ImageUsage {
+    /// Image object ID
+    pub object_id: ObjectId,
+    /// Native image dimensions in pixels (width, height)
+    pub pixel_dims: (u32, u32),
+    /// Display size of each placement in points (width, height), in scan order
+    pub placements: Vec<(f32, f32)>,
+}
+
+/// Where an image is drawn on a page
+#[derive(Debug, Clone)]
+pub struct ImagePlacement {
+    /// 1-based page number
+    pub page: u32,
+    /// Image object ID
+    pub object_id: ObjectId,
+    /// Lower-left corner of the drawn area in page space, in points
+    pub x: f32,
+    pub y: f32,
+    /// Extent of the drawn area in points; rotated or skewed images report
+    /// their axis-aligned bounding box
+    pub w: f32,
+    pub h: f32,
 }
 
-/// Parse a number from a token
-fn parse_number(token: &str) -> Option<f32> {
-    token.parse::<f32>().ok()
+/// A single drawing of an image, as recorded by the scanner
+struct Placement {
+    image: ObjectId,
+    /// Page or form/pattern whose resource dictionary maps the name used by `Do`
+    owner: ObjectId,
+    size: (f32, f32),
+    /// Page number the drawing was found on
+    page: u32,
+    /// Area covered on the page
+    bounds: Rect,
 }
 
 /// Context for scanning content streams
@@ -252,10 +1134,25 @@ struct ContentScanner<'a> {
     display_info: HashMap<ObjectId, Vec<(f32, f32)>>,
     /// Image dimensions cache (object ID -> pixel dimensions)
     image_dims: HashMap<ObjectId, (u32, u32)>,
-    /// Form XObjects that have been scanned (to avoid infinite loops)
-    scanned_forms: HashSet<ObjectId>,
+    /// Form XObject and pattern contents scanned so far, counted against [`MAX_FORM_SCANS`]
+    form_scans: usize,
+    /// Forms and patterns currently being scanned, outermost first; one that is
+    /// already on the stack invokes itself and is not entered again
+    form_stack: Vec<ObjectId>,
+    /// Page whose content is currently being scanned
+    current_page: Option<ObjectId>,
+    /// 1-based number of that page
+    current_page_number: u32,
+    /// Visible area left by the /BBox of the forms being scanned, in page space
+    clip: Option<Rect>,
+    /// Every image placement with the object whose resources named the image
+    placements: Vec<Placement>,
+    /// Deepest nesting of forms and patterns that is followed
+    max_form_depth: usize,
+    /// Largest output allowed when inflating content streams
+    max_decompressed_bytes: usize,
     verbose: bool,
-    log_callback: Option<Box<dyn Fn(&str) + 'a>>,
+    log_callback: Option<LogCallback<'a>>,
 }
 
 impl<'a> ContentScanner<'a> {
@@ -264,7 +1161,14 @@ impl<'a> ContentScanner<'a> {
             doc,
             display_info: HashMap::new(),
             image_dims: HashMap::new(),
-            scanned_forms: HashSet::new(),
+            form_scans: 0,
+            form_stack: Vec::new(),
+            current_page: None,
+            current_page_number: 0,
+            clip: None,
+            placements: Vec::new(),
+            max_form_depth: DEFAULT_MAX_FORM_DEPTH,
+            max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
             verbose,
             log_callback: None,
         };
@@ -274,6 +1178,13 @@ impl<'a> ContentScanner<'a> {
         scanner
     }
 
+    /// Apply the nesting and decompression limits from `options`
+    fn with_limits(mut self, options: &ResampleOptions) -> Self {
+        self.max_form_depth = options.max_form_depth;
+        self.max_decompressed_bytes = options.max_decompressed_bytes;
+        self
+    }
+
     fn log(&self, msg: &str) {
         if self.verbose {
             if let Some(ref cb) = self.log_callback {
@@ -295,25 +1206,9 @@ impl<'a> ContentScanner<'a> {
                 });
 
                 if subtype.as_deref() == Some("Image") {
-                    let width = stream
-                        .dict
-                        .get(b"Width")
-                        .ok()
-                        .and_then(|w| match w {
-                            Object::Integer(n) => Some(*n as u32),
-                            _ => None,
-                        })
-                        .unwrap_or(0);
+                    let width = get_int_resolved(&stream.dict, b"Width", self.doc).unwrap_or(0);
 
-                    let height = stream
-                        .dict
-                        .get(b"Height")
-                        .ok()
-                        .and_then(|h| match h {
-                            Object::Integer(n) => Some(*n as u32),
-                            _ => None,
-                        })
-                        .unwrap_or(0);
+                    let height = get_int_resolved(&stream.dict, b"Height", self.doc).unwrap_or(0);
 
                     if width > 0 && height > 0 {
                         self.image_dims.insert(*id, (width, height));
@@ -333,32 +1228,7 @@ impl<'a> ContentScanner<'a> {
 
     /// Get XObject dictionary from resources
     fn get_xobjects_from_resources(&self, resources: &Object) -> HashMap<String, ObjectId> {
-        let mut result = HashMap::new();
-
-        let res_dict = match self.resolve(resources) {
-            Some(Object::Dictionary(d)) => Some(d),
-            _ => None,
-        };
-
-        if let Some(res_dict) = res_dict {
-            if let Ok(xobjects) = res_dict.get(b"XObject") {
-                let xobj_dict = match self.resolve(xobjects) {
-                    Some(Object::Dictionary(d)) => Some(d),
-                    _ => None,
-                };
-
-                if let Some(xobj_dict) = xobj_dict {
-                    for (name, value) in xobj_dict.iter() {
-                        let name_str = String::from_utf8_lossy(name).to_string();
-                        if let Object::Reference(obj_id) = value {
-                            result.insert(name_str, *obj_id);
-                        }
-                    }
-                }
-            }
-        }
-
-        result
+        get_xobjects_static(self.doc, resources)
     }
 
     /// Get ExtGState dictionary from resources (name -> object ID)
@@ -405,19 +1275,15 @@ impl<'a> ContentScanner<'a> {
         match smask {
             Object::Dictionary(dict) => {
                 // SMask dictionary with /G entry pointing to Form XObject
-                if let Ok(g) = dict.get(b"G") {
-                    if let Object::Reference(form_id) = g {
-                        return Some(*form_id);
-                    }
+                if let Ok(Object::Reference(form_id)) = dict.get(b"G") {
+                    return Some(*form_id);
                 }
             }
             Object::Reference(id) => {
                 // Reference to SMask dictionary
                 if let Ok(Object::Dictionary(dict)) = self.doc.get_object(*id) {
-                    if let Ok(g) = dict.get(b"G") {
-                        if let Object::Reference(form_id) = g {
-                            return Some(*form_id);
-                        }
+                    if let Ok(Object::Reference(form_id)) = dict.get(b"G") {
+                        return Some(*form_id);
                     }
                 }
             }
@@ -481,172 +1347,172 @@ impl<'a> ContentScanner<'a> {
             self.scan_tiling_pattern(pattern_id, initial_matrix);
         }
 
-        let content_str = String::from_utf8_lossy(content);
-
         // Graphics state stack
         let mut matrix_stack: Vec<Matrix> = vec![initial_matrix];
 
-        // Tokenize (simplified - doesn't handle all PDF syntax but works for most cases)
-        let mut tokens: Vec<String> = Vec::new();
-        let mut in_string = false;
-        let mut paren_depth = 0;
-        let mut current_token = String::new();
-
-        for ch in content_str.chars() {
-            if in_string {
-                current_token.push(ch);
-                if ch == '(' {
-                    paren_depth += 1;
-                } else if ch == ')' {
-                    paren_depth -= 1;
-                    if paren_depth == 0 {
-                        in_string = false;
-                        tokens.push(current_token.clone());
-                        current_token.clear();
-                    }
-                }
-            } else {
-                match ch {
-                    '(' => {
-                        if !current_token.is_empty() {
-                            tokens.push(current_token.clone());
-                            current_token.clear();
-                        }
-                        in_string = true;
-                        paren_depth = 1;
-                        current_token.push(ch);
-                    }
-                    ' ' | '\t' | '\n' | '\r' => {
-                        if !current_token.is_empty() {
-                            tokens.push(current_token.clone());
-                            current_token.clear();
-                        }
-                    }
-                    '[' | ']' => {
-                        if !current_token.is_empty() {
-                            tokens.push(current_token.clone());
-                            current_token.clear();
-                        }
-                        tokens.push(ch.to_string());
-                    }
-                    _ => {
-                        current_token.push(ch);
-                    }
-                }
-            }
-        }
-        if !current_token.is_empty() {
-            tokens.push(current_token);
-        }
+        // Operands collected since the last operator
+        let mut operands: Vec<Token> = Vec::new();
 
         // Process tokens
-        let mut i = 0;
-        while i < tokens.len() {
-            let token = &tokens[i];
+        let mut lexer = Lexer::new(content);
+        for token in lexer.by_ref() {
+            let op = match token {
+                Token::Operator(op) if !matches!(op.as_str(), "true" | "false" | "null") => op,
+                other => {
+                    operands.push(other);
+                    continue;
+                }
+            };
 
-            match token.as_str() {
+            match op.as_str() {
                 "q" => {
                     // Save graphics state
                     if let Some(current) = matrix_stack.last() {
                         matrix_stack.push(*current);
                     }
                 }
-                "Q" => {
-                    // Restore graphics state
-                    if matrix_stack.len() > 1 {
-                        matrix_stack.pop();
-                    }
+                "Q" if matrix_stack.len() > 1 => {
+                    // Restore graphics state. The seed matrix is never popped, so an
+                    // unbalanced Q in a form cannot discard the CTM it was invoked with;
+                    // each form is scanned with its own stack, leaving the caller's intact
+                    matrix_stack.pop();
                 }
-                "cm" => {
+                "cm" if operands.len() >= 6 => {
                     // Concatenate matrix: a b c d e f cm
-                    if i >= 6 {
-                        let a = parse_number(&tokens[i - 6]);
-                        let b = parse_number(&tokens[i - 5]);
-                        let c = parse_number(&tokens[i - 4]);
-                        let d = parse_number(&tokens[i - 3]);
-                        let e = parse_number(&tokens[i - 2]);
-                        let f = parse_number(&tokens[i - 1]);
-
-                        if let (Some(a), Some(b), Some(c), Some(d), Some(e), Some(f)) =
-                            (a, b, c, d, e, f)
-                        {
-                            let new_matrix = Matrix { a, b, c, d, e, f };
-                            if let Some(current) = matrix_stack.last_mut() {
-                                *current = current.concat(&new_matrix);
-                            }
+                    let n = operands.len();
+                    let a = token_number(&operands[n - 6]);
+                    let b = token_number(&operands[n - 5]);
+                    let c = token_number(&operands[n - 4]);
+                    let d = token_number(&operands[n - 3]);
+                    let e = token_number(&operands[n - 2]);
+                    let f = token_number(&operands[n - 1]);
+
+                    if let (Some(a), Some(b), Some(c), Some(d), Some(e), Some(f)) =
+                        (a, b, c, d, e, f)
+                    {
+                        // CTM' = cm × CTM: the new matrix applies first
+                        let new_matrix = Matrix { a, b, c, d, e, f };
+                        if let Some(current) = matrix_stack.last_mut() {
+                            *current = new_matrix.concat(current);
                         }
                     }
                 }
                 "gs" => {
                     // Set graphics state: /Name gs
-                    if i >= 1 {
-                        let name = tokens[i - 1].trim_start_matches('/');
-                        if let Some(&gs_id) = extgstates.get(name) {
-                            let current_matrix =
-                                matrix_stack.last().copied().unwrap_or(Matrix::identity());
-
-                            // Check if this ExtGState has an SMask with a Form XObject
-                            if let Some(form_id) = self.get_smask_form_from_extgstate(gs_id) {
-                                // Scan the SMask Form with the current transformation
-                                self.scan_form_xobject(form_id, current_matrix);
-                            }
+                    let name = operands.last().and_then(token_name);
+                    if let Some(&gs_id) = name.and_then(|n| extgstates.get(n)) {
+                        let current_matrix =
+                            matrix_stack.last().copied().unwrap_or(Matrix::identity());
+
+                        // Check if this ExtGState has an SMask with a Form XObject
+                        if let Some(form_id) = self.get_smask_form_from_extgstate(gs_id) {
+                            // Scan the SMask Form with the current transformation
+                            self.scan_form_xobject(form_id, current_matrix);
                         }
                     }
                 }
                 "Do" => {
                     // XObject invocation: /Name Do
-                    if i >= 1 {
-                        let name = tokens[i - 1].trim_start_matches('/');
-                        if let Some(&obj_id) = xobjects.get(name) {
-                            let current_matrix =
-                                matrix_stack.last().copied().unwrap_or(Matrix::identity());
-
-                            // Check if it's an image or form
-                            if let Ok(Object::Stream(stream)) = self.doc.get_object(obj_id) {
-                                let subtype =
-                                    stream.dict.get(b"Subtype").ok().and_then(|s| match s {
-                                        Object::Name(n) => {
-                                            Some(String::from_utf8_lossy(n).to_string())
-                                        }
-                                        _ => None,
-                                    });
+                    let name = operands.last().and_then(token_name);
+                    if let Some(&obj_id) = name.and_then(|n| xobjects.get(n)) {
+                        let current_matrix =
+                            matrix_stack.last().copied().unwrap_or(Matrix::identity());
+
+                        // Check if it's an image or form
+                        if let Ok(Object::Stream(stream)) = self.doc.get_object(obj_id) {
+                            let subtype =
+                                stream.dict.get(b"Subtype").ok().and_then(|s| match s {
+                                    Object::Name(n) => {
+                                        Some(String::from_utf8_lossy(n).to_string())
+                                    }
+                                    _ => None,
+                                });
+
+                            match subtype.as_deref() {
+                                Some("Image") => {
+                                    // Record display dimensions for this image
+                                    let mut display_w = current_matrix.scale_x();
+                                    let mut display_h = current_matrix.scale_y();
+
+                                    // Only the part inside the enclosing forms' /BBox is
+                                    // seen, so a partly clipped placement records its
+                                    // visible extent and one entirely outside is dropped
+                                    let bounds = Rect::unit().transformed(&current_matrix);
+                                    let visible = self.clip.map(|clip| clip.intersect(&bounds));
+                                    let clipped_away = visible.is_some_and(|v| v.is_empty());
+                                    let partly_clipped = visible.filter(|v| !v.contains(&bounds));
+                                    if let (Some(visible), Some(inverse)) =
+                                        (partly_clipped, current_matrix.invert())
+                                    {
+                                        // Share of the image's unit square left visible
+                                        let shown =
+                                            visible.transformed(&inverse).intersect(&Rect::unit());
+                                        display_w *= (shown.x1 - shown.x0).clamp(0.0, 1.0);
+                                        display_h *= (shown.y1 - shown.y0).clamp(0.0, 1.0);
+                                    }
+                                    if clipped_away {
+                                        self.log(&format!(
+                                            "  Ignoring {:?}: drawn outside its form's /BBox",
+                                            obj_id
+                                        ));
+                                    }
 
-                                match subtype.as_deref() {
-                                    Some("Image") => {
-                                        // Record display dimensions for this image
-                                        let display_w = current_matrix.scale_x();
-                                        let display_h = current_matrix.scale_y();
-
-                                        if display_w > 0.0 && display_h > 0.0 {
-                                            self.display_info
-                                                .entry(obj_id)
-                                                .or_default()
-                                                .push((display_w, display_h));
+                                    if display_w > 0.0 && display_h > 0.0 && !clipped_away {
+                                        trace_event!(
+                                            trace,
+                                            object_id = ?obj_id,
+                                            width_pt = display_w,
+                                            height_pt = display_h,
+                                            "image placement"
+                                        );
+                                        self.display_info
+                                            .entry(obj_id)
+                                            .or_default()
+                                            .push((display_w, display_h));
+
+                                        let owner =
+                                            self.form_stack.last().copied().or(self.current_page);
+                                        if let Some(owner) = owner {
+                                            self.placements.push(Placement {
+                                                image: obj_id,
+                                                owner,
+                                                size: (display_w, display_h),
+                                                page: self.current_page_number,
+                                                bounds,
+                                            });
                                         }
                                     }
-                                    Some("Form") => {
-                                        // Recursively scan Form XObject
-                                        self.scan_form_xobject(obj_id, current_matrix);
-                                    }
-                                    _ => {}
                                 }
+                                Some("Form") => {
+                                    // Recursively scan Form XObject
+                                    self.scan_form_xobject(obj_id, current_matrix);
+                                }
+                                _ => {}
                             }
                         }
                     }
                 }
                 _ => {}
             }
-            i += 1;
+            operands.clear();
+        }
+
+        if lexer.malformed_numbers() > 0 {
+            self.log(&format!(
+                "[Scanner] Warning: read {} malformed numbers leniently",
+                lexer.malformed_numbers()
+            ));
         }
     }
 
     /// Scan a Form XObject's content stream
+    ///
+    /// Every use is scanned, since each can draw the form's images at a
+    /// different size or position.
     fn scan_form_xobject(&mut self, form_id: ObjectId, parent_matrix: Matrix) {
-        // Avoid infinite recursion
-        if self.scanned_forms.contains(&form_id) {
+        if !self.may_enter(form_id) {
             return;
         }
-        self.scanned_forms.insert(form_id);
 
         let stream = match self.doc.get_object(form_id) {
             Ok(Object::Stream(s)) => s.clone(),
@@ -656,8 +1522,8 @@ impl<'a> ContentScanner<'a> {
         // Get Form's transformation matrix (if any)
         let form_matrix = self.parse_matrix_from_dict(&stream.dict);
 
-        // Combined matrix = parent * form
-        let combined_matrix = parent_matrix.concat(&form_matrix);
+        // Combined matrix = form * parent: form space maps through /Matrix first
+        let combined_matrix = form_matrix.concat(&parent_matrix);
 
         // Get resources
         let resources = stream
@@ -666,18 +1532,32 @@ impl<'a> ContentScanner<'a> {
             .cloned()
             .unwrap_or(Object::Null);
 
-        // Decompress and scan content
-        let content = decompress_stream(&stream);
+        let content = match decompress_stream(&stream, self.max_decompressed_bytes) {
+            Ok(content) => content,
+            Err(e) => {
+                self.log(&format!("[Scanner] Skipping form {:?}: {}", form_id, e));
+                return;
+            }
+        };
+
+        // Nothing the form draws is visible outside its bounding box
+        let outer_clip = self.clip;
+        if let Some(bbox) = stream.dict.get(b"BBox").ok().and_then(Rect::from_array) {
+            let bbox = bbox.transformed(&combined_matrix);
+            self.clip = Some(outer_clip.map_or(bbox, |clip| clip.intersect(&bbox)));
+        }
+
+        self.form_stack.push(form_id);
         self.scan_content_stream(&content, &resources, combined_matrix);
+        self.form_stack.pop();
+        self.clip = outer_clip;
     }
 
     /// Scan a tiling pattern's content stream
     fn scan_tiling_pattern(&mut self, pattern_id: ObjectId, parent_matrix: Matrix) {
-        // Avoid infinite recursion (patterns can be in scanned_forms too)
-        if self.scanned_forms.contains(&pattern_id) {
+        if !self.may_enter(pattern_id) {
             return;
         }
-        self.scanned_forms.insert(pattern_id);
 
         let stream = match self.doc.get_object(pattern_id) {
             Ok(Object::Stream(s)) => s.clone(),
@@ -687,8 +1567,8 @@ impl<'a> ContentScanner<'a> {
         // Get pattern's transformation matrix
         let pattern_matrix = self.parse_matrix_from_dict(&stream.dict);
 
-        // Combined matrix = parent * pattern
-        let combined_matrix = parent_matrix.concat(&pattern_matrix);
+        // Combined matrix = pattern * parent: pattern space maps through /Matrix first
+        let combined_matrix = pattern_matrix.concat(&parent_matrix);
 
         // Get resources
         let resources = stream
@@ -697,9 +1577,53 @@ impl<'a> ContentScanner<'a> {
             .cloned()
             .unwrap_or(Object::Null);
 
-        // Decompress and scan content
-        let content = decompress_stream(&stream);
+        let content = match decompress_stream(&stream, self.max_decompressed_bytes) {
+            Ok(content) => content,
+            Err(e) => {
+                self.log(&format!("[Scanner] Skipping pattern {:?}: {}", pattern_id, e));
+                return;
+            }
+        };
+
+        // The cell repeats across the painted area, so a clip test against a
+        // single copy of it would be meaningless
+        let outer_clip = self.clip.take();
+
+        self.form_stack.push(pattern_id);
         self.scan_content_stream(&content, &resources, combined_matrix);
+        self.form_stack.pop();
+        self.clip = outer_clip;
+    }
+
+    /// Whether the form or pattern `id` can be scanned at this use
+    ///
+    /// Not when it invokes itself, when nesting would pass `max_form_depth`, or
+    /// once [`MAX_FORM_SCANS`] uses have been scanned. A use refused for depth
+    /// does not stop a shallower use from reaching the same object.
+    fn may_enter(&mut self, id: ObjectId) -> bool {
+        if self.form_stack.contains(&id) {
+            self.log(&format!("[Scanner] Warning: not entering {:?}, it invokes itself", id));
+            return false;
+        }
+        if self.form_stack.len() >= self.max_form_depth {
+            self.log(&format!(
+                "[Scanner] Warning: not entering {:?}, forms are nested more than {} deep",
+                id, self.max_form_depth
+            ));
+            return false;
+        }
+        if self.form_scans >= MAX_FORM_SCANS {
+            if self.form_scans == MAX_FORM_SCANS {
+                self.log(&format!(
+                    "[Scanner] Warning: scanned {} form and pattern uses, ignoring the rest",
+                    MAX_FORM_SCANS
+                ));
+            }
+            self.form_scans += 1;
+            return false;
+        }
+        self.form_scans += 1;
+        true
     }
 
     /// Parse a transformation matrix from a dictionary's /Matrix entry
@@ -739,6 +1663,8 @@ impl<'a> ContentScanner<'a> {
 
         for (page_num, &page_id) in pages.iter() {
             self.log(&format!("[Scanner] Scanning page {}...", page_num));
+            self.current_page = Some(page_id);
+            self.current_page_number = *page_num;
 
             let page_dict = match self.doc.get_object(page_id) {
                 Ok(Object::Dictionary(d)) => d.clone(),
@@ -746,80 +1672,97 @@ impl<'a> ContentScanner<'a> {
             };
 
             // Get page resources
-            let resources = self.get_page_resources(&page_dict, page_id);
+            let resources = get_page_resources_static(self.doc, page_id);
+
+            // /UserUnit scales default user space units to multiples of 1/72 inch
+            let user_unit = page_dict
+                .get(b"UserUnit")
+                .ok()
+                .and_then(|u| match u {
+                    Object::Integer(n) => Some(*n as f32),
+                    Object::Real(n) => Some(*n),
+                    _ => None,
+                })
+                .filter(|u| *u > 0.0)
+                .unwrap_or(1.0);
+            let page_matrix = Matrix::scale(user_unit);
 
             // Get page contents
             let contents = page_dict.get(b"Contents").ok();
 
             if let Some(contents) = contents {
                 let content_data = self.get_content_data(contents);
-                self.scan_content_stream(&content_data, &resources, Matrix::identity());
+                self.scan_content_stream(&content_data, &resources, page_matrix);
             }
 
             // Scan annotations on this page
-            self.scan_page_annotations(&page_dict);
-        }
-    }
-
-    /// Get resources for a page, checking parent pages if needed
-    fn get_page_resources(&self, page_dict: &Dictionary, page_id: ObjectId) -> Object {
-        // First try to get resources directly from the page
-        if let Ok(resources) = page_dict.get(b"Resources") {
-            return resources.clone();
-        }
-
-        // Otherwise, look in parent (for inherited resources)
-        if let Ok(Object::Reference(parent_id)) = page_dict.get(b"Parent") {
-            if let Ok(Object::Dictionary(parent_dict)) = self.doc.get_object(*parent_id) {
-                if let Ok(resources) = parent_dict.get(b"Resources") {
-                    return resources.clone();
-                }
-            }
-        }
-
-        // Try to get from document catalog
-        if let Ok(catalog) = self.doc.catalog() {
-            if let Ok(pages_ref) = catalog.get(b"Pages") {
-                if let Object::Reference(pages_id) = pages_ref {
-                    if let Ok(Object::Dictionary(pages_dict)) = self.doc.get_object(*pages_id) {
-                        if let Ok(resources) = pages_dict.get(b"Resources") {
-                            return resources.clone();
-                        }
-                    }
-                }
-            }
+            self.scan_page_annotations(&page_dict, page_matrix);
         }
-
-        let _ = page_id; // suppress unused warning
-        Object::Null
     }
 
     /// Get content data from a Contents entry (may be stream or array of streams)
+    ///
+    /// Malformed entries are read where possible and otherwise reported, since
+    /// the images drawn by a page whose contents cannot be read go unseen.
     fn get_content_data(&self, contents: &Object) -> Vec<u8> {
+        self.get_content_data_nested(contents, 0)
+    }
+
+    fn get_content_data_nested(&self, contents: &Object, depth: usize) -> Vec<u8> {
+        // Arrays of references to arrays and reference chains are tolerated, cycles are not
+        if depth > MAX_CONTENTS_NESTING {
+            self.log(&format!(
+                "[Scanner] Warning: page {}: /Contents nested more than {} levels deep, ignoring the rest",
+                self.current_page_number, MAX_CONTENTS_NESTING
+            ));
+            return Vec::new();
+        }
         match contents {
-            Object::Reference(id) => {
-                if let Ok(obj) = self.doc.get_object(*id) {
-                    self.get_content_data(obj)
-                } else {
+            Object::Reference(id) => match self.doc.get_object(*id) {
+                Ok(obj) => self.get_content_data_nested(obj, depth + 1),
+                Err(e) => {
+                    self.log(&format!(
+                        "[Scanner] Warning: page {}: content object {:?} is missing ({}), its images cannot be located",
+                        self.current_page_number, id, e
+                    ));
                     Vec::new()
                 }
-            }
-            Object::Stream(stream) => decompress_stream(stream),
+            },
+            Object::Stream(stream) => decompress_stream(stream, self.max_decompressed_bytes).unwrap_or_else(|e| {
+                self.log(&format!("[Scanner] Skipping content stream: {}", e));
+                Vec::new()
+            }),
             Object::Array(arr) => {
                 let mut combined = Vec::new();
                 for item in arr {
-                    let data = self.get_content_data(item);
+                    let data = self.get_content_data_nested(item, depth + 1);
                     combined.extend(data);
                     combined.push(b'\n');
                 }
                 combined
             }
-            _ => Vec::new(),
+            // Some broken writers store the operators as a string
+            Object::String(bytes, _) => {
+                self.log(&format!(
+                    "[Scanner] Warning: page {}: content stored as a string, reading it as content",
+                    self.current_page_number
+                ));
+                bytes.clone()
+            }
+            Object::Null => Vec::new(),
+            other => {
+                self.log(&format!(
+                    "[Scanner] Warning: page {}: unexpected {} in /Contents, its images cannot be located",
+                    self.current_page_number,
+                    other.enum_variant()
+                ));
+                Vec::new()
+            }
         }
     }
 
     /// Scan annotations on a page
-    fn scan_page_annotations(&mut self, page_dict: &Dictionary) {
+    fn scan_page_annotations(&mut self, page_dict: &Dictionary, page_matrix: Matrix) {
         let annots = match page_dict.get(b"Annots").ok() {
             Some(a) => a,
             None => return,
@@ -832,13 +1775,13 @@ impl<'a> ContentScanner<'a> {
 
         for annot_ref in annot_array {
             if let Object::Reference(annot_id) = annot_ref {
-                self.scan_annotation(annot_id);
+                self.scan_annotation(annot_id, page_matrix);
             }
         }
     }
 
     /// Scan an annotation's appearance streams
-    fn scan_annotation(&mut self, annot_id: ObjectId) {
+    fn scan_annotation(&mut self, annot_id: ObjectId, page_matrix: Matrix) {
         let annot_dict = match self.doc.get_object(annot_id) {
             Ok(Object::Dictionary(d)) => d.clone(),
             _ => return,
@@ -858,13 +1801,13 @@ impl<'a> ContentScanner<'a> {
         // Scan Normal (N), Rollover (R), and Down (D) appearances
         for key in [b"N".as_slice(), b"R".as_slice(), b"D".as_slice()] {
             if let Ok(appearance) = ap_dict.get(key) {
-                self.scan_appearance_entry(appearance);
+                self.scan_appearance_entry(appearance, page_matrix);
             }
         }
     }
 
     /// Scan an appearance entry (may be a stream or dictionary of streams)
-    fn scan_appearance_entry(&mut self, appearance: &Object) {
+    fn scan_appearance_entry(&mut self, appearance: &Object, page_matrix: Matrix) {
         // First, collect any object IDs we need to scan
         let mut ids_to_scan: Vec<ObjectId> = Vec::new();
 
@@ -902,10 +1845,43 @@ impl<'a> ContentScanner<'a> {
 
         // Now scan all collected IDs
         for id in ids_to_scan {
-            self.scan_form_xobject(id, Matrix::identity());
+            self.scan_form_xobject(id, page_matrix);
         }
     }
 
+    /// Get every recorded placement of each image, sorted by object ID
+    fn get_image_usages(&self) -> Vec<ImageUsage> {
+        let mut usages: Vec<ImageUsage> = self
+            .display_info
+            .iter()
+            .filter_map(|(obj_id, placements)| {
+                let &pixel_dims = self.image_dims.get(obj_id)?;
+                Some(ImageUsage {
+                    object_id: *obj_id,
+                    pixel_dims,
+                    placements: placements.clone(),
+                })
+            })
+            .collect();
+        usages.sort_by_key(|usage| usage.object_id);
+        usages
+    }
+
+    /// Get the page position of every recorded placement, in scan order
+    fn get_image_placements(&self) -> Vec<ImagePlacement> {
+        self.placements
+            .iter()
+            .map(|placement| ImagePlacement {
+                page: placement.page,
+                object_id: placement.image,
+                x: placement.bounds.x0,
+                y: placement.bounds.y0,
+                w: placement.bounds.x1 - placement.bounds.x0,
+                h: placement.bounds.y1 - placement.bounds.y0,
+            })
+            .collect()
+    }
+
     /// Get the final display info map (object ID -> best display info)
     fn get_display_info_map(&self) -> HashMap<ObjectId, ImageDisplayInfo> {
         let mut result = HashMap::new();
@@ -939,8 +1915,23 @@ impl<'a> ContentScanner<'a> {
     }
 }
 
-/// Decode an SMask stream (grayscale alpha channel)
-fn decode_smask_stream(stream: &Stream, width: u32, height: u32) -> Result<Vec<u8>, String> {
+/// Decode an SMask stream (grayscale alpha channel) at the base image's size
+///
+/// An SMask may have its own /Width and /Height; it is decoded at those and
+/// then scaled to `width` x `height` so the alpha lines up with the base image.
+fn decode_smask_stream(
+    doc: &Document,
+    stream: &Stream,
+    width: u32,
+    height: u32,
+    max_bytes: usize,
+) -> Result<Vec<u8>, String> {
+    let dimension = |key: &[u8], fallback: u32| {
+        get_int_resolved(&stream.dict, key, doc).filter(|&n| n > 0).unwrap_or(fallback)
+    };
+    let mut smask_width = dimension(b"Width", width);
+    let mut smask_height = dimension(b"Height", height);
+
     let content = &stream.content;
     let filter = stream.dict.get(b"Filter").ok().and_then(|f| match f {
         Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
@@ -953,12 +1944,16 @@ fn decode_smask_stream(stream: &Stream, width: u32, height: u32) -> Result<Vec<u
 
     let decoded_data = match filter.as_deref() {
         Some("FlateDecode") => {
-            let mut decoder = ZlibDecoder::new(&content[..]);
-            let mut decoded = Vec::new();
-            decoder
-                .read_to_end(&mut decoded)
-                .map_err(|e| e.to_string())?;
-            decoded
+            undo_predictor(inflate(content, max_bytes)?, first_decode_parms(&stream.dict))?
+        }
+        // Soft masks written by this tool are JPEG
+        Some("DCTDecode") => {
+            let alpha = image::load_from_memory_with_format(content, ImageFormat::Jpeg)
+                .map_err(|e| format!("Failed to decode SMask JPEG: {}", e))?
+                .to_luma8();
+            smask_width = alpha.width();
+            smask_height = alpha.height();
+            alpha.into_raw()
         }
         None => content.clone(),
         Some(other) => {
@@ -966,26 +1961,188 @@ fn decode_smask_stream(stream: &Stream, width: u32, height: u32) -> Result<Vec<u
         }
     };
 
-    let expected_size = (width * height) as usize;
-    if decoded_data.len() >= expected_size {
-        Ok(decoded_data[..expected_size].to_vec())
-    } else {
-        Err(format!(
+    let expected_size = (smask_width as usize)
+        .checked_mul(smask_height as usize)
+        .ok_or_else(|| format!("SMask dimensions {}x{} are too large", smask_width, smask_height))?;
+    if decoded_data.len() < expected_size {
+        return Err(format!(
             "SMask data size mismatch: got {} expected {}",
             decoded_data.len(),
             expected_size
-        ))
+        ));
+    }
+    let alpha = decoded_data[..expected_size].to_vec();
+
+    if (smask_width, smask_height) == (width, height) {
+        return Ok(alpha);
+    }
+
+    let alpha = image::GrayImage::from_raw(smask_width, smask_height, alpha)
+        .ok_or("SMask buffer does not match its dimensions")?;
+    Ok(image::imageops::resize(
+        &alpha,
+        width,
+        height,
+        image::imageops::FilterType::Triangle,
+    )
+    .into_raw())
+}
+
+/// Read an SMask's /Matte color as 0-255 RGB
+///
+/// Only gray and RGB base images are handled; other mattes return `None` and
+/// the colors are used as they are.
+fn smask_matte_rgb(smask: &Stream) -> Option<[f32; 3]> {
+    let Ok(Object::Array(matte)) = smask.dict.get(b"Matte") else {
+        return None;
+    };
+    let values: Vec<f32> = matte
+        .iter()
+        .filter_map(|v| match v {
+            Object::Integer(n) => Some(*n as f32 * 255.0),
+            Object::Real(n) => Some(*n * 255.0),
+            _ => None,
+        })
+        .collect();
+    match values.as_slice() {
+        [gray] => Some([*gray; 3]),
+        [r, g, b] => Some([*r, *g, *b]),
+        _ => None,
+    }
+}
+
+/// Undo pre-blending against a matte color, c = m + (c' - m) / alpha
+///
+/// Resampling pre-blended colors would smear the matte into visible halos
+/// along transparent edges.
+fn unblend_matte(rgb: &mut RgbImage, alpha: &[u8], matte: [f32; 3]) {
+    for (pixel, &a) in rgb.pixels_mut().zip(alpha) {
+        // Fully transparent pixels have no recoverable color
+        if a == 0 {
+            continue;
+        }
+        let a = a as f32 / 255.0;
+        for (channel, m) in pixel.0.iter_mut().zip(matte) {
+            *channel = (m + (*channel as f32 - m) / a).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Combine a decoded image with its soft mask into RGBA
+///
+/// The mask is scaled to the decoded image, whose size can differ from the
+/// mask's (and, for JPEGs, from the image's own /Width and /Height). Any
+/// /Matte pre-blending is removed, as the result carries straight colors.
+fn apply_smask(
+    doc: &Document,
+    img: &DynamicImage,
+    smask: &Stream,
+    max_bytes: usize,
+) -> Result<DynamicImage, String> {
+    let (width, height) = (img.width(), img.height());
+    let alpha = decode_smask_stream(doc, smask, width, height, max_bytes)?;
+    let mut rgb = img.to_rgb8();
+    if let Some(matte) = smask_matte_rgb(smask) {
+        unblend_matte(&mut rgb, &alpha, matte);
+    }
+    let mut rgba = image::RgbaImage::new(width, height);
+    for ((out, pixel), &a) in rgba.pixels_mut().zip(rgb.pixels()).zip(&alpha) {
+        *out = image::Rgba([pixel[0], pixel[1], pixel[2], a]);
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// Why `decode_image_stream` cannot decode an image, judged from its dictionary alone
+///
+/// This is the one classification of what the decoder supports: the decoder
+/// checks it before reading any data, and [`validate_pdf`] and
+/// [`unsupported_images`] report it. `color_space` and `bits_per_component`
+/// are as the decoder receives them.
+fn undecodable_reason(
+    doc: &Document,
+    dict: &Dictionary,
+    color_space: &str,
+    bits_per_component: u32,
+) -> Option<String> {
+    let filter = match dict.get(b"Filter") {
+        Ok(Object::Name(n)) => Some(n.as_slice()),
+        Ok(Object::Array(arr)) => arr.first().and_then(|f| f.as_name().ok()),
+        _ => None,
+    };
+    match filter.map(String::from_utf8_lossy).as_deref() {
+        // The codestream carries its own color information
+        Some("DCTDecode") => return None,
+        #[cfg(feature = "jpeg2000")]
+        Some("JPXDecode") => return None,
+        None | Some("FlateDecode") => {}
+        Some(other) => {
+            return Some(match unsupported_filter_reason(other) {
+                Some(reason) => reason.to_string(),
+                None => format!("{} filter (unsupported)", other),
+            });
+        }
+    }
+    if matches!(dict.get(b"ImageMask"), Ok(Object::Boolean(true))) {
+        return Some("stencil mask (ImageMask)".to_string());
+    }
+
+    let bpc = bits_per_component;
+    match color_space {
+        "DeviceGray" | "Gray" | "CalGray" | "Indexed" if !matches!(bpc, 1 | 2 | 4 | 8) => Some(
+            format!("{}-bit {} (only 1, 2, 4 and 8-bit are supported)", bpc, color_space),
+        ),
+        "DeviceGray" | "Gray" | "CalGray" => None,
+        "DeviceRGB" | "RGB" | "CalRGB" | "DeviceCMYK" | "CMYK" | "ICCBased" | "Lab"
+        | "Separation" | "DeviceN"
+            if bpc != 8 =>
+        {
+            Some(format!("{}-bit {} (only 8-bit is supported)", bpc, color_space))
+        }
+        "DeviceRGB" | "RGB" | "CalRGB" | "DeviceCMYK" | "CMYK" | "ICCBased" | "Lab"
+        | "Separation" | "DeviceN" => None,
+        "Indexed" => {
+            let base = match dict.get(b"ColorSpace") {
+                Ok(Object::Array(arr)) => arr.get(1),
+                Ok(Object::Reference(id)) => match doc.get_object(*id) {
+                    Ok(Object::Array(arr)) => arr.get(1),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let base_name = base.map(|base| match base {
+                Object::Reference(id) => doc
+                    .get_object(*id)
+                    .map(|base| get_color_space_name(base, doc))
+                    .unwrap_or_default(),
+                base => get_color_space_name(base, doc),
+            });
+            match base_name.as_deref() {
+                Some(
+                    "DeviceGray" | "Gray" | "CalGray" | "DeviceRGB" | "RGB" | "CalRGB"
+                    | "DeviceCMYK" | "CMYK" | "ICCBased",
+                ) => None,
+                Some(other) => Some(format!("Indexed over {} (unsupported)", other)),
+                None => Some("malformed Indexed color space".to_string()),
+            }
+        }
+        other => Some(format!("{} color space (unsupported)", other)),
     }
 }
 
 /// Decode a PDF image stream into raw pixel data
 fn decode_image_stream(
+    doc: &Document,
     stream: &Stream,
     width: u32,
     height: u32,
     color_space: &str,
     bits_per_component: u32,
+    max_bytes: usize,
 ) -> Result<DynamicImage, String> {
+    if let Some(reason) = undecodable_reason(doc, &stream.dict, color_space, bits_per_component) {
+        return Err(reason);
+    }
+
     let content = &stream.content;
     let filter = stream.dict.get(b"Filter").ok().and_then(|f| match f {
         Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
@@ -998,34 +2155,61 @@ fn decode_image_stream(
 
     let decoded_data = match filter.as_deref() {
         Some("FlateDecode") => {
-            let mut decoder = ZlibDecoder::new(&content[..]);
-            let mut decoded = Vec::new();
-            decoder
-                .read_to_end(&mut decoded)
-                .map_err(|e| e.to_string())?;
-            decoded
+            undo_predictor(inflate(content, max_bytes)?, first_decode_parms(&stream.dict))?
         }
         Some("DCTDecode") => {
-            // JPEG data - decode using image crate
+            // Plain CMYK JPEGs are converted here so inversion and /Decode are honored;
+            // the image crate always treats them as Adobe-inverted
+            if let Some(layout) = read_jpeg_layout(content) {
+                if layout.components == 4 && layout.adobe_transform != Some(2) {
+                    // The JPEG header is authoritative for the sample layout
+                    let (samples, jpeg_width, jpeg_height) = decode_jpeg_cmyk(content)?;
+                    let inverted =
+                        layout.adobe_transform.is_some() || decode_is_inverted(&stream.dict, 4);
+                    return cmyk_samples_to_rgb(&samples, jpeg_width, jpeg_height, inverted);
+                }
+            }
+            // JPEG data - decode using image crate. An EXIF orientation tag is
+            // deliberately not applied: PDF viewers ignore it and orient the image
+            // by the CTM alone, and the re-encoded JPEG carries no EXIF, so the
+            // stored pixel order is what the page shows
             let img = image::load_from_memory_with_format(content, ImageFormat::Jpeg)
                 .map_err(|e| format!("Failed to decode JPEG image: {}", e))?;
             return Ok(img);
         }
+        #[cfg(feature = "jpeg2000")]
         Some("JPXDecode") => {
-            // JPEG2000 - try to decode using image crate
-            let img = image::load_from_memory(content)
-                .map_err(|e| format!("Failed to decode JPEG2000 image: {}", e))?;
-            return Ok(img);
+            let jpx = decode_jpx_samples(stream, width, height)?;
+            // A /ColorSpace in the image dictionary overrides the codestream's own
+            let color_space = if stream.dict.has(b"ColorSpace") {
+                color_space
+            } else {
+                jpx.color_space
+            };
+            // Interpret the samples as an unfiltered 8-bit image with the same dictionary
+            let mut dict = stream.dict.clone();
+            dict.remove(b"Filter");
+            dict.remove(b"DecodeParms");
+            let raw = Stream::new(dict, jpx.samples);
+            let img = decode_image_stream(doc, &raw, width, height, color_space, 8, max_bytes)?;
+            let Some(alpha) = jpx.alpha else {
+                return Ok(img);
+            };
+            let mut rgba = img.to_rgba8();
+            for (pixel, a) in rgba.pixels_mut().zip(alpha) {
+                pixel[3] = a;
+            }
+            return Ok(DynamicImage::ImageRgba8(rgba));
         }
         None => content.clone(),
-        Some(other) => {
-            return Err(format!("Unsupported filter: {}", other));
-        }
+        // Turned away by `undecodable_reason`
+        Some(other) => return Err(format!("Unsupported filter: {}", other)),
     };
 
-    // Convert raw pixel data to DynamicImage based on color space
+    // Convert raw pixel data to DynamicImage based on color space.
+    // CalRGB/CalGray are close enough to their device equivalents for resampling
     match color_space {
-        "DeviceRGB" | "RGB" => {
+        "DeviceRGB" | "RGB" | "CalRGB" => {
             let expected_size = (width * height * 3) as usize;
             if bits_per_component == 8 && decoded_data.len() >= expected_size {
                 let img = RgbImage::from_raw(width, height, decoded_data[..expected_size].to_vec())
@@ -1040,7 +2224,23 @@ fn decode_image_stream(
                 ))
             }
         }
-        "DeviceGray" | "Gray" => {
+        // 1-bit scans and 2/4-bit gray are widened to 8 bits, scaling the samples to 0-255
+        "DeviceGray" | "Gray" | "CalGray" if bits_per_component < 8 => {
+            let samples = unpack_samples(&decoded_data, width, height, bits_per_component)
+                .ok_or_else(|| {
+                    format!(
+                        "Unsupported grayscale format: {} bits, {} bytes",
+                        bits_per_component,
+                        decoded_data.len()
+                    )
+                })?;
+            let max = (1u32 << bits_per_component) - 1;
+            let gray = samples.into_iter().map(|v| (v as u32 * 255 / max) as u8).collect();
+            let img = image::GrayImage::from_raw(width, height, gray)
+                .ok_or("Failed to create grayscale image from raw data")?;
+            Ok(DynamicImage::ImageLuma8(img))
+        }
+        "DeviceGray" | "Gray" | "CalGray" => {
             let expected_size = (width * height) as usize;
             if bits_per_component == 8 && decoded_data.len() >= expected_size {
                 let img = image::GrayImage::from_raw(
@@ -1060,27 +2260,10 @@ fn decode_image_stream(
             }
         }
         "DeviceCMYK" | "CMYK" => {
-            // Convert CMYK to RGB
             let expected_size = (width * height * 4) as usize;
             if bits_per_component == 8 && decoded_data.len() >= expected_size {
-                let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
-                for chunk in decoded_data[..expected_size].chunks(4) {
-                    let c = chunk[0] as f32 / 255.0;
-                    let m = chunk[1] as f32 / 255.0;
-                    let y = chunk[2] as f32 / 255.0;
-                    let k = chunk[3] as f32 / 255.0;
-
-                    let r = ((1.0 - c) * (1.0 - k) * 255.0) as u8;
-                    let g = ((1.0 - m) * (1.0 - k) * 255.0) as u8;
-                    let b = ((1.0 - y) * (1.0 - k) * 255.0) as u8;
-
-                    rgb_data.push(r);
-                    rgb_data.push(g);
-                    rgb_data.push(b);
-                }
-                let img = RgbImage::from_raw(width, height, rgb_data)
-                    .ok_or("Failed to create RGB image from CMYK data")?;
-                Ok(DynamicImage::ImageRgb8(img))
+                let inverted = decode_is_inverted(&stream.dict, 4);
+                cmyk_samples_to_rgb(&decoded_data[..expected_size], width, height, inverted)
             } else {
                 Err(format!(
                     "Unsupported CMYK format: {} bits, {} bytes (expected {})",
@@ -1105,943 +2288,6537 @@ fn decode_image_stream(
                 Err("Could not determine ICCBased color space format".to_string())
             }
         }
+        "Lab" => {
+            let expected_size = (width * height * 3) as usize;
+            if bits_per_component == 8 && decoded_data.len() >= expected_size {
+                let params = LabParams::from_image(stream, doc);
+                lab_samples_to_rgb(&decoded_data[..expected_size], width, height, &params)
+            } else {
+                Err(format!(
+                    "Unsupported Lab format: {} bits, {} bytes (expected {})",
+                    bits_per_component,
+                    decoded_data.len(),
+                    expected_size
+                ))
+            }
+        }
+        "Indexed" => decode_indexed_samples(
+            &decoded_data,
+            width,
+            height,
+            bits_per_component,
+            stream.dict.get(b"ColorSpace").ok(),
+            doc,
+            max_bytes,
+        ),
+        "Separation" | "DeviceN" => {
+            let color_space_obj = stream.dict.get(b"ColorSpace").ok();
+            decode_tinted_samples(
+                &decoded_data,
+                width,
+                height,
+                bits_per_component,
+                color_space_obj,
+                doc,
+                max_bytes,
+            )
+        }
         _ => Err(format!("Unsupported color space: {}", color_space)),
     }
 }
 
-/// Encode an image as JPEG and create a PDF stream
-fn encode_as_jpeg_stream(img: &DynamicImage, quality: u8) -> Result<(Stream, u32, u32), String> {
-    let rgb = img.to_rgb8();
-    let (width, height) = rgb.dimensions();
+/// Pixels decoded from a JPEG2000 stream
+#[cfg(feature = "jpeg2000")]
+struct JpxSamples {
+    /// Interleaved 8-bit color samples
+    samples: Vec<u8>,
+    /// Color space the codestream declares
+    color_space: &'static str,
+    /// Opacity channel, when /SMaskInData asks for it and the codestream has one
+    alpha: Option<Vec<u8>>,
+}
 
-    let mut jpeg_bytes = Vec::new();
-    let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg_bytes, quality);
-    encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_0);
-    encoder
-        .encode(
-            rgb.as_raw(),
-            width as u16,
-            height as u16,
-            jpeg_encoder::ColorType::Rgb,
-        )
-        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+/// Decode a JPEG2000 stream into 8-bit samples at `width` x `height`
+///
+/// sYCC data is converted to RGB and premultiplied colors are divided back out.
+/// Subsampled components are stretched to the full image size by repeating samples.
+#[cfg(feature = "jpeg2000")]
+fn decode_jpx_samples(stream: &Stream, width: u32, height: u32) -> Result<JpxSamples, String> {
+    use jpeg2k::ColorSpace as JpxColorSpace;
+
+    let image = jpeg2k::Image::from_bytes(&stream.content)
+        .map_err(|e| format!("Failed to decode JPEG2000 image: {}", e))?;
+
+    // Opacity channels are only meaningful with /SMaskInData (1 for straight,
+    // 2 for premultiplied colors), and an /SMask entry takes precedence over them
+    let components: Vec<_> = image.components().iter().filter(|c| !c.is_alpha()).collect();
+    let smask_in_data = match stream.dict.get(b"SMaskInData") {
+        Ok(Object::Integer(n)) if !stream.dict.has(b"SMask") => *n,
+        _ => 0,
+    };
+    let alpha_component = image
+        .components()
+        .iter()
+        .find(|c| c.is_alpha())
+        .filter(|_| smask_in_data != 0);
+
+    let embedded_color_space = match (image.color_space(), components.len()) {
+        (JpxColorSpace::EYCC, _) => {
+            return Err("Unsupported JPEG2000 color space: e-sYCC".to_string());
+        }
+        (_, 3) => "DeviceRGB",
+        (_, 1) => "DeviceGray",
+        (_, 4) => "DeviceCMYK",
+        (cs, n) => {
+            return Err(format!(
+                "Unsupported JPEG2000 color space: {:?} with {} components",
+                cs, n
+            ));
+        }
+    };
 
-    let mut dict = lopdf::Dictionary::new();
-    dict.set("Type", Object::Name(b"XObject".to_vec()));
-    dict.set("Subtype", Object::Name(b"Image".to_vec()));
-    dict.set("Width", Object::Integer(width as i64));
-    dict.set("Height", Object::Integer(height as i64));
-    dict.set("ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
-    dict.set("BitsPerComponent", Object::Integer(8));
-    dict.set("Filter", Object::Name(b"DCTDecode".to_vec()));
-    dict.set("Length", Object::Integer(jpeg_bytes.len() as i64));
+    let planes: Vec<(u32, u32, Vec<u8>)> = components
+        .iter()
+        .copied()
+        .chain(alpha_component)
+        .map(|c| (c.width(), c.height(), c.data_u8().collect()))
+        .collect();
+    if planes.iter().any(|(w, h, _)| *w == 0 || *h == 0) {
+        return Err("JPEG2000 image has an empty component".to_string());
+    }
 
-    Ok((Stream::new(dict, jpeg_bytes), width, height))
-}
+    // Subsampled components are stretched to the full image size
+    let color_planes = components.len();
+    let mut samples = Vec::with_capacity((width * height) as usize * color_planes);
+    let mut alpha = alpha_component.map(|_| Vec::with_capacity((width * height) as usize));
+    for y in 0..height {
+        for x in 0..width {
+            for (i, (w, h, data)) in planes.iter().enumerate() {
+                let px = (x as u64 * *w as u64 / width as u64) as usize;
+                let py = (y as u64 * *h as u64 / height as u64) as usize;
+                let sample = data[py * *w as usize + px];
+                match alpha.as_mut() {
+                    Some(alpha) if i == color_planes => alpha.push(sample),
+                    _ => samples.push(sample),
+                }
+            }
+        }
+    }
 
-/// Create an SMask stream for the alpha channel using JPEG compression
-fn create_smask_stream(alpha_data: &[u8], width: u32, height: u32, quality: u8) -> Result<Stream, String> {
-    let mut jpeg_bytes = Vec::new();
-    let encoder = jpeg_encoder::Encoder::new(&mut jpeg_bytes, quality);
-    encoder
-        .encode(
-            alpha_data,
-            width as u16,
-            height as u16,
-            jpeg_encoder::ColorType::Luma,
-        )
-        .map_err(|e| format!("Failed to encode SMask as JPEG: {}", e))?;
+    if matches!(image.color_space(), JpxColorSpace::SYCC) && color_planes == 3 {
+        for pixel in samples.chunks_mut(3) {
+            let y = pixel[0] as f32;
+            let cb = pixel[1] as f32 - 128.0;
+            let cr = pixel[2] as f32 - 128.0;
+            pixel[0] = (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8;
+            pixel[1] = (y - 0.344136 * cb - 0.714136 * cr).round().clamp(0.0, 255.0) as u8;
+            pixel[2] = (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8;
+        }
+    }
 
-    let mut dict = lopdf::Dictionary::new();
-    dict.set("Type", Object::Name(b"XObject".to_vec()));
-    dict.set("Subtype", Object::Name(b"Image".to_vec()));
-    dict.set("Width", Object::Integer(width as i64));
-    dict.set("Height", Object::Integer(height as i64));
-    dict.set("ColorSpace", Object::Name(b"DeviceGray".to_vec()));
-    dict.set("BitsPerComponent", Object::Integer(8));
-    dict.set("Filter", Object::Name(b"DCTDecode".to_vec()));
-    dict.set("Length", Object::Integer(jpeg_bytes.len() as i64));
+    if let (Some(alpha), 2) = (&alpha, smask_in_data) {
+        for (pixel, &a) in samples.chunks_mut(color_planes).zip(alpha) {
+            if a == 0 {
+                continue;
+            }
+            for channel in pixel {
+                *channel = (*channel as u32 * 255 / a as u32).min(255) as u8;
+            }
+        }
+    }
 
-    Ok(Stream::new(dict, jpeg_bytes))
+    Ok(JpxSamples {
+        samples,
+        color_space: embedded_color_space,
+        alpha,
+    })
 }
 
-/// Encode an image with alpha
-fn encode_with_alpha_stream(
-    img: &DynamicImage,
-    quality: u8,
-) -> Result<(Stream, Option<Stream>, u32, u32), String> {
-    let rgba = img.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    let raw_data = rgba.into_raw();
-
-    // Separate RGB and Alpha channels
-    let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
-    let mut alpha_data = Vec::with_capacity((width * height) as usize);
+/// Check whether an image's /Decode array inverts every one of its components
+fn decode_is_inverted(dict: &Dictionary, components: usize) -> bool {
+    let decode = match dict.get(b"Decode") {
+        Ok(Object::Array(decode)) if decode.len() == components * 2 => decode,
+        _ => return false,
+    };
+    let as_number = |obj: &Object| match obj {
+        Object::Integer(n) => Some(*n as f32),
+        Object::Real(n) => Some(*n),
+        _ => None,
+    };
+    decode
+        .chunks(2)
+        .all(|pair| as_number(&pair[0]) == Some(1.0) && as_number(&pair[1]) == Some(0.0))
+}
 
-    for chunk in raw_data.chunks(4) {
-        rgb_data.push(chunk[0]);
-        rgb_data.push(chunk[1]);
-        rgb_data.push(chunk[2]);
-        alpha_data.push(chunk[3]);
+/// Convert interleaved 8-bit CMYK samples to an RGB image
+/// `inverted` means samples store 255 minus the ink amount, as in Adobe CMYK JPEGs
+fn cmyk_samples_to_rgb(
+    samples: &[u8],
+    width: u32,
+    height: u32,
+    inverted: bool,
+) -> Result<DynamicImage, String> {
+    let expected_size = (width * height * 4) as usize;
+    if samples.len() < expected_size {
+        return Err(format!(
+            "CMYK data too short: {} bytes (expected {})",
+            samples.len(),
+            expected_size
+        ));
     }
 
-    // Compress RGB with FlateDecode
-    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
-    std::io::Write::write_all(&mut encoder, &rgb_data)
-        .map_err(|e| format!("Failed to compress RGB data: {}", e))?;
-    let compressed_rgb = encoder
-        .finish()
-        .map_err(|e| format!("Failed to finish compression: {}", e))?;
-
-    let mut dict = lopdf::Dictionary::new();
-    dict.set("Type", Object::Name(b"XObject".to_vec()));
-    dict.set("Subtype", Object::Name(b"Image".to_vec()));
-    dict.set("Width", Object::Integer(width as i64));
-    dict.set("Height", Object::Integer(height as i64));
-    dict.set("ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
-    dict.set("BitsPerComponent", Object::Integer(8));
-    dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
-    dict.set("Length", Object::Integer(compressed_rgb.len() as i64));
+    let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
+    for chunk in samples[..expected_size].chunks(4) {
+        let ink = |v: u8| if inverted { 255 - v } else { v } as f32 / 255.0;
+        let c = ink(chunk[0]);
+        let m = ink(chunk[1]);
+        let y = ink(chunk[2]);
+        let k = ink(chunk[3]);
+
+        rgb_data.push(((1.0 - c) * (1.0 - k) * 255.0).round() as u8);
+        rgb_data.push(((1.0 - m) * (1.0 - k) * 255.0).round() as u8);
+        rgb_data.push(((1.0 - y) * (1.0 - k) * 255.0).round() as u8);
+    }
 
-    let main_stream = Stream::new(dict, compressed_rgb);
-    let smask_stream = create_smask_stream(&alpha_data, width, height, quality)?;
+    let img = RgbImage::from_raw(width, height, rgb_data)
+        .ok_or("Failed to create RGB image from CMYK data")?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
 
-    Ok((main_stream, Some(smask_stream), width, height))
+/// White point and sample ranges of a Lab color space
+struct LabParams {
+    white_point: [f32; 3],
+    /// Minimum and maximum of L*, a* and b*, mapped from samples 0 and 255
+    ranges: [(f32, f32); 3],
 }
 
-/// Get color space name from PDF object
-fn get_color_space_name(obj: &Object, doc: &Document) -> String {
-    match obj {
-        Object::Name(name) => String::from_utf8_lossy(name).to_string(),
-        Object::Array(arr) => {
-            if let Some(Object::Name(name)) = arr.first() {
-                String::from_utf8_lossy(name).to_string()
-            } else {
-                "Unknown".to_string()
+impl LabParams {
+    /// Read /WhitePoint and /Range from an image's [/Lab <<...>>] color space,
+    /// with the image's /Decode array taking precedence over /Range
+    fn from_image(stream: &Stream, doc: &Document) -> Self {
+        let resolve = |obj: &Object| -> Option<Object> {
+            match obj {
+                Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+                other => Some(other.clone()),
             }
-        }
-        Object::Reference(id) => {
-            if let Ok(resolved) = doc.get_object(*id) {
-                get_color_space_name(resolved, doc)
-            } else {
-                "Unknown".to_string()
+        };
+        let numbers = |obj: Option<Object>| -> Vec<f32> {
+            match obj {
+                Some(Object::Array(arr)) => arr
+                    .iter()
+                    .filter_map(|v| match resolve(v)? {
+                        Object::Integer(n) => Some(n as f32),
+                        Object::Real(n) => Some(n),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
             }
-        }
-        _ => "Unknown".to_string(),
-    }
+        };
+
+        let dict = match stream.dict.get(b"ColorSpace").ok().and_then(resolve) {
+            Some(Object::Array(arr)) => match arr.get(1).and_then(resolve) {
+                Some(Object::Dictionary(d)) => Some(d),
+                _ => None,
+            },
+            _ => None,
+        };
+        let entry = |key: &[u8]| dict.as_ref().and_then(|d| d.get(key).ok()).and_then(resolve);
+
+        // Default to D65 if the (required) white point is missing
+        let white_point = match numbers(entry(b"WhitePoint")).as_slice() {
+            [x, y, z] if *y > 0.0 => [*x, *y, *z],
+            _ => [0.9505, 1.0, 1.089],
+        };
+
+        let mut ranges = [(0.0, 100.0), (-100.0, 100.0), (-100.0, 100.0)];
+        if let [a_min, a_max, b_min, b_max] = numbers(entry(b"Range")).as_slice() {
+            ranges[1] = (*a_min, *a_max);
+            ranges[2] = (*b_min, *b_max);
+        }
+        if let [l_min, l_max, a_min, a_max, b_min, b_max] =
+            numbers(stream.dict.get(b"Decode").ok().cloned()).as_slice()
+        {
+            ranges = [(*l_min, *l_max), (*a_min, *a_max), (*b_min, *b_max)];
+        }
+
+        LabParams { white_point, ranges }
+    }
+}
+
+/// Multiply two 3x3 matrices
+fn mat3_mul(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+/// Matrix taking XYZ relative to `white_point` to linear sRGB, adapting the
+/// white point to D65 with the Bradford transform
+fn xyz_to_linear_srgb(white_point: [f32; 3]) -> [[f32; 3]; 3] {
+    const BRADFORD: [[f32; 3]; 3] =
+        [[0.8951, 0.2664, -0.1614], [-0.7502, 1.7135, 0.0367], [0.0389, -0.0685, 1.0296]];
+    const BRADFORD_INV: [[f32; 3]; 3] = [
+        [0.9869929, -0.1470543, 0.1599627],
+        [0.4323053, 0.5183603, 0.0492912],
+        [-0.0085287, 0.0400428, 0.9684867],
+    ];
+    const XYZ_TO_SRGB: [[f32; 3]; 3] =
+        [[3.2406, -1.5372, -0.4986], [-0.9689, 1.8758, 0.0415], [0.0557, -0.2040, 1.0570]];
+    const D65: [f32; 3] = [0.9505, 1.0, 1.089];
+
+    let cone = |xyz: [f32; 3]| -> [f32; 3] {
+        std::array::from_fn(|i| (0..3).map(|k| BRADFORD[i][k] * xyz[k]).sum())
+    };
+    let (source, target) = (cone(white_point), cone(D65));
+    let mut scale = [[0.0; 3]; 3];
+    for i in 0..3 {
+        scale[i][i] = target[i] / source[i];
+    }
+
+    mat3_mul(&XYZ_TO_SRGB, &mat3_mul(&BRADFORD_INV, &mat3_mul(&scale, &BRADFORD)))
+}
+
+/// Convert interleaved 8-bit Lab samples to an sRGB image
+fn lab_samples_to_rgb(
+    samples: &[u8],
+    width: u32,
+    height: u32,
+    params: &LabParams,
+) -> Result<DynamicImage, String> {
+    let inverse_f = |t: f32| {
+        if t > 6.0 / 29.0 {
+            t * t * t
+        } else {
+            3.0 * (6.0f32 / 29.0).powi(2) * (t - 4.0 / 29.0)
+        }
+    };
+    let gamma = |c: f32| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.0031308 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    };
+    let white = params.white_point;
+    let matrix = xyz_to_linear_srgb(white);
+
+    let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
+    for chunk in samples.chunks_exact(3) {
+        let [l, a, b] = std::array::from_fn(|i| {
+            let (min, max) = params.ranges[i];
+            min + chunk[i] as f32 / 255.0 * (max - min)
+        });
+
+        let fy = (l + 16.0) / 116.0;
+        let xyz = [
+            white[0] * inverse_f(fy + a / 500.0),
+            white[1] * inverse_f(fy),
+            white[2] * inverse_f(fy - b / 200.0),
+        ];
+        for row in &matrix {
+            rgb_data.push(gamma(row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2]));
+        }
+    }
+
+    let img = RgbImage::from_raw(width, height, rgb_data)
+        .ok_or("Failed to create RGB image from Lab data")?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Tint transform of a Separation or DeviceN color space, mapping its tints
+/// into the alternate color space
+struct TintTransform {
+    /// Input ranges as `[min0 max0 min1 max1 ...]`; tints are clipped to them
+    domain: Vec<f32>,
+    /// Output ranges in the same layout; empty when the function has none
+    range: Vec<f32>,
+    function: TintFunction,
+}
+
+enum TintFunction {
+    /// Type 2: exponential interpolation between C0 and C1 of a single input
+    Exponential { c0: Vec<f32>, c1: Vec<f32>, exponent: f32 },
+    /// Type 4: PostScript calculator program taking every colorant's tint
+    Calculator(Calculator),
+}
+
+/// Clip each value to its `[min max]` pair in `ranges`; values without one are left as they are
+fn clip_to_ranges(values: &mut [f32], ranges: &[f32]) {
+    for (value, range) in values.iter_mut().zip(ranges.chunks_exact(2)) {
+        *value = value.max(range[0].min(range[1])).min(range[0].max(range[1]));
+    }
+}
+
+impl TintTransform {
+    /// Map tints in 0..1, one per colorant, to alternate color space components
+    ///
+    /// Returns None when a calculator program fails for these inputs.
+    fn apply(&self, tints: &[f32]) -> Option<Vec<f32>> {
+        let mut inputs = tints.to_vec();
+        clip_to_ranges(&mut inputs, &self.domain);
+        let mut outputs = match &self.function {
+            TintFunction::Exponential { c0, c1, exponent } => {
+                let t = inputs.first()?.powf(*exponent);
+                c0.iter().zip(c1.iter()).map(|(c0, c1)| c0 + t * (c1 - c0)).collect()
+            }
+            TintFunction::Calculator(calculator) => calculator.eval(&inputs)?,
+        };
+        clip_to_ranges(&mut outputs, &self.range);
+        Some(outputs)
+    }
+}
+
+/// Parse the tint transform of a Separation or DeviceN color space array with `inputs` colorants
+///
+/// Type 2 functions (single input only) and Type 4 calculator functions are
+/// supported; other function types return None.
+fn parse_tint_transform(
+    color_space: &Object,
+    inputs: usize,
+    doc: &Document,
+    max_bytes: usize,
+) -> Option<TintTransform> {
+    let resolve = |obj: &Object| -> Option<Object> {
+        match obj {
+            Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+            other => Some(other.clone()),
+        }
+    };
+
+    let arr = match resolve(color_space)? {
+        Object::Array(arr) => arr,
+        _ => return None,
+    };
+
+    // [/Separation name alternate tintTransform] or [/DeviceN names alternate tintTransform ...]
+    let (function, program) = match resolve(arr.get(3)?)? {
+        Object::Dictionary(d) => (d, None),
+        Object::Stream(s) => {
+            let program = decompress_stream(&s, max_bytes).ok();
+            (s.dict, program)
+        }
+        _ => return None,
+    };
+
+    let get_num = |obj: &Object| -> Option<f32> {
+        match obj {
+            Object::Integer(n) => Some(*n as f32),
+            Object::Real(n) => Some(*n),
+            _ => None,
+        }
+    };
+    let get_array = |key: &[u8], default: Vec<f32>| -> Vec<f32> {
+        match function.get(key).ok().and_then(resolve) {
+            Some(Object::Array(values)) => values.iter().filter_map(get_num).collect(),
+            _ => default,
+        }
+    };
+
+    let domain = get_array(b"Domain", Vec::new());
+    let range = get_array(b"Range", Vec::new());
+    let function_type = function.get(b"FunctionType").ok().and_then(get_num)?;
+    let function = if function_type == 2.0 && inputs == 1 {
+        let c0 = get_array(b"C0", vec![0.0]);
+        let c1 = get_array(b"C1", vec![1.0]);
+        let exponent = function.get(b"N").ok().and_then(get_num).unwrap_or(1.0);
+        if c0.len() != c1.len() || c0.is_empty() {
+            return None;
+        }
+        TintFunction::Exponential { c0, c1, exponent }
+    } else if function_type == 4.0 {
+        TintFunction::Calculator(Calculator::parse(&program?)?)
+    } else {
+        return None;
+    };
+
+    Some(TintTransform { domain, range, function })
+}
+
+/// Decode Separation/DeviceN samples by mapping tints through the alternate color space
+///
+/// Every colorant's tint is fed to the tint transform. When its function type
+/// is unsupported, ink coverage is rendered as grayscale instead.
+fn decode_tinted_samples(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    bits_per_component: u32,
+    color_space: Option<&Object>,
+    doc: &Document,
+    max_bytes: usize,
+) -> Result<DynamicImage, String> {
+    if bits_per_component != 8 {
+        return Err(format!(
+            "Unsupported Separation/DeviceN format: {} bits",
+            bits_per_component
+        ));
+    }
+
+    let resolved = match color_space {
+        Some(Object::Reference(id)) => doc.get_object(*id).ok(),
+        other => other,
+    };
+
+    // Separation has one colorant; DeviceN lists its colorant names in an array
+    let components = match resolved {
+        Some(Object::Array(arr)) => match (arr.first(), arr.get(1)) {
+            (Some(Object::Name(n)), Some(Object::Array(names))) if n == b"DeviceN" => names.len(),
+            (Some(Object::Name(n)), Some(Object::Reference(id))) if n == b"DeviceN" => {
+                match doc.get_object(*id) {
+                    Ok(Object::Array(names)) => names.len(),
+                    _ => 1,
+                }
+            }
+            _ => 1,
+        },
+        _ => 1,
+    }
+    .max(1);
+
+    let pixels = (width * height) as usize;
+    let expected_size = pixels * components;
+    if data.len() < expected_size {
+        return Err(format!(
+            "Separation/DeviceN data size mismatch: got {} expected {}",
+            data.len(),
+            expected_size
+        ));
+    }
+
+    let tint_transform = resolved.and_then(|cs| parse_tint_transform(cs, components, doc, max_bytes));
+
+    // Heaviest ink coverage determines darkness
+    let coverage_gray = |samples: &[u8]| {
+        let gray = 255 - samples.iter().copied().max().unwrap_or(0);
+        [gray, gray, gray]
+    };
+    let to_rgb = |samples: &[u8]| -> [u8; 3] {
+        let Some(transform) = &tint_transform else {
+            return coverage_gray(samples);
+        };
+        let tints: Vec<f32> = samples.iter().map(|&s| s as f32 / 255.0).collect();
+        let (r, g, b) = match transform.apply(&tints).as_deref() {
+            Some(&[v]) => (v, v, v),
+            Some(&[r, g, b]) => (r, g, b),
+            Some(&[c, m, y, k]) => ((1.0 - c) * (1.0 - k), (1.0 - m) * (1.0 - k), (1.0 - y) * (1.0 - k)),
+            _ => return coverage_gray(samples),
+        };
+        [r, g, b].map(|v| (v.clamp(0.0, 1.0) * 255.0) as u8)
+    };
+
+    // Images hold few distinct tint combinations, so each is converted once
+    let mut colors: HashMap<&[u8], [u8; 3]> = HashMap::new();
+    let mut rgb_data = Vec::with_capacity(pixels * 3);
+    for samples in data[..expected_size].chunks(components) {
+        let rgb = *colors.entry(samples).or_insert_with(|| to_rgb(samples));
+        rgb_data.extend_from_slice(&rgb);
+    }
+
+    let img = RgbImage::from_raw(width, height, rgb_data)
+        .ok_or("Failed to create RGB image from Separation/DeviceN data")?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Unpack single-component samples of 1, 2, 4 or 8 bits into one byte each
+///
+/// Rows are padded to whole bytes, samples packed most significant bit first.
+/// Returns None if `data` holds fewer than `height` rows.
+fn unpack_samples(data: &[u8], width: u32, height: u32, bits_per_component: u32) -> Option<Vec<u8>> {
+    let row_bytes = (width * bits_per_component).div_ceil(8) as usize;
+    if data.len() < row_bytes * height as usize {
+        return None;
+    }
+
+    let samples_per_byte = 8 / bits_per_component as usize;
+    let mask = (1u16 << bits_per_component) - 1;
+    let mut samples = Vec::with_capacity((width * height) as usize);
+    for row in data.chunks(row_bytes).take(height as usize) {
+        for x in 0..width as usize {
+            let byte = row[x / samples_per_byte] as u16;
+            let shift = 8 - bits_per_component as usize * (x % samples_per_byte + 1);
+            samples.push(((byte >> shift) & mask) as u8);
+        }
+    }
+    Some(samples)
+}
+
+/// Decode /Indexed samples through their palette
+///
+/// The lookup table may be a string in the color space array or a reference to
+/// a (usually Flate-compressed) stream. Gray palettes give a grayscale image,
+/// RGB, CMYK and ICCBased ones an RGB image.
+fn decode_indexed_samples(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    bits_per_component: u32,
+    color_space: Option<&Object>,
+    doc: &Document,
+    max_bytes: usize,
+) -> Result<DynamicImage, String> {
+    if !matches!(bits_per_component, 1 | 2 | 4 | 8) {
+        return Err(format!("Unsupported Indexed format: {} bits", bits_per_component));
+    }
+
+    let resolved = match color_space {
+        Some(Object::Reference(id)) => doc.get_object(*id).ok(),
+        other => other,
+    };
+    let Some(Object::Array(arr)) = resolved else {
+        return Err("Indexed color space is not an array".to_string());
+    };
+    let (Some(base), Some(hival), Some(lookup)) = (arr.get(1), arr.get(2), arr.get(3)) else {
+        return Err("Indexed color space is missing entries".to_string());
+    };
+    let hival = match hival {
+        Object::Integer(n) => (*n).clamp(0, 255) as usize,
+        _ => return Err("Indexed color space has no valid hival".to_string()),
+    };
+
+    let base = match base {
+        Object::Reference(id) => doc.get_object(*id).map_err(|e| e.to_string())?,
+        other => other,
+    };
+    let base_name = get_color_space_name(base, doc);
+    let components = match base_name.as_str() {
+        "DeviceGray" | "Gray" | "CalGray" => 1,
+        "DeviceRGB" | "RGB" | "CalRGB" => 3,
+        "DeviceCMYK" | "CMYK" => 4,
+        "ICCBased" => {
+            let profile = match base {
+                Object::Array(arr) => match arr.get(1) {
+                    Some(Object::Reference(id)) => doc.get_object(*id).ok(),
+                    other => other,
+                },
+                _ => None,
+            };
+            match profile {
+                Some(Object::Stream(profile)) => match profile.dict.get(b"N") {
+                    Ok(Object::Integer(n @ (1 | 3 | 4))) => *n as usize,
+                    _ => 3,
+                },
+                _ => 3,
+            }
+        }
+        other => return Err(format!("Unsupported Indexed base color space: {}", other)),
+    };
+
+    let lookup = match lookup {
+        Object::Reference(id) => doc.get_object(*id).map_err(|e| e.to_string())?,
+        other => other,
+    };
+    let table = match lookup {
+        Object::String(bytes, _) => bytes.clone(),
+        Object::Stream(stream) => decompress_stream(stream, max_bytes)?,
+        _ => return Err("Indexed color space has no lookup table".to_string()),
+    };
+
+    // Palette entries as RGB (or gray); indices past the table read as black
+    let palette: Vec<[u8; 3]> = (0..=hival)
+        .map(|index| {
+            let entry = table.get(index * components..(index + 1) * components);
+            match entry {
+                Some([g]) => [*g, *g, *g],
+                Some([r, g, b]) => [*r, *g, *b],
+                Some([c, m, y, k]) => {
+                    let ink = |v: u8| v as f32 / 255.0;
+                    let channel = |v: u8| ((1.0 - ink(v)) * (1.0 - ink(*k)) * 255.0).round() as u8;
+                    [channel(*c), channel(*m), channel(*y)]
+                }
+                _ => [0, 0, 0],
+            }
+        })
+        .collect();
+
+    let indices = unpack_samples(data, width, height, bits_per_component).ok_or_else(|| {
+        format!(
+            "Indexed data size mismatch: got {} expected {}",
+            data.len(),
+            (width * bits_per_component).div_ceil(8) as usize * height as usize
+        )
+    })?;
+    let colors: Vec<[u8; 3]> =
+        indices.into_iter().map(|index| palette[(index as usize).min(hival)]).collect();
+
+    if components == 1 {
+        let gray = colors.iter().map(|c| c[0]).collect();
+        let img = image::GrayImage::from_raw(width, height, gray)
+            .ok_or("Failed to create grayscale image from Indexed data")?;
+        return Ok(DynamicImage::ImageLuma8(img));
+    }
+    let img = RgbImage::from_raw(width, height, colors.concat())
+        .ok_or("Failed to create RGB image from Indexed data")?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Standard IJG luminance quantization table (quality 50)
+const STD_LUMINANCE_QUANT_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// Component count and Adobe APP14 color transform read from a JPEG's headers
+struct JpegLayout {
+    components: u8,
+    /// Transform flag of an Adobe APP14 segment: 0 = none (CMYK), 1 = YCbCr, 2 = YCCK
+    adobe_transform: Option<u8>,
+}
+
+/// Read the frame component count and any Adobe APP14 marker from a JPEG
+fn read_jpeg_layout(data: &[u8]) -> Option<JpegLayout> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+
+    let mut components = None;
+    let mut adobe_transform = None;
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            break;
+        }
+        let marker = data[pos + 1];
+        // APP14 may come before or after the frame header, so read up to the first scan
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let segment_len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment_end = pos + 2 + segment_len;
+        if segment_len < 2 || segment_end > data.len() {
+            break;
+        }
+        let segment = &data[pos + 4..segment_end];
+
+        match marker {
+            // APP14: "Adobe", version, flags0, flags1, transform
+            0xEE if segment.len() >= 12 && segment.starts_with(b"Adobe") => {
+                adobe_transform = Some(segment[11]);
+            }
+            // SOF markers (excluding DHT 0xC4, JPG 0xC8 and DAC 0xCC):
+            // precision, height, width, component count
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                components = segment.get(5).copied();
+            }
+            _ => {}
+        }
+
+        pos = segment_end;
+    }
+
+    Some(JpegLayout {
+        components: components?,
+        adobe_transform,
+    })
+}
+
+/// Decode a four-component JPEG to interleaved CMYK samples as stored in the file
+fn decode_jpeg_cmyk(data: &[u8]) -> Result<(Vec<u8>, u32, u32), String> {
+    let options = zune_core::options::DecoderOptions::default()
+        .jpeg_set_out_colorspace(zune_core::colorspace::ColorSpace::CMYK)
+        .set_strict_mode(false);
+    let mut decoder =
+        zune_jpeg::JpegDecoder::new_with_options(zune_core::bytestream::ZCursor::new(data), options);
+    let samples = decoder
+        .decode()
+        .map_err(|e| format!("Failed to decode CMYK JPEG image: {:?}", e))?;
+    let (width, height) = decoder.dimensions().ok_or("CMYK JPEG has no frame header")?;
+    Ok((samples, width as u32, height as u32))
+}
+
+/// Estimate the IJG quality setting of a JPEG from its luminance quantization table
+fn estimate_jpeg_quality(data: &[u8]) -> Option<u8> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Start of scan or end of image: no DQT found before entropy-coded data
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let segment_len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment_end = pos + 2 + segment_len;
+        if segment_len < 2 || segment_end > data.len() {
+            return None;
+        }
+
+        if marker == 0xDB {
+            // DQT segment may hold several tables; use table 0 (luminance)
+            let mut table_pos = pos + 4;
+            while table_pos < segment_end {
+                let precision = data[table_pos] >> 4;
+                let table_id = data[table_pos] & 0x0F;
+                let entry_size = if precision == 0 { 1 } else { 2 };
+                let table_end = table_pos + 1 + 64 * entry_size;
+                if table_end > segment_end {
+                    return None;
+                }
+
+                if table_id == 0 {
+                    let sum: u32 = (0..64)
+                        .map(|i| {
+                            let offset = table_pos + 1 + i * entry_size;
+                            if entry_size == 1 {
+                                data[offset] as u32
+                            } else {
+                                u16::from_be_bytes([data[offset], data[offset + 1]]) as u32
+                            }
+                        })
+                        .sum();
+                    let std_sum: u32 = STD_LUMINANCE_QUANT_TABLE.iter().map(|&v| v as u32).sum();
+
+                    // Invert the IJG scaling: table = std * scale / 100
+                    let scale = sum as f32 * 100.0 / std_sum as f32;
+                    let quality = if scale <= 100.0 {
+                        (200.0 - scale) / 2.0
+                    } else {
+                        5000.0 / scale
+                    };
+                    return Some(quality.round().clamp(1.0, 100.0) as u8);
+                }
+
+                table_pos = table_end;
+            }
+        }
+
+        pos = segment_end;
+    }
+
+    None
+}
+
+/// Approximate the size of a baseline 4:2:0 JPEG from its pixel count
+///
+/// Uses a bits-per-pixel curve fitted to typical photographic content, growing
+/// from about 0.4 bpp at low quality to about 3 bpp at quality 100.
+fn estimate_jpeg_bytes(width: u32, height: u32, quality: u8) -> usize {
+    let q = quality.clamp(1, 100) as f64 / 100.0;
+    let bits_per_pixel = 0.4 + 2.6 * q * q;
+    let pixels = width as f64 * height as f64;
+    // Headers and quantization/Huffman tables
+    (pixels * bits_per_pixel / 8.0) as usize + 600
+}
+
+/// Pick a sampling factor for an image: full chroma where it costs little
+///
+/// Small images and nearly colorless content (scans, diagrams) keep 4:4:4 since
+/// their chroma adds few bytes; larger color images use 4:2:0.
+fn auto_sampling_factor(rgb: &RgbImage) -> jpeg_encoder::SamplingFactor {
+    const SMALL_IMAGE_PIXELS: u64 = 512 * 512;
+    const MAX_COLORLESS_CHROMA: f64 = 4.0;
+
+    let (width, height) = rgb.dimensions();
+    if (width as u64 * height as u64) <= SMALL_IMAGE_PIXELS {
+        return jpeg_encoder::SamplingFactor::R_4_4_4;
+    }
+
+    // Mean channel spread over a sparse sample of pixels
+    let step = ((width as u64 * height as u64) / 65536).max(1) as usize;
+    let (total, count) = rgb
+        .pixels()
+        .step_by(step)
+        .fold((0u64, 0u64), |(total, count), p| {
+            let max = p[0].max(p[1]).max(p[2]);
+            let min = p[0].min(p[1]).min(p[2]);
+            (total + (max - min) as u64, count + 1)
+        });
+    if count > 0 && (total as f64 / count as f64) <= MAX_COLORLESS_CHROMA {
+        jpeg_encoder::SamplingFactor::R_4_4_4
+    } else {
+        jpeg_encoder::SamplingFactor::R_4_2_0
+    }
+}
+
+/// Image size as JPEG frame header fields, which cannot exceed 65535
+fn jpeg_dimensions(width: u32, height: u32) -> Result<(u16, u16), String> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(format!("{}x{} is too large for JPEG (max 65535 per side)", width, height)),
+    }
+}
+
+/// Samples for [`encode_jpeg`]
+enum JpegPixels<'a> {
+    /// Interleaved RGB, with its chroma subsampling
+    Rgb(&'a [u8], jpeg_encoder::SamplingFactor),
+    /// A single gray or alpha channel
+    Gray(&'a [u8]),
+}
+
+/// Encode pixels as a baseline or progressive JPEG
+#[cfg(not(feature = "mozjpeg"))]
+fn encode_jpeg(
+    pixels: JpegPixels,
+    width: u32,
+    height: u32,
+    quality: u8,
+    progressive: bool,
+    optimize_huffman: bool,
+) -> Result<Vec<u8>, String> {
+    let (width, height) = jpeg_dimensions(width, height)?;
+    let mut jpeg_bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg_bytes, quality);
+    encoder.set_progressive(progressive);
+    encoder.set_optimized_huffman_tables(optimize_huffman);
+    let (samples, color_type) = match pixels {
+        JpegPixels::Rgb(samples, sampling_factor) => {
+            encoder.set_sampling_factor(sampling_factor);
+            (samples, jpeg_encoder::ColorType::Rgb)
+        }
+        JpegPixels::Gray(samples) => (samples, jpeg_encoder::ColorType::Luma),
+    };
+    encoder
+        .encode(samples, width, height, color_type)
+        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+    Ok(jpeg_bytes)
+}
+
+/// Encode pixels as a baseline or progressive JPEG with mozjpeg, whose
+/// trellis quantization gives smaller files at the same quality setting; it
+/// always optimizes Huffman tables
+#[cfg(feature = "mozjpeg")]
+fn encode_jpeg(
+    pixels: JpegPixels,
+    width: u32,
+    height: u32,
+    quality: u8,
+    progressive: bool,
+    _optimize_huffman: bool,
+) -> Result<Vec<u8>, String> {
+    use jpeg_encoder::SamplingFactor;
+
+    let (width, height) = jpeg_dimensions(width, height)?;
+
+    // libjpeg reports errors by unwinding
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let (mut compress, samples) = match pixels {
+            JpegPixels::Rgb(samples, sampling_factor) => {
+                // Chroma "pixel" size per luma pixel, for Cb and Cr alike
+                let chroma_size = match sampling_factor {
+                    SamplingFactor::R_4_4_4 => (1, 1),
+                    SamplingFactor::R_4_2_2 => (2, 1),
+                    _ => (2, 2),
+                };
+                let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+                compress.set_chroma_sampling_pixel_sizes(chroma_size, chroma_size);
+                (compress, samples)
+            }
+            JpegPixels::Gray(samples) => {
+                (mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_GRAYSCALE), samples)
+            }
+        };
+        compress.set_size(width as usize, height as usize);
+        compress.set_quality(quality as f32);
+        if progressive {
+            compress.set_progressive_mode();
+        } else {
+            // mozjpeg defaults to progressive scans
+            compress.set_optimize_scans(false);
+        }
+        let mut started = compress.start_compress(Vec::new())?;
+        started.write_scanlines(samples)?;
+        started.finish()
+    }))
+    .map_err(|_| "Failed to encode JPEG: mozjpeg aborted".to_string())?
+    .map_err(|e| format!("Failed to encode JPEG: {}", e))
+}
+
+/// Encode an image as JPEG and create a PDF stream
+fn encode_as_jpeg_stream(
+    img: &DynamicImage,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+    progressive: bool,
+    optimize_huffman: bool,
+) -> Result<(Stream, u32, u32), String> {
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    let sampling_factor = match subsampling {
+        ChromaSubsampling::Auto => auto_sampling_factor(&rgb),
+        ChromaSubsampling::S444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        ChromaSubsampling::S422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+        ChromaSubsampling::S420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+    };
+
+    let pixels = JpegPixels::Rgb(rgb.as_raw(), sampling_factor);
+    let jpeg_bytes = encode_jpeg(pixels, width, height, quality, progressive, optimize_huffman)?;
+
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(width as i64));
+    dict.set("Height", Object::Integer(height as i64));
+    dict.set("ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
+    dict.set("BitsPerComponent", Object::Integer(8));
+    dict.set("Filter", Object::Name(b"DCTDecode".to_vec()));
+    dict.set("Length", Object::Integer(jpeg_bytes.len() as i64));
+
+    Ok((Stream::new(dict, jpeg_bytes), width, height))
+}
+
+/// Encode an image as a DeviceGray JPEG stream
+fn encode_as_gray_jpeg_stream(
+    img: &DynamicImage,
+    quality: u8,
+    progressive: bool,
+    optimize_huffman: bool,
+) -> Result<(Stream, u32, u32), String> {
+    let gray = img.to_luma8();
+    let (width, height) = gray.dimensions();
+    let pixels = JpegPixels::Gray(gray.as_raw());
+    let jpeg_bytes = encode_jpeg(pixels, width, height, quality, progressive, optimize_huffman)?;
+
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(width as i64));
+    dict.set("Height", Object::Integer(height as i64));
+    dict.set("ColorSpace", Object::Name(b"DeviceGray".to_vec()));
+    dict.set("BitsPerComponent", Object::Integer(8));
+    dict.set("Filter", Object::Name(b"DCTDecode".to_vec()));
+    dict.set("Length", Object::Integer(jpeg_bytes.len() as i64));
+
+    Ok((Stream::new(dict, jpeg_bytes), width, height))
+}
+
+/// Create an SMask stream for the alpha channel using JPEG compression
+fn create_smask_stream(
+    alpha_data: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    optimize_huffman: bool,
+) -> Result<Stream, String> {
+    let jpeg_bytes =
+        encode_jpeg(JpegPixels::Gray(alpha_data), width, height, quality, false, optimize_huffman)
+            .map_err(|e| format!("SMask: {}", e))?;
+
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(width as i64));
+    dict.set("Height", Object::Integer(height as i64));
+    dict.set("ColorSpace", Object::Name(b"DeviceGray".to_vec()));
+    dict.set("BitsPerComponent", Object::Integer(8));
+    dict.set("Filter", Object::Name(b"DCTDecode".to_vec()));
+    dict.set("Length", Object::Integer(jpeg_bytes.len() as i64));
+
+    Ok(Stream::new(dict, jpeg_bytes))
+}
+
+/// Encode an image with alpha, as DeviceGray rather than DeviceRGB if `gray`
+fn encode_with_alpha_stream(
+    img: &DynamicImage,
+    quality: u8,
+    gray: bool,
+    optimize_huffman: bool,
+) -> Result<(Stream, Option<Stream>, u32, u32), String> {
+    let (width, height) = (img.width(), img.height());
+
+    // Separate color and alpha channels
+    let mut color_data = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha_data = Vec::with_capacity((width * height) as usize);
+
+    if gray {
+        for pixel in img.to_luma_alpha8().pixels() {
+            color_data.push(pixel[0]);
+            alpha_data.push(pixel[1]);
+        }
+    } else {
+        for chunk in img.to_rgba8().into_raw().chunks(4) {
+            color_data.push(chunk[0]);
+            color_data.push(chunk[1]);
+            color_data.push(chunk[2]);
+            alpha_data.push(chunk[3]);
+        }
+    }
+
+    // Compress the color channels with FlateDecode
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    std::io::Write::write_all(&mut encoder, &color_data)
+        .map_err(|e| format!("Failed to compress color data: {}", e))?;
+    let compressed_color = encoder
+        .finish()
+        .map_err(|e| format!("Failed to finish compression: {}", e))?;
+
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(width as i64));
+    dict.set("Height", Object::Integer(height as i64));
+    let color_space: &[u8] = if gray { b"DeviceGray" } else { b"DeviceRGB" };
+    dict.set("ColorSpace", Object::Name(color_space.to_vec()));
+    dict.set("BitsPerComponent", Object::Integer(8));
+    dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+    dict.set("Length", Object::Integer(compressed_color.len() as i64));
+
+    let main_stream = Stream::new(dict, compressed_color);
+    let smask_stream =
+        create_smask_stream(&alpha_data, width, height, quality, optimize_huffman)?;
+
+    Ok((main_stream, Some(smask_stream), width, height))
+}
+
+/// Encode an opaque image losslessly as a FlateDecode DeviceRGB or DeviceGray stream
+fn encode_as_flate_stream(img: &DynamicImage, gray: bool) -> Result<(Stream, u32, u32), String> {
+    let (width, height) = (img.width(), img.height());
+    let samples = if gray { img.to_luma8().into_raw() } else { img.to_rgb8().into_raw() };
+
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    std::io::Write::write_all(&mut encoder, &samples)
+        .map_err(|e| format!("Failed to compress image data: {}", e))?;
+    let compressed = encoder
+        .finish()
+        .map_err(|e| format!("Failed to finish compression: {}", e))?;
+
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(width as i64));
+    dict.set("Height", Object::Integer(height as i64));
+    let color_space: &[u8] = if gray { b"DeviceGray" } else { b"DeviceRGB" };
+    dict.set("ColorSpace", Object::Name(color_space.to_vec()));
+    dict.set("BitsPerComponent", Object::Integer(8));
+    dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+    dict.set("Length", Object::Integer(compressed.len() as i64));
+
+    Ok((Stream::new(dict, compressed), width, height))
+}
+
+/// JPEG settings for [`encode_image_for_pdf`]
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// JPEG quality (1-100)
+    pub quality: u8,
+    /// Chroma subsampling for color JPEGs
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Write a progressive rather than baseline JPEG
+    pub progressive_jpeg: bool,
+    /// Compute optimized Huffman tables for each JPEG
+    pub optimize_huffman: bool,
+    /// Store opaque images as DeviceGray, as `detect_grayscale` does for neutral images
+    pub grayscale: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions::from(&ResampleOptions::default())
+    }
+}
+
+impl From<&ResampleOptions> for EncodeOptions {
+    fn from(options: &ResampleOptions) -> Self {
+        Self {
+            quality: options.quality,
+            chroma_subsampling: options.chroma_subsampling,
+            progressive_jpeg: options.progressive_jpeg,
+            optimize_huffman: options.optimize_huffman,
+            grayscale: false,
+        }
+    }
+}
+
+/// An image encoded the way resampling writes it into a PDF
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    /// The image XObject: encoded bytes plus the dictionary (filter, color
+    /// space, size) they are stored with
+    pub stream: Stream,
+    /// JPEG soft mask holding the alpha channel, for images with transparency;
+    /// the image's /SMask would reference it
+    pub smask: Option<Stream>,
+}
+
+/// Encode a single image as resampling would, without building a PDF
+///
+/// Images with transparency become FlateDecode RGB with a JPEG soft mask;
+/// opaque images become DCTDecode JPEGs. Useful for tuning quality settings.
+pub fn encode_image_for_pdf(
+    img: &DynamicImage,
+    options: &EncodeOptions,
+) -> Result<EncodedImage, ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    let encoded = if has_alpha(img) {
+        encode_with_alpha_stream(img, options.quality, false, options.optimize_huffman)
+            .map(|(stream, smask, _, _)| (stream, smask))
+    } else if options.grayscale {
+        encode_as_gray_jpeg_stream(
+            img,
+            options.quality,
+            options.progressive_jpeg,
+            options.optimize_huffman,
+        )
+        .map(|(stream, _, _)| (stream, None))
+    } else {
+        encode_as_jpeg_stream(
+            img,
+            options.quality,
+            options.chroma_subsampling,
+            options.progressive_jpeg,
+            options.optimize_huffman,
+        )
+        .map(|(stream, _, _)| (stream, None))
+    };
+    let (stream, smask) = encoded.map_err(ResampleError::ProcessingError)?;
+    Ok(EncodedImage { stream, smask })
+}
+
+/// Minimal ICC v2 sRGB profile: D50-adapted sRGB primaries and a 64-point
+/// tone curve, shared by every image tagged with `embed_srgb`
+const SRGB_ICC_PROFILE: &[u8] = include_bytes!("srgb.icc");
+
+/// Get an `[/ICCBased ...]` color space for the bundled sRGB profile, adding
+/// the profile stream to the document on first use
+fn srgb_color_space(doc: &mut Document, profile_id: &mut Option<ObjectId>) -> Object {
+    let id = *profile_id.get_or_insert_with(|| {
+        let mut dict = lopdf::Dictionary::new();
+        dict.set("N", Object::Integer(3));
+        dict.set("Alternate", Object::Name(b"DeviceRGB".to_vec()));
+        doc.add_object(Stream::new(dict, SRGB_ICC_PROFILE.to_vec()))
+    });
+    Object::Array(vec![Object::Name(b"ICCBased".to_vec()), Object::Reference(id)])
+}
+
+/// Image dictionary entries carried over from the original stream when re-encoding
+/// /OPI links prepress workflows to high-resolution replacement images;
+/// /Intent selects the rendering intent used for color management;
+/// /OC ties the image to an optional content group (layer) and /ID identifies
+/// web capture content
+const PRESERVED_IMAGE_KEYS: &[&[u8]] = &[b"OPI", b"Intent", b"OC", b"ID"];
+
+/// Copy preserved entries from the original image dictionary onto a re-encoded one
+///
+/// Re-encoded dictionaries are built from scratch, so nothing else carries over:
+/// the original /Filter, /DecodeParms and /Length never describe the new data.
+/// `color_space` is the original image's color space. The re-encoded image is
+/// DeviceRGB, or DeviceGray if `gray_output`, so /Decode is only kept when it
+/// can be mapped onto that. (Images converted to DeviceGray by
+/// `detect_grayscale` never have a /Decode; ones converted by
+/// `force_color_space` keep a gray /Decode and lose an RGB one.)
+fn copy_preserved_entries(
+    original: &Dictionary,
+    new_dict: &mut Dictionary,
+    color_space: &str,
+    gray_output: bool,
+) {
+    for key in PRESERVED_IMAGE_KEYS {
+        if let Ok(value) = original.get(key) {
+            new_dict.set(*key, value.clone());
+        }
+    }
+
+    // A stencil mask is independent of the image's resolution and encoding;
+    // images with a color key mask array are skipped by `plan_image`
+    if let Ok(mask @ Object::Reference(_)) = original.get(b"Mask") {
+        new_dict.set("Mask", mask.clone());
+    }
+
+    // Samples are copied to RGB without applying /Decode, so the original ranges
+    // still apply per channel; gray ranges are repeated for each RGB channel
+    if let Ok(Object::Array(decode)) = original.get(b"Decode") {
+        let decode = match (color_space, decode.len()) {
+            ("DeviceGray" | "Gray" | "CalGray", 2) if gray_output => Some(decode.clone()),
+            _ if gray_output => None,
+            ("DeviceRGB" | "RGB" | "CalRGB", 6) => Some(decode.clone()),
+            ("DeviceGray" | "Gray" | "CalGray", 2) => Some(decode.iter().cycle().take(6).cloned().collect()),
+            _ => None,
+        };
+        if let Some(decode) = decode {
+            new_dict.set("Decode", Object::Array(decode));
+        }
+    }
+}
+
+/// JPEG quality for an image
+///
+/// Grayscale images are usually scanned text, which low quality makes unreadable,
+/// so `adaptive_quality` raises them to the gray floor; images that were already
+/// JPEG get `jpeg_source_quality_bonus` on top.
+fn image_quality(options: &ResampleOptions, is_grayscale: bool, from_jpeg: bool) -> u8 {
+    let mut quality = options.quality;
+    if options.adaptive_quality && is_grayscale {
+        quality = quality.max(options.gray_quality_floor.min(100));
+    }
+    if from_jpeg {
+        quality = quality.saturating_add(options.jpeg_source_quality_bonus).min(100);
+    }
+    quality
+}
+
+/// Name of the first filter in an image dictionary's /Filter entry
+fn first_filter(dict: &Dictionary) -> Option<String> {
+    dict.get(b"Filter").ok().and_then(|f| match f {
+        Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+        Object::Array(arr) => arr.first().and_then(|f| match f {
+            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+/// Target DPI for an image: `gray_target_dpi` for grayscale color spaces when set,
+/// otherwise `target_dpi`
+fn image_target_dpi(doc: &Document, dict: &Dictionary, options: &ResampleOptions) -> f32 {
+    match options.gray_target_dpi {
+        Some(gray_dpi) if has_gray_color_space(doc, dict) => gray_dpi,
+        _ => options.target_dpi,
+    }
+}
+
+/// Check whether an image's /ColorSpace has a single gray component
+fn has_gray_color_space(doc: &Document, dict: &Dictionary) -> bool {
+    let resolve = |obj: &Object| -> Option<Object> {
+        match obj {
+            Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+            other => Some(other.clone()),
+        }
+    };
+    let Some(color_space) = dict.get(b"ColorSpace").ok().and_then(resolve) else {
+        return false;
+    };
+    match get_color_space_name(&color_space, doc).as_str() {
+        "DeviceGray" | "Gray" | "CalGray" => true,
+        "ICCBased" => {
+            let Object::Array(arr) = &color_space else {
+                return false;
+            };
+            let profile = match arr.get(1) {
+                Some(Object::Reference(id)) => doc.get_object(*id).ok(),
+                other => other,
+            };
+            matches!(profile, Some(Object::Stream(profile)) if profile.dict.get(b"N").ok() == Some(&Object::Integer(1)))
+        }
+        _ => false,
+    }
+}
+
+/// Read a non-negative integer entry such as /Width, following an indirect
+/// reference, since some producers store image dimensions as separate objects
+fn get_int_resolved(dict: &Dictionary, key: &[u8], doc: &Document) -> Option<u32> {
+    let value = match dict.get(key).ok()? {
+        Object::Reference(id) => doc.get_object(*id).ok()?,
+        other => other,
+    };
+    match value {
+        Object::Integer(n) => u32::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+/// Get color space name from PDF object
+fn get_color_space_name(obj: &Object, doc: &Document) -> String {
+    match obj {
+        Object::Name(name) => String::from_utf8_lossy(name).to_string(),
+        Object::Array(arr) => {
+            if let Some(Object::Name(name)) = arr.first() {
+                String::from_utf8_lossy(name).to_string()
+            } else {
+                "Unknown".to_string()
+            }
+        }
+        Object::Reference(id) => {
+            if let Ok(resolved) = doc.get_object(*id) {
+                get_color_space_name(resolved, doc)
+            } else {
+                "Unknown".to_string()
+            }
+        }
+        _ => "Unknown".to_string(),
+    }
+}
+
+/// Whether an image holds 1-bit samples or is a stencil mask, i.e. line art
+/// or text that lossy encoding would visibly damage
+///
+/// Only 1-bit gray and Indexed images reach the encoder today: stencil masks
+/// are turned away by `undecodable_reason`, so for them this guard is only
+/// future-proofing.
+fn is_bilevel(dict: &Dictionary, bits_per_component: u32) -> bool {
+    bits_per_component == 1 || matches!(dict.get(b"ImageMask"), Ok(Object::Boolean(true)))
+}
+
+/// Check if an image has meaningful alpha
+///
+/// Every pixel is checked: a sparse sample can miss a small transparent corner
+/// and flatten the image to JPEG, and the scan costs little next to decoding.
+fn has_alpha(img: &DynamicImage) -> bool {
+    match img {
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|p| p.0[3] < 255),
+        DynamicImage::ImageLumaA8(la) => la.pixels().any(|p| p.0[1] < 255),
+        _ => false,
+    }
+}
+
+/// Composite an image over an opaque `background` color
+fn flatten_over(img: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let mut rgb = RgbImage::new(rgba.width(), rgba.height());
+    for (out, pixel) in rgb.pixels_mut().zip(rgba.pixels()) {
+        let alpha = pixel[3] as u32;
+        for c in 0..3 {
+            let blended = pixel[c] as u32 * alpha + background[c] as u32 * (255 - alpha);
+            out[c] = ((blended + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Largest channel spread (max - min of R, G, B) still considered neutral gray;
+/// leaves room for the color noise of scanners and JPEG
+const GRAYSCALE_TOLERANCE: u8 = 12;
+
+/// Check whether every sampled pixel is near-neutral gray
+///
+/// Samples up to 100000 evenly spaced pixels, dense enough that small colored
+/// marks such as stamps or signatures are not missed.
+fn is_near_neutral(img: &DynamicImage) -> bool {
+    let neutral = |r: u8, g: u8, b: u8| r.max(g).max(b) - r.min(g).min(b) <= GRAYSCALE_TOLERANCE;
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => true,
+        DynamicImage::ImageRgb8(rgb) => {
+            let sample_rate = std::cmp::max(1, rgb.pixels().len() / 100000);
+            rgb.pixels().step_by(sample_rate).all(|p| neutral(p.0[0], p.0[1], p.0[2]))
+        }
+        DynamicImage::ImageRgba8(rgba) => {
+            let sample_rate = std::cmp::max(1, rgba.pixels().len() / 100000);
+            rgba.pixels().step_by(sample_rate).all(|p| neutral(p.0[0], p.0[1], p.0[2]))
+        }
+        other => is_near_neutral(&DynamicImage::ImageRgb8(other.to_rgb8())),
+    }
+}
+
+/// Scale dimensions down to fit within a total pixel count, preserving aspect ratio
+/// Returns None if the dimensions are already within the cap
+fn cap_pixel_count(width: u32, height: u32, max_pixels: u32) -> Option<(u32, u32)> {
+    let pixels = width as u64 * height as u64;
+    if pixels <= max_pixels as u64 {
+        return None;
+    }
+
+    let scale = (max_pixels as f64 / pixels as f64).sqrt();
+    let capped_w = ((width as f64 * scale).floor() as u32).max(1);
+    let capped_h = ((height as f64 * scale).floor() as u32).max(1);
+
+    Some((capped_w, capped_h))
+}
+
+/// Scale dimensions down so the longest side fits within a limit, preserving aspect ratio
+/// Returns None if the dimensions are already within the limit
+fn cap_long_edge(width: u32, height: u32, max_long_edge: u32) -> Option<(u32, u32)> {
+    let long_edge = width.max(height);
+    if long_edge <= max_long_edge {
+        return None;
+    }
+
+    let scale = max_long_edge as f64 / long_edge as f64;
+    let capped_w = ((width as f64 * scale).round() as u32).clamp(1, max_long_edge.max(1));
+    let capped_h = ((height as f64 * scale).round() as u32).clamp(1, max_long_edge.max(1));
+
+    Some((capped_w, capped_h))
+}
+
+/// Resample an image to target dimensions
+/// Reductions beyond `area_average_above_ratio` are split in two: a fast area
+/// average down to twice the target size, then Lanczos3 for the last step
+fn resample_image(
+    img: &DynamicImage,
+    target_width: u32,
+    target_height: u32,
+    area_average_above_ratio: Option<f32>,
+) -> DynamicImage {
+    let ratio = (img.width() as f32 / target_width as f32)
+        .min(img.height() as f32 / target_height as f32);
+
+    match area_average_above_ratio {
+        Some(threshold) if ratio > threshold && ratio > 2.0 => img
+            .thumbnail_exact(target_width * 2, target_height * 2)
+            .resize_exact(target_width, target_height, image::imageops::FilterType::Lanczos3),
+        _ => img.resize_exact(
+            target_width,
+            target_height,
+            image::imageops::FilterType::Lanczos3,
+        ),
+    }
+}
+
+/// Merge image XObjects with identical dictionaries and content into a single object
+/// References to duplicates are rewritten to point at the lowest-numbered copy
+/// Returns a map from each removed duplicate to the object that replaced it
+fn deduplicate_images(doc: &mut Document) -> HashMap<ObjectId, ObjectId> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut remap: HashMap<ObjectId, ObjectId> = HashMap::new();
+
+    // Repeat so that images whose SMasks were merged in one pass can merge in the next
+    loop {
+        let mut by_hash: HashMap<u64, Vec<ObjectId>> = HashMap::new();
+
+        for (id, object) in doc.objects.iter() {
+            if let Object::Stream(stream) = object {
+                let is_image = matches!(stream.dict.get(b"Subtype"), Ok(Object::Name(n)) if n == b"Image");
+                if is_image {
+                    let mut hasher = DefaultHasher::new();
+                    stream.content.hash(&mut hasher);
+                    by_hash.entry(hasher.finish()).or_default().push(*id);
+                }
+            }
+        }
+
+        let mut pass_remap: HashMap<ObjectId, ObjectId> = HashMap::new();
+
+        for ids in by_hash.values().filter(|ids| ids.len() > 1) {
+            let mut representatives: Vec<ObjectId> = Vec::new();
+            for &id in ids {
+                let stream = match doc.objects.get(&id) {
+                    Some(Object::Stream(s)) => s,
+                    _ => continue,
+                };
+                let existing = representatives.iter().find(|rep_id| match doc.objects.get(rep_id) {
+                    Some(Object::Stream(rep)) => rep.dict == stream.dict && rep.content == stream.content,
+                    _ => false,
+                });
+                match existing {
+                    Some(&rep_id) => {
+                        pass_remap.insert(id, rep_id);
+                    }
+                    None => representatives.push(id),
+                }
+            }
+        }
+
+        if pass_remap.is_empty() {
+            break;
+        }
+
+        for object in doc.objects.values_mut() {
+            remap_references(object, &pass_remap);
+        }
+        for (_, value) in doc.trailer.iter_mut() {
+            remap_references(value, &pass_remap);
+        }
+        for id in pass_remap.keys() {
+            doc.objects.remove(id);
+        }
+
+        // Keep earlier entries pointing at the final surviving object
+        for target in remap.values_mut() {
+            if let Some(new_target) = pass_remap.get(target) {
+                *target = *new_target;
+            }
+        }
+        remap.extend(pass_remap);
+    }
+
+    remap
+}
+
+/// Rewrite references inside an object according to an ID map
+fn remap_references(object: &mut Object, remap: &HashMap<ObjectId, ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            if let Some(new_id) = remap.get(id) {
+                *id = *new_id;
+            }
+        }
+        Object::Array(arr) => {
+            for item in arr.iter_mut() {
+                remap_references(item, remap);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                remap_references(value, remap);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter_mut() {
+                remap_references(value, remap);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace references to any of `ids` inside an object with null
+fn null_references(object: &mut Object, ids: &HashSet<ObjectId>) {
+    match object {
+        Object::Reference(id) if ids.contains(id) => *object = Object::Null,
+        Object::Array(arr) => {
+            for item in arr.iter_mut() {
+                null_references(item, ids);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                null_references(value, ids);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter_mut() {
+                null_references(value, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Add every object ID referenced from `object` to `refs`
+fn collect_references(object: &Object, refs: &mut HashSet<ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            refs.insert(*id);
+        }
+        Object::Array(arr) => {
+            for item in arr {
+                collect_references(item, refs);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter() {
+                collect_references(value, refs);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter() {
+                collect_references(value, refs);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Remove image XObjects (soft masks included) not reachable from the trailer
+///
+/// Only images are removed, so unreferenced objects of other kinds are written
+/// out as before. Returns the number of objects removed.
+fn remove_unreachable_images(doc: &mut Document) -> usize {
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    collect_references(&Object::Dictionary(doc.trailer.clone()), &mut reachable);
+    pending.extend(reachable.iter().copied());
+    while let Some(id) = pending.pop() {
+        let Some(object) = doc.objects.get(&id) else {
+            continue;
+        };
+        let mut refs = HashSet::new();
+        collect_references(object, &mut refs);
+        pending.extend(refs.into_iter().filter(|r| reachable.insert(*r)));
+    }
+
+    let unreachable: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(id, object)| {
+            !reachable.contains(id)
+                && matches!(object, Object::Stream(s)
+                    if matches!(s.dict.get(b"Subtype"), Ok(Object::Name(n)) if n == b"Image"))
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    for id in &unreachable {
+        doc.objects.remove(id);
+    }
+    unreachable.len()
+}
+
+/// Remove the /Thumb entry of every page along with its image
+///
+/// Returns the number of thumbnail images removed.
+fn remove_thumbnails(doc: &mut Document) -> usize {
+    let mut thumbs = HashSet::new();
+    for page_id in doc.get_pages().into_values() {
+        if let Ok(Object::Dictionary(page)) = doc.get_object_mut(page_id) {
+            if let Some(Object::Reference(id)) = page.remove(b"Thumb") {
+                thumbs.insert(id);
+            }
+        }
+    }
+    for id in &thumbs {
+        doc.objects.remove(id);
+    }
+    thumbs.len()
+}
+
+/// Remove the document /Info dictionary and the catalog's XMP /Metadata stream
+fn strip_metadata(doc: &mut Document) {
+    if let Some(Object::Reference(id)) = doc.trailer.remove(b"Info") {
+        doc.objects.remove(&id);
+    }
+
+    let metadata = doc
+        .catalog_mut()
+        .ok()
+        .and_then(|catalog| catalog.remove(b"Metadata"));
+    if let Some(Object::Reference(id)) = metadata {
+        doc.objects.remove(&id);
+    }
+}
+
+/// Renumber objects so the catalog, the first page and everything it uses come first
+///
+/// lopdf cannot write true linearized files (no linearization dictionary or hint
+/// tables), but since objects are written in ID order this puts the data needed
+/// for the first page at the start of the file, which is what progressive
+/// viewers read first. Remaining objects keep their relative order.
+fn order_objects_for_first_page(doc: &mut Document) {
+    // Depth-first in reading order, with an explicit stack so a long chain of
+    // references (forms nested thousands deep) cannot overflow the call stack
+    fn visit<'a>(
+        doc: &'a Document,
+        object: &'a Object,
+        order: &mut Vec<ObjectId>,
+        visited: &mut HashSet<ObjectId>,
+    ) {
+        let mut stack = vec![object];
+        while let Some(object) = stack.pop() {
+            let dict = match object {
+                Object::Reference(id) => {
+                    if visited.insert(*id) {
+                        order.push(*id);
+                        if let Ok(child) = doc.get_object(*id) {
+                            stack.push(child);
+                        }
+                    }
+                    continue;
+                }
+                Object::Array(arr) => {
+                    stack.extend(arr.iter().rev());
+                    continue;
+                }
+                Object::Dictionary(dict) => dict,
+                Object::Stream(stream) => &stream.dict,
+                _ => continue,
+            };
+            // Following /Parent from a page would pull in the whole page tree
+            let values: Vec<&Object> = dict
+                .iter()
+                .filter(|(key, _)| key.as_slice() != b"Parent")
+                .map(|(_, value)| value)
+                .collect();
+            stack.extend(values.into_iter().rev());
+        }
+    }
+
+    let mut order: Vec<ObjectId> = Vec::new();
+    let mut visited: HashSet<ObjectId> = HashSet::new();
+
+    let root = doc.trailer.get(b"Root").ok().cloned();
+    if let Some(Object::Reference(root_id)) = root {
+        visited.insert(root_id);
+        order.push(root_id);
+
+        // Page tree nodes leading to the first page, then the page itself
+        if let Some(first_page) = doc.get_pages().values().next().copied() {
+            let mut ancestors = Vec::new();
+            let mut node = first_page;
+            while let Ok(Object::Reference(parent)) = doc
+                .get_dictionary(node)
+                .and_then(|d| d.get(b"Parent"))
+            {
+                if ancestors.contains(parent) {
+                    break;
+                }
+                ancestors.push(*parent);
+                node = *parent;
+            }
+            for id in ancestors.into_iter().rev() {
+                if visited.insert(id) {
+                    order.push(id);
+                }
+            }
+            visit(doc, &Object::Reference(first_page), &mut order, &mut visited);
+        }
+
+        // Everything else reachable from the catalog (remaining pages, outlines, ...)
+        if let Ok(catalog) = doc.get_object(root_id) {
+            visit(doc, catalog, &mut order, &mut visited);
+        }
+    }
+
+    // Page tree nodes were only added on the path to the first page; unreachable
+    // and remaining objects keep their original order
+    for id in doc.objects.keys() {
+        if visited.insert(*id) {
+            order.push(*id);
+        }
+    }
+    order.retain(|id| doc.objects.contains_key(id));
+
+    let remap: HashMap<ObjectId, ObjectId> = order
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id, (i as u32 + 1, 0)))
+        .collect();
+
+    // A reference to a missing object would keep its old number and so point
+    // at whichever object is renumbered into it; it becomes null instead
+    let mut dangling = HashSet::new();
+    collect_references(&Object::Dictionary(doc.trailer.clone()), &mut dangling);
+    for object in doc.objects.values() {
+        collect_references(object, &mut dangling);
+    }
+    dangling.retain(|id| !remap.contains_key(id));
+
+    let old_objects = std::mem::take(&mut doc.objects);
+    for (id, mut object) in old_objects {
+        null_references(&mut object, &dangling);
+        remap_references(&mut object, &remap);
+        doc.objects.insert(remap[&id], object);
+    }
+    for (_, value) in doc.trailer.iter_mut() {
+        null_references(value, &dangling);
+        remap_references(value, &remap);
+    }
+    doc.max_id = order.len() as u32;
+}
+
+/// Merge identical images in a document and carry their display info over
+///
+/// Returns the updated display info map and the number of images merged away.
+fn merge_duplicate_images(
+    doc: &mut Document,
+    display_info_map: &HashMap<ObjectId, ImageDisplayInfo>,
+) -> (HashMap<ObjectId, ImageDisplayInfo>, usize) {
+    let duplicates = deduplicate_images(doc);
+    let mut display_info_map = display_info_map.clone();
+    for (duplicate, kept) in &duplicates {
+        // The surviving image must satisfy the largest display size of any copy
+        if let Some(info) = display_info_map.remove(duplicate) {
+            let area = info.display_width_points * info.display_height_points;
+            let keep_existing = display_info_map.get(kept).is_some_and(|existing| {
+                existing.display_width_points * existing.display_height_points >= area
+            });
+            if !keep_existing {
+                display_info_map.insert(*kept, info);
+            }
+        }
+    }
+    (display_info_map, duplicates.len())
+}
+
+/// Give the small placements of a multi-use image their own copy of it
+///
+/// Otherwise an image drawn both full-page and as a thumbnail keeps the
+/// resolution its largest placement needs everywhere. Placements are grouped
+/// by the page or form whose resources name the image; groups drawing it at no
+/// more than half the largest size are pointed at a copy, which processing then
+/// downsamples for them alone. A form or pattern shares one copy across all
+/// its uses, sized for the largest of them.
+///
+/// Returns the display info map, rescanned if anything was split, and the
+/// number of copies made.
+fn split_multiuse_images(
+    doc: &mut Document,
+    display_info_map: HashMap<ObjectId, ImageDisplayInfo>,
+    options: &ResampleOptions,
+) -> (HashMap<ObjectId, ImageDisplayInfo>, usize) {
+    // Image -> owner -> largest area drawn by that owner
+    let owner_areas = {
+        let mut scanner = ContentScanner::new(doc, false).with_limits(options);
+        scanner.scan_all_pages();
+
+        let mut owner_areas: HashMap<ObjectId, HashMap<ObjectId, f32>> = HashMap::new();
+        for placement in &scanner.placements {
+            let area = placement.size.0 * placement.size.1;
+            let largest = owner_areas
+                .entry(placement.image)
+                .or_default()
+                .entry(placement.owner)
+                .or_insert(0.0);
+            *largest = largest.max(area);
+        }
+        owner_areas
+    };
+
+    let mut images: Vec<ObjectId> = owner_areas.keys().copied().collect();
+    images.sort();
+
+    let mut copies = 0;
+    for image_id in images {
+        let owners = &owner_areas[&image_id];
+        let largest = owners.values().fold(0.0f32, |a, b| a.max(*b));
+
+        // Half the side length of the largest placement is a quarter of its area
+        let mut small_owners: Vec<ObjectId> = owners
+            .iter()
+            .filter(|(_, area)| **area * 4.0 <= largest)
+            .map(|(owner, _)| *owner)
+            .collect();
+        if small_owners.is_empty() {
+            continue;
+        }
+        small_owners.sort();
+
+        let image = match doc.get_object(image_id) {
+            Ok(object) => object.clone(),
+            Err(_) => continue,
+        };
+        let copy_id = doc.add_object(image);
+        for owner in small_owners {
+            retarget_xobject(doc, owner, image_id, copy_id);
+        }
+        copies += 1;
+    }
+
+    if copies == 0 {
+        return (display_info_map, 0);
+    }
+
+    let mut scanner = ContentScanner::new(doc, false).with_limits(options);
+    scanner.scan_all_pages();
+    (scanner.get_display_info_map(), copies)
+}
+
+/// Point the names in `owner`'s XObject resources that refer to `from` at `to`
+///
+/// `owner` is a page or a form/pattern stream. Its resources are copied into a
+/// direct dictionary first, so pages or forms sharing them are unaffected.
+fn retarget_xobject(doc: &mut Document, owner: ObjectId, from: ObjectId, to: ObjectId) {
+    let resources = match doc.get_object(owner) {
+        Ok(Object::Stream(stream)) => stream.dict.get(b"Resources").ok().cloned(),
+        Ok(Object::Dictionary(_)) => {
+            // Pages inherit resources through the page tree
+            let mut node = owner;
+            let mut found = None;
+            let mut visited = HashSet::new();
+            while visited.insert(node) {
+                let Ok(dict) = doc.get_dictionary(node) else {
+                    break;
+                };
+                if let Ok(resources) = dict.get(b"Resources") {
+                    found = Some(resources.clone());
+                    break;
+                }
+                match dict.get(b"Parent") {
+                    Ok(Object::Reference(parent)) => node = *parent,
+                    _ => break,
+                }
+            }
+            found
+        }
+        _ => None,
+    };
+
+    let mut resources = match resources {
+        Some(Object::Dictionary(dict)) => dict,
+        Some(Object::Reference(id)) => match doc.get_dictionary(id) {
+            Ok(dict) => dict.clone(),
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    let mut xobjects = match resources.get(b"XObject") {
+        Ok(Object::Dictionary(dict)) => dict.clone(),
+        Ok(Object::Reference(id)) => match doc.get_dictionary(*id) {
+            Ok(dict) => dict.clone(),
+            Err(_) => return,
+        },
+        _ => return,
+    };
+
+    for (_, value) in xobjects.iter_mut() {
+        if matches!(doc.dereference(value), Ok((Some(id), _)) if id == from) {
+            *value = Object::Reference(to);
+        }
+    }
+    resources.set("XObject", Object::Dictionary(xobjects));
+
+    match doc.get_object_mut(owner) {
+        Ok(Object::Dictionary(dict)) => dict.set("Resources", Object::Dictionary(resources)),
+        Ok(Object::Stream(stream)) => stream.dict.set("Resources", Object::Dictionary(resources)),
+        _ => {}
+    }
+}
+
+/// Collect the IDs of all image XObjects in a document
+fn collect_image_objects(doc: &Document) -> Vec<ObjectId> {
+    let mut image_objects: Vec<ObjectId> = Vec::new();
+
+    for (id, object) in doc.objects.iter() {
+        if let Object::Stream(stream) = object {
+            let subtype = stream.dict.get(b"Subtype").ok().and_then(|s| match s {
+                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+                _ => None,
+            });
+
+            if subtype.as_deref() == Some("Image") {
+                image_objects.push(*id);
+            }
+        }
+    }
+
+    image_objects
+}
+
+/// Collect the IDs of images used as another image's /SMask
+///
+/// Soft masks are decoded and re-encoded together with the image they belong
+/// to, so they must not be processed as images of their own.
+fn collect_soft_masks(doc: &Document) -> HashSet<ObjectId> {
+    doc.objects
+        .values()
+        .filter_map(|object| match object {
+            Object::Stream(stream) => match stream.dict.get(b"SMask") {
+                Ok(Object::Reference(id)) => Some(*id),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Dimensions decided for an image that will be re-encoded
+struct ImagePlan {
+    width: u32,
+    height: u32,
+    target_width: u32,
+    target_height: u32,
+    needs_resampling: bool,
+}
+
+/// Decide whether an image should be re-encoded and at what size
+///
+/// Returns the reason when the image should be left untouched.
+fn plan_image(
+    doc: &Document,
+    object_id: ObjectId,
+    stream: &Stream,
+    display_info_map: &HashMap<ObjectId, ImageDisplayInfo>,
+    options: &ResampleOptions,
+    log: &impl Fn(&str),
+) -> Result<ImagePlan, SkipReason> {
+    let target_dpi = image_target_dpi(doc, &stream.dict, options);
+    let convert_color_space =
+        options.force_color_space.is_some_and(|cs| !cs.matches(doc, &stream.dict));
+
+    // Get image dimensions
+    let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
+
+    let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
+
+    if width == 0 || height == 0 {
+        if options.verbose {
+            log(&format!("[Process] Skipping {:?}: invalid dimensions", object_id));
+        }
+        return Err(SkipReason::InvalidDimensions);
+    }
+
+    // Skip tiny images that aren't worth re-encoding
+    let too_few_bytes = options.min_image_bytes.is_some_and(|min| stream.content.len() < min);
+    let too_few_pixels = options
+        .min_image_pixels
+        .is_some_and(|min| (width as u64 * height as u64) < min as u64);
+    if too_few_bytes || too_few_pixels {
+        if options.verbose {
+            log(&format!(
+                "[Process] Skipping {:?}: below size threshold ({}x{}, {} bytes)",
+                object_id,
+                width,
+                height,
+                stream.content.len()
+            ));
+        }
+        return Err(SkipReason::TooSmall);
+    }
+
+    // Check current encoding
+    let current_filter = stream.dict.get(b"Filter").ok().and_then(|f| match f {
+        Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+        Object::Array(arr) => arr.first().and_then(|f| match f {
+            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+            _ => None,
+        }),
+        _ => None,
+    });
+    let is_already_jpeg = current_filter.as_deref() == Some("DCTDecode");
+    let is_lossless = matches!(
+        current_filter.as_deref(),
+        Some("FlateDecode" | "LZWDecode" | "RunLengthDecode")
+    );
+
+    if let Some(reason) = current_filter.as_deref().and_then(unsupported_filter_reason) {
+        if options.verbose {
+            log(&format!("[Process] Skipping {:?}: {}", object_id, reason));
+        }
+        return Err(SkipReason::UnsupportedFilter);
+    }
+
+    // Color keys select exact sample values, so re-encoded pixels would paint
+    // the keyed background as an opaque box
+    if let Ok(mask) = stream.dict.get(b"Mask") {
+        if matches!(doc.dereference(mask), Ok((_, Object::Array(_)))) {
+            if options.verbose {
+                log(&format!("[Process] Skipping {:?}: color key mask", object_id));
+            }
+            return Err(SkipReason::ColorKeyMask);
+        }
+    }
+
+    // Look up display info; only real placements are affected by the output scale
+    let display_known = display_info_map.contains_key(&object_id);
+    let display_info = match display_info_map.get(&object_id) {
+        Some(info) => info.scaled(options.output_scale),
+        None => match options.on_unknown_dpi {
+            UnknownDpiPolicy::AssumeLow => {
+                if options.verbose {
+                    log(&format!(
+                        "[Process] Image {:?} ({}x{}): No display info found, using pixel dims",
+                        object_id, width, height
+                    ));
+                }
+                // Fall back to assuming 72 DPI (1 pixel = 1 point)
+                ImageDisplayInfo {
+                    pixel_width: width,
+                    pixel_height: height,
+                    display_width_points: width as f32,
+                    display_height_points: height as f32,
+                }
+            }
+            UnknownDpiPolicy::AssumeTarget => {
+                if options.verbose {
+                    log(&format!(
+                        "[Process] Image {:?} ({}x{}): No display info found, assuming target DPI",
+                        object_id, width, height
+                    ));
+                }
+                // Display size at which the pixels are exactly target DPI
+                ImageDisplayInfo {
+                    pixel_width: width,
+                    pixel_height: height,
+                    display_width_points: width as f32 * 72.0 / target_dpi,
+                    display_height_points: height as f32 * 72.0 / target_dpi,
+                }
+            }
+            UnknownDpiPolicy::Skip => {
+                if options.verbose {
+                    log(&format!(
+                        "[Process] Skipping {:?}: no display info found",
+                        object_id
+                    ));
+                }
+                return Err(SkipReason::NoDisplayInfo);
+            }
+        },
+    };
+
+    let current_dpi = display_info.max_effective_dpi();
+
+    if options.verbose {
+        log(&format!(
+            "[Process] Image {:?}: {}x{} px, {:.1}x{:.1} pt, {:.1} DPI ({})",
+            object_id,
+            width,
+            height,
+            display_info.display_width_points,
+            display_info.display_height_points,
+            current_dpi,
+            current_filter.as_deref().unwrap_or("raw")
+        ));
+    }
+
+    // Don't trust an implausible DPI; downsampling on it would wreck the image
+    if let Some(max_dpi) = options.sanity_max_dpi {
+        if current_dpi > max_dpi {
+            if options.verbose {
+                log(&format!(
+                    "  Warning: effective DPI {:.0} exceeds sanity limit {:.0}, display size is likely wrong; skipping",
+                    current_dpi, max_dpi
+                ));
+            }
+            return Err(SkipReason::ImplausibleDpi);
+        }
+    }
+
+    // Check if resampling is needed
+    let upscale = options.allow_upscaling && display_known && current_dpi < target_dpi - 1.0;
+    let mut needs_resampling =
+        (current_dpi > target_dpi + 1.0 && current_dpi > options.min_dpi) || upscale;
+
+    // Calculate target dimensions
+    let (mut target_width, mut target_height) = if needs_resampling {
+        display_info.target_pixels_for_dpi(target_dpi)
+    } else {
+        (width, height)
+    };
+
+    // Enforce the pixel count cap even when DPI is unknown or below target
+    if let Some(max_pixels) = options.max_pixels {
+        if let Some((capped_w, capped_h)) =
+            cap_pixel_count(target_width, target_height, max_pixels)
+        {
+            if options.verbose {
+                log(&format!(
+                    "  Capping {}x{} to {}x{} (max {} pixels)",
+                    target_width, target_height, capped_w, capped_h, max_pixels
+                ));
+            }
+            target_width = capped_w;
+            target_height = capped_h;
+            needs_resampling = true;
+        }
+    }
+
+    // Likewise limit the longest side; combined with the DPI target, the smaller size wins
+    if let Some(max_long_edge) = options.max_long_edge {
+        if let Some((capped_w, capped_h)) = cap_long_edge(target_width, target_height, max_long_edge) {
+            if options.verbose {
+                log(&format!(
+                    "  Capping {}x{} to {}x{} (max long edge {} px)",
+                    target_width, target_height, capped_w, capped_h, max_long_edge
+                ));
+            }
+            target_width = capped_w;
+            target_height = capped_h;
+            needs_resampling = true;
+        }
+    }
+
+    // Skip if already JPEG and no resampling needed, unless its quality is above the recompress threshold
+    if !needs_resampling && is_already_jpeg {
+        let estimated_quality = estimate_jpeg_quality(&stream.content);
+        let recompress = options.recompress_even_if_not_smaller
+            || convert_color_space
+            || match (options.recompress_jpeg_below_quality, estimated_quality) {
+                (Some(threshold), Some(q)) => q > threshold,
+                _ => false,
+            };
+
+        if !recompress {
+            if options.verbose {
+                log("  Skipping: Already JPEG at target DPI");
+            }
+            return Err(SkipReason::AlreadyAtTargetDpi);
+        }
+
+        if options.verbose {
+            if convert_color_space {
+                log("  Recompressing: converting to the forced color space");
+            } else if options.recompress_even_if_not_smaller {
+                log("  Recompressing: re-encode requested at target DPI");
+            } else {
+                log(&format!(
+                    "  Recompressing: estimated JPEG quality {} above threshold",
+                    estimated_quality.unwrap_or(0)
+                ));
+            }
+        }
+    }
+
+    // A losslessly compressed image at target DPI would only lose quality as JPEG
+    if !needs_resampling
+        && is_lossless
+        && !options.recompress_even_if_not_smaller
+        && !convert_color_space
+    {
+        if options.verbose {
+            log("  Skipping: Already losslessly compressed at target DPI");
+        }
+        return Err(SkipReason::AlreadyAtTargetDpi);
+    }
+
+    // Re-encoding bilevel content at its own size can only make it larger
+    let bits_per_component =
+        get_int_resolved(&stream.dict, b"BitsPerComponent", doc).unwrap_or(8);
+    if !needs_resampling && is_bilevel(&stream.dict, bits_per_component) && !convert_color_space {
+        if options.verbose {
+            log("  Skipping: 1-bit image at target DPI");
+        }
+        return Err(SkipReason::AlreadyAtTargetDpi);
+    }
+
+    // Resampling would make the image larger: unless that was asked for, keep
+    // its size, and either re-encode it as is or leave it alone
+    if needs_resampling && !upscale && target_width >= width && target_height >= height {
+        if !options.recompress_even_if_not_smaller && !convert_color_space {
+            if options.verbose {
+                log("  Skipping: Target dimensions not smaller");
+            }
+            return Err(SkipReason::TargetNotSmaller);
+        }
+
+        if options.verbose {
+            log("  Target dimensions not smaller; re-encoding at original size");
+        }
+        needs_resampling = false;
+        target_width = width;
+        target_height = height;
+    }
+
+    Ok(ImagePlan {
+        width,
+        height,
+        target_width,
+        target_height,
+        needs_resampling,
+    })
+}
+
+/// Process images in PDF document (in-memory version)
+fn process_images_in_doc(
+    doc: &mut Document,
+    display_info_map: &HashMap<ObjectId, ImageDisplayInfo>,
+    options: &ResampleOptions,
+    should_cancel: Option<&dyn Fn() -> bool>,
+    log: impl Fn(&str),
+) -> Result<ResampleResult, ResampleError> {
+    let mut total_images = 0;
+    let mut resampled_images = 0;
+    let mut skipped_images = 0;
+    let mut failed_images = 0;
+    let mut dropped_images = 0;
+    let mut skip_reasons: HashMap<SkipReason, usize> = HashMap::new();
+    let mut per_image = Vec::new();
+    // Skipped images are unchanged, so their records are filled in after the loop
+    let mut skipped_ids = Vec::new();
+    // Decoder messages of images skipped as `DecodeFailed`, for their records
+    let mut decode_errors: HashMap<ObjectId, String> = HashMap::new();
+    let mut skip = |object_id: ObjectId, reason: SkipReason| {
+        trace_event!(debug, object_id = ?object_id, reason = reason.as_str(), "image skipped");
+        skipped_images += 1;
+        *skip_reasons.entry(reason).or_default() += 1;
+        if options.collect_per_image {
+            skipped_ids.push((object_id, reason));
+        }
+    };
+
+    // Time spent in each stage, summed over all images
+    let mut decode_ms = 0.0;
+    let mut resample_ms = 0.0;
+    let mut encode_ms = 0.0;
+
+    // Merge identical images so each is only decoded and re-encoded once
+    let (display_info_map, merged) = merge_duplicate_images(doc, display_info_map);
+    if options.verbose && merged > 0 {
+        log(&format!("[Process] Merged {} duplicate image XObjects", merged));
+    }
+
+    let display_info_map = if options.split_multiuse_images {
+        let (display_info_map, split) =
+            split_multiuse_images(doc, display_info_map, options);
+        if options.verbose && split > 0 {
+            log(&format!("[Process] Split {} images drawn at very different sizes", split));
+        }
+        display_info_map
+    } else {
+        display_info_map
+    };
+
+    // Collect all image XObjects
+    let image_objects = collect_image_objects(doc);
+
+    if options.verbose {
+        log(&format!("[Process] Found {} image XObjects", image_objects.len()));
+    }
+
+    let soft_masks = collect_soft_masks(doc);
+    let mut srgb_profile = None;
+    let hidden_groups = if options.drop_hidden_oc {
+        hidden_ocgs(doc)
+    } else {
+        HashSet::new()
+    };
+
+    // Process each image
+    for object_id in image_objects {
+        if should_cancel.is_some_and(|cancel| cancel()) {
+            return Err(ResampleError::Cancelled);
+        }
+
+        let stream = match doc.get_object(object_id) {
+            Ok(Object::Stream(s)) => s.clone(),
+            _ => continue,
+        };
+
+        total_images += 1;
+
+        if soft_masks.contains(&object_id) {
+            if options.verbose {
+                log(&format!(
+                    "[Process] Skipping {:?}: soft mask, handled with its image",
+                    object_id
+                ));
+            }
+            skip(object_id, SkipReason::SoftMask);
+            continue;
+        }
+
+        if !hidden_groups.is_empty() {
+            if let Ok(oc) = stream.dict.get(b"OC") {
+                if is_oc_hidden(doc, oc, &hidden_groups) {
+                    if options.verbose {
+                        log(&format!("[Process] Dropping {:?}: in a hidden layer", object_id));
+                    }
+                    trace_event!(debug, object_id = ?object_id, "image dropped in hidden layer");
+                    let placeholder = empty_image_placeholder(&stream.dict);
+                    if options.collect_per_image {
+                        let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
+                        let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
+                        per_image.push(PerImageResult {
+                            object_id,
+                            original_width: width,
+                            original_height: height,
+                            target_width: 1,
+                            target_height: 1,
+                            original_bytes: image_bytes_with_smask(doc, &stream),
+                            new_bytes: placeholder.content.len(),
+                            decision: "dropped",
+                            reason: None,
+                            error: None,
+                        });
+                    }
+                    doc.objects.insert(object_id, Object::Stream(placeholder));
+                    dropped_images += 1;
+                    continue;
+                }
+            }
+        }
+
+        let ImagePlan {
+            mut width,
+            mut height,
+            mut target_width,
+            mut target_height,
+            mut needs_resampling,
+        } = match plan_image(
+            doc,
+            object_id,
+            &stream,
+            &display_info_map,
+            options,
+            &log,
+        ) {
+            Ok(plan) => plan,
+            Err(reason) => {
+                skip(object_id, reason);
+                continue;
+            }
+        };
+        let (original_width, original_height) = (width, height);
+
+        // Get color space and bits per component
+        let color_space = stream
+            .dict
+            .get(b"ColorSpace")
+            .ok()
+            .map(|cs| get_color_space_name(cs, doc))
+            .unwrap_or_else(|| "DeviceRGB".to_string());
+
+        let bits_per_component = get_int_resolved(&stream.dict, b"BitsPerComponent", doc)
+            .unwrap_or(8);
+
+        // Check for SMask
+        let smask_id = stream.dict.get(b"SMask").ok().and_then(|s| match s {
+            Object::Reference(id) => Some(*id),
+            _ => None,
+        });
+
+        // Decode the image
+        let decode_start = now_ms();
+        let decoded = decode_image_stream(
+            doc,
+            &stream,
+            width,
+            height,
+            &color_space,
+            bits_per_component,
+            options.max_decompressed_bytes,
+        );
+        let mut img = match decoded {
+            Ok(img) => img,
+            Err(e) => {
+                trace_event!(warn, object_id = ?object_id, error = %e, "image decode failed");
+                if options.verbose {
+                    log(&format!("  Skipping {:?}: could not decode: {}", object_id, e));
+                }
+                skip(object_id, SkipReason::DecodeFailed);
+                decode_errors.insert(object_id, e);
+                continue;
+            }
+        };
+
+        // A JPEG's own header wins over /Width and /Height; the target size
+        // comes from the display size, but must still be a reduction
+        if (img.width(), img.height()) != (width, height) {
+            trace_event!(warn, object_id = ?object_id, "decoded size differs from dictionary");
+            if options.verbose {
+                log(&format!(
+                    "  Warning: decoded size {}x{} differs from dictionary {}x{}; using the decoded size",
+                    img.width(),
+                    img.height(),
+                    width,
+                    height
+                ));
+            }
+            width = img.width();
+            height = img.height();
+            let enlarges = target_width >= width && target_height >= height;
+            if !needs_resampling || (enlarges && !options.allow_upscaling) {
+                needs_resampling = false;
+                target_width = width;
+                target_height = height;
+            }
+        }
+
+        // Color images holding only gray pixels are stored as gray; a /Decode
+        // array's per-channel ranges cannot be carried over, so those stay as they are
+        let store_as_gray = match options.force_color_space {
+            Some(forced) => forced == ForcedCs::Gray,
+            None => {
+                options.detect_grayscale
+                    && stream.dict.get(b"Decode").is_err()
+                    && is_near_neutral(&img)
+            }
+        };
+
+        let is_grayscale = store_as_gray
+            || matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_));
+        let from_jpeg = first_filter(&stream.dict).as_deref() == Some("DCTDecode");
+        let quality = image_quality(options, is_grayscale, from_jpeg);
+        if options.verbose && quality != options.quality {
+            log(&format!(
+                "  Raising JPEG quality to {} for {} image",
+                quality,
+                if from_jpeg && options.jpeg_source_quality_bonus > 0 {
+                    "already-JPEG"
+                } else {
+                    "grayscale"
+                }
+            ));
+        }
+
+        // Handle SMask
+        if let Some(smask_obj_id) = smask_id {
+            if let Ok(Object::Stream(smask_stream)) = doc.get_object(smask_obj_id) {
+                match apply_smask(doc, &img, smask_stream, options.max_decompressed_bytes) {
+                    Ok(rgba_img) => {
+                        img = rgba_img;
+                        if options.verbose {
+                            // The new SMask carries no /Matte, so store straight colors
+                            if smask_matte_rgb(smask_stream).is_some() {
+                                log("    Removed SMask matte pre-blending");
+                            }
+                            log("    Decoded SMask alpha channel");
+                        }
+                    }
+                    Err(e) => {
+                        if options.verbose {
+                            log(&format!("    Warning: Could not decode SMask: {}", e));
+                        }
+                    }
+                }
+            }
+        }
+
+        decode_ms += now_ms() - decode_start;
+
+        // Resample if needed
+        let resample_start = now_ms();
+        let resampled = if needs_resampling {
+            if options.verbose {
+                log(&format!(
+                    "  Resampling from {}x{} to {}x{}",
+                    width, height, target_width, target_height
+                ));
+            }
+            resample_image(&img, target_width, target_height, options.area_average_above_ratio)
+        } else {
+            if options.verbose {
+                log("  Re-encoding as JPEG (no resize needed)");
+            }
+            img
+        };
+
+        resample_ms += now_ms() - resample_start;
+
+        // Encode
+        let encode_start = now_ms();
+        let (resampled, store_as_gray) = match options.flatten_alpha {
+            Some(background) if has_alpha(&resampled) => {
+                if options.verbose {
+                    log(&format!("      Flattening transparency over {:?}", background));
+                }
+                let flat = flatten_over(&resampled, background);
+                // The background can add color to an otherwise gray image
+                let store_as_gray = store_as_gray
+                    && (options.force_color_space.is_some() || is_near_neutral(&flat));
+                (flat, store_as_gray)
+            }
+            _ => (resampled, store_as_gray),
+        };
+        let img_has_alpha = has_alpha(&resampled);
+
+        // Compared against the image and its old soft mask, both of which are replaced
+        let original_bytes = image_bytes_with_smask(doc, &stream);
+        let savings_required = options.min_savings_ratio > 0.0
+            && target_width <= width
+            && target_height <= height
+            && options.force_color_space.is_none_or(|cs| cs.matches(doc, &stream.dict));
+        let too_little_saved = |new_bytes: usize| {
+            let saved = 1.0 - new_bytes as f64 / original_bytes.max(1) as f64;
+            savings_required && saved < options.min_savings_ratio as f64
+        };
+        let record = |decision: &'static str, new_bytes: usize, error: Option<String>| {
+            PerImageResult {
+                object_id,
+                original_width,
+                original_height,
+                target_width,
+                target_height,
+                original_bytes,
+                new_bytes,
+                decision,
+                reason: None,
+                error,
+            }
+        };
+
+        let new_bytes = if img_has_alpha {
+            let (mut new_stream, smask_stream, _, _) =
+                match encode_with_alpha_stream(
+                    &resampled,
+                    quality,
+                    store_as_gray,
+                    options.optimize_huffman,
+                ) {
+                    Ok(encoded) => encoded,
+                    Err(e) => {
+                        trace_event!(warn, object_id = ?object_id, error = %e, "image encode failed");
+                        if options.verbose {
+                            log(&format!(
+                                "  Failed {:?}: could not encode, keeping original: {}",
+                                object_id, e
+                            ));
+                        }
+                        failed_images += 1;
+                        if options.collect_per_image {
+                            per_image.push(record("failed", original_bytes, Some(e)));
+                        }
+                        continue;
+                    }
+                };
+            let new_bytes = new_stream.content.len()
+                + smask_stream.as_ref().map_or(0, |smask| smask.content.len());
+            if too_little_saved(new_bytes) {
+                if options.verbose {
+                    log(&format!(
+                        "  Keeping original: {} -> {} bytes saves less than {:.0}%",
+                        original_bytes,
+                        new_bytes,
+                        options.min_savings_ratio * 100.0
+                    ));
+                }
+                skip(object_id, SkipReason::InsufficientSavings);
+                continue;
+            }
+            copy_preserved_entries(&stream.dict, &mut new_stream.dict, &color_space, store_as_gray);
+            if options.embed_srgb && !store_as_gray {
+                new_stream.dict.set("ColorSpace", srgb_color_space(doc, &mut srgb_profile));
+            }
+
+            if let Some(smask) = smask_stream {
+                let smask_id = doc.add_object(Object::Stream(smask));
+                new_stream.dict.set("SMask", Object::Reference(smask_id));
+
+                if options.verbose {
+                    log(&format!("      Preserved alpha channel with SMask {:?}", smask_id));
+                }
+            }
+
+            doc.objects.insert(object_id, Object::Stream(new_stream));
+            new_bytes
+        } else {
+            if options.verbose && smask_id.is_some() {
+                log("      Converting opaque image to JPEG");
+            }
+            if options.verbose && store_as_gray {
+                log("      Storing neutral image as DeviceGray");
+            }
+            // JPEG smears the hard edges of line art, so bilevel images stay lossless
+            let bilevel = is_bilevel(&stream.dict, bits_per_component);
+            let store_as_gray = store_as_gray
+                || (bilevel
+                    && matches!(resampled, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_)));
+            let encoded = if bilevel {
+                if options.verbose {
+                    log("      Storing 1-bit image with FlateDecode instead of JPEG");
+                }
+                encode_as_flate_stream(&resampled, store_as_gray)
+            } else if store_as_gray {
+                encode_as_gray_jpeg_stream(
+                    &resampled,
+                    quality,
+                    options.progressive_jpeg,
+                    options.optimize_huffman,
+                )
+            } else {
+                encode_as_jpeg_stream(
+                    &resampled,
+                    quality,
+                    options.chroma_subsampling,
+                    options.progressive_jpeg,
+                    options.optimize_huffman,
+                )
+            };
+            let (mut new_stream, _, _) = match encoded {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    trace_event!(warn, object_id = ?object_id, error = %e, "image encode failed");
+                    if options.verbose {
+                        log(&format!(
+                            "  Failed {:?}: could not encode, keeping original: {}",
+                            object_id, e
+                        ));
+                    }
+                    failed_images += 1;
+                    if options.collect_per_image {
+                        per_image.push(record("failed", original_bytes, Some(e)));
+                    }
+                    continue;
+                }
+            };
+            let new_bytes = new_stream.content.len();
+            if too_little_saved(new_bytes) {
+                if options.verbose {
+                    log(&format!(
+                        "  Keeping original: {} -> {} bytes saves less than {:.0}%",
+                        original_bytes,
+                        new_bytes,
+                        options.min_savings_ratio * 100.0
+                    ));
+                }
+                skip(object_id, SkipReason::InsufficientSavings);
+                continue;
+            }
+            copy_preserved_entries(&stream.dict, &mut new_stream.dict, &color_space, store_as_gray);
+            if options.embed_srgb && !store_as_gray {
+                new_stream.dict.set("ColorSpace", srgb_color_space(doc, &mut srgb_profile));
+            }
+            doc.objects.insert(object_id, Object::Stream(new_stream));
+            new_bytes
+        };
+
+        encode_ms += now_ms() - encode_start;
+        trace_event!(
+            debug,
+            object_id = ?object_id,
+            width,
+            height,
+            target_width,
+            target_height,
+            quality,
+            "image resampled"
+        );
+        resampled_images += 1;
+        if options.collect_per_image {
+            let decision = if needs_resampling { "resampled" } else { "reencoded" };
+            per_image.push(record(decision, new_bytes, None));
+        }
+    }
+
+    // Before the old soft masks they refer to are removed
+    for (object_id, reason) in skipped_ids {
+        let Ok(Object::Stream(stream)) = doc.get_object(object_id) else {
+            continue;
+        };
+        let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
+        let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
+        let bytes = image_bytes_with_smask(doc, stream);
+        per_image.push(PerImageResult {
+            object_id,
+            original_width: width,
+            original_height: height,
+            target_width: width,
+            target_height: height,
+            original_bytes: bytes,
+            new_bytes: bytes,
+            decision: "skipped",
+            reason: Some(reason.as_str()),
+            error: decode_errors.remove(&object_id),
+        });
+    }
+    per_image.sort_by_key(|record| record.object_id);
+
+    // Re-encoded images get new soft masks; drop the old ones nothing refers to anymore
+    let mut still_referenced = HashSet::new();
+    collect_references(&Object::Dictionary(doc.trailer.clone()), &mut still_referenced);
+    for object in doc.objects.values() {
+        collect_references(object, &mut still_referenced);
+    }
+    let mut removed_masks = 0;
+    for id in soft_masks.difference(&still_referenced) {
+        doc.objects.remove(id);
+        removed_masks += 1;
+    }
+    if options.verbose && removed_masks > 0 {
+        log(&format!("[Process] Removed {} replaced soft masks", removed_masks));
+    }
+
+    if options.verbose {
+        log(&format!(
+            "[Timing] decode {:.0} ms, resample {:.0} ms, encode {:.0} ms",
+            decode_ms, resample_ms, encode_ms
+        ));
+    }
+
+    Ok(ResampleResult {
+        total_images,
+        resampled_images,
+        skipped_images,
+        failed_images,
+        dropped_images,
+        skip_reasons,
+        per_image,
+    })
+}
+
+/// Encoded size of an image together with its soft mask, if any
+fn image_bytes_with_smask(doc: &Document, stream: &Stream) -> usize {
+    stream.content.len()
+        + stream
+            .dict
+            .get(b"SMask")
+            .ok()
+            .and_then(|smask| smask.as_reference().ok())
+            .and_then(|id| doc.get_object(id).ok())
+            .and_then(|smask| smask.as_stream().ok())
+            .map_or(0, |smask| smask.content.len())
+}
+
+/// Optional content groups that are off in the document's default configuration
+fn hidden_ocgs(doc: &Document) -> HashSet<ObjectId> {
+    let resolve = |obj: &Object| -> Option<Object> {
+        match obj {
+            Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+            other => Some(other.clone()),
+        }
+    };
+    let references = |obj: Option<Object>| -> HashSet<ObjectId> {
+        match obj {
+            Some(Object::Array(arr)) => arr.iter().filter_map(|o| o.as_reference().ok()).collect(),
+            _ => HashSet::new(),
+        }
+    };
+
+    let Some(Object::Dictionary(properties)) = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"OCProperties").ok())
+        .and_then(resolve)
+    else {
+        return HashSet::new();
+    };
+    let Some(Object::Dictionary(config)) = properties.get(b"D").ok().and_then(resolve) else {
+        return HashSet::new();
+    };
+    let entry = |key: &[u8]| config.get(key).ok().and_then(resolve);
+
+    // With /BaseState /OFF every group is hidden unless listed in /ON
+    if matches!(entry(b"BaseState"), Some(Object::Name(ref n)) if n == b"OFF") {
+        let all = references(properties.get(b"OCGs").ok().and_then(resolve));
+        let on = references(entry(b"ON"));
+        all.difference(&on).copied().collect()
+    } else {
+        references(entry(b"OFF"))
+    }
+}
+
+/// Check whether content tagged with `oc`, an optional content group or
+/// membership dictionary, is hidden given the groups that are off
+///
+/// Visibility expressions (/VE) are not evaluated; such content counts as visible.
+fn is_oc_hidden(doc: &Document, oc: &Object, hidden_groups: &HashSet<ObjectId>) -> bool {
+    let (id, dict) = match oc {
+        Object::Reference(id) => match doc.get_object(*id) {
+            Ok(Object::Dictionary(dict)) => (Some(*id), dict),
+            _ => return false,
+        },
+        Object::Dictionary(dict) => (None, dict),
+        _ => return false,
+    };
+
+    if dict.get(b"Type").ok() != Some(&Object::Name(b"OCMD".to_vec())) {
+        return id.is_some_and(|id| hidden_groups.contains(&id));
+    }
+    if dict.has(b"VE") {
+        return false;
+    }
+
+    let groups: Vec<ObjectId> = match dict.get(b"OCGs") {
+        Ok(Object::Reference(id)) => vec![*id],
+        Ok(Object::Array(arr)) => arr.iter().filter_map(|o| o.as_reference().ok()).collect(),
+        _ => return false,
+    };
+    if groups.is_empty() {
+        return false;
+    }
+    let mut on = groups.iter().map(|id| !hidden_groups.contains(id));
+    match dict.get(b"P") {
+        Ok(Object::Name(p)) if p == b"AllOn" => on.any(|on| !on),
+        Ok(Object::Name(p)) if p == b"AnyOff" => on.all(|on| on),
+        Ok(Object::Name(p)) if p == b"AllOff" => on.any(|on| on),
+        // /AnyOn is the default policy
+        _ => on.all(|on| !on),
+    }
+}
+
+/// A 1x1 stencil mask that paints nothing, standing in for a dropped image
+///
+/// Keeps the original's /OC so it still follows the layer when toggled.
+fn empty_image_placeholder(original: &Dictionary) -> Stream {
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Type", Object::Name(b"XObject".to_vec()));
+    dict.set("Subtype", Object::Name(b"Image".to_vec()));
+    dict.set("Width", Object::Integer(1));
+    dict.set("Height", Object::Integer(1));
+    dict.set("ImageMask", Object::Boolean(true));
+    dict.set("BitsPerComponent", Object::Integer(1));
+    if let Ok(oc) = original.get(b"OC") {
+        dict.set("OC", oc.clone());
+    }
+    // With the default /Decode [0 1], a 1 bit leaves the page unpainted
+    Stream::new(dict, vec![0x80])
+}
+
+/// Scan and resample an already-loaded document in place
+///
+/// Use this to combine resampling with other lopdf-based processing without
+/// serializing and reparsing the PDF. Display info is collected from the
+/// document before any image is modified, so a single load serves both the
+/// scan and the processing pass. `options.password` is ignored here since the
+/// caller has already loaded (and decrypted) the document.
+pub fn resample_document(
+    doc: &mut Document,
+    options: &ResampleOptions,
+) -> Result<ResampleResult, ResampleError> {
+    resample_document_with_cancel(doc, options, None)
+}
+
+/// Like [`resample_document`], stopping early when `should_cancel` returns true
+///
+/// The check runs between images, so cancellation takes effect once the
+/// image being processed is finished. On cancellation the document is left
+/// partially resampled and [`ResampleError::Cancelled`] is returned.
+pub fn resample_document_with_cancel(
+    doc: &mut Document,
+    options: &ResampleOptions,
+    should_cancel: Option<&dyn Fn() -> bool>,
+) -> Result<ResampleResult, ResampleError> {
+    resample_document_with_logger(doc, options, should_cancel, |msg| log_verbose(options, msg))
+}
+
+/// Like [`resample_document_with_cancel`], sending verbose output to `log`
+///
+/// Lets servers route messages to their own logging instead of stdout. `log`
+/// is only called when `options.verbose` is set.
+pub fn resample_document_with_logger(
+    doc: &mut Document,
+    options: &ResampleOptions,
+    should_cancel: Option<&dyn Fn() -> bool>,
+    log: impl Fn(&str),
+) -> Result<ResampleResult, ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "resample_document",
+        target_dpi = options.target_dpi,
+        quality = options.quality
+    )
+    .entered();
+
+    let scan_start = now_ms();
+    resolve_named_color_spaces(doc);
+
+    // Drop thumbnails up front so they are not re-encoded for nothing
+    match options.thumbnails {
+        ThumbPolicy::Keep => {}
+        ThumbPolicy::Remove => {
+            let removed = remove_thumbnails(doc);
+            if options.verbose && removed > 0 {
+                log(&format!("[Process] Removed {} page thumbnails", removed));
+            }
+        }
+        ThumbPolicy::Regenerate => {
+            if options.verbose {
+                log("[Process] Warning: regenerating thumbnails is not supported yet; keeping them");
+            }
+        }
+    }
+
+    // Step 1: Scan all content streams to find image display dimensions
+    let display_info_map = {
+        let mut scanner = ContentScanner::new(doc, options.verbose).with_limits(options);
+        scanner.log_callback = Some(Box::new(&log));
+        scanner.scan_all_pages();
+        scanner.get_display_info_map()
+    }; // scanner borrow ends here
+
+    if options.verbose {
+        log(&format!("\nFound display info for {} images", display_info_map.len()));
+        for (id, info) in &display_info_map {
+            log(&format!(
+                "  {:?}: {}x{} px @ {:.1}x{:.1} pt = {:.1} DPI",
+                id,
+                info.pixel_width,
+                info.pixel_height,
+                info.display_width_points,
+                info.display_height_points,
+                info.max_effective_dpi()
+            ));
+        }
+    }
+
+    let scan_ms = now_ms() - scan_start;
+
+    // Step 2: Process images
+    let process_start = now_ms();
+    let result = process_images_in_doc(doc, &display_info_map, options, should_cancel, &log)?;
+    let process_ms = now_ms() - process_start;
+    trace_event!(
+        info,
+        total = result.total_images,
+        resampled = result.resampled_images,
+        skipped = result.skipped_images,
+        failed = result.failed_images,
+        "images processed"
+    );
+
+    let finish_start = now_ms();
+    if !options.preserve_metadata {
+        strip_metadata(doc);
+    }
+
+    if options.gc {
+        let removed = remove_unreachable_images(doc);
+        if options.verbose && removed > 0 {
+            log(&format!("[Process] Removed {} unreferenced image objects", removed));
+        }
+    }
+
+    // Compress streams if requested
+    if options.compress_streams {
+        doc.compress();
+    }
+
+    if options.linearize {
+        order_objects_for_first_page(doc);
+    }
+
+    if options.verbose {
+        log(&format!(
+            "[Timing] scan {:.0} ms, process {:.0} ms, finish {:.0} ms",
+            scan_ms,
+            process_ms,
+            now_ms() - finish_start
+        ));
+    }
+
+    Ok(result)
+}
+
+/// Print a message when verbose output is enabled (browsers have no stdout)
+fn log_verbose(options: &ResampleOptions, _msg: &str) {
+    if options.verbose {
+        #[cfg(not(target_arch = "wasm32"))]
+        println!("{}", _msg);
+    }
+}
+
+/// Milliseconds on a monotonic clock, for verbose timing output
+///
+/// `std::time::Instant` panics on wasm32-unknown-unknown, so the browser's
+/// clock is used there.
+fn now_ms() -> f64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        EPOCH
+            .get_or_init(std::time::Instant::now)
+            .elapsed()
+            .as_secs_f64()
+            * 1000.0
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now()
+    }
+}
+
+/// Load a PDF, decrypting it with `password` if it is encrypted
+///
+/// lopdf opens encrypted files with an empty user password on its own; a file
+/// that still carries /Encrypt after loading needs a real password.
+fn load_document(bytes: &[u8], password: Option<&str>) -> Result<Document, ResampleError> {
+    load_document_with_logger(bytes, password, |_| {})
+}
+
+/// Like [`load_document`], reporting a recovered cross-reference table to `log`
+///
+/// A file lopdf rejects, typically for a broken or missing xref table, is
+/// retried with a table rebuilt by [`rebuild_xref`]; the original error is
+/// returned if that fails too.
+fn load_document_with_logger(
+    bytes: &[u8],
+    password: Option<&str>,
+    log: impl Fn(&str),
+) -> Result<Document, ResampleError> {
+    let load = |bytes: &[u8]| match password {
+        Some(password) => Document::load_mem_with_password(bytes, password),
+        None => Document::load_mem(bytes),
+    };
+    let wrong_password = || {
+        ResampleError::LoadError("PDF is encrypted and the password is incorrect".to_string())
+    };
+    let doc = match load(bytes) {
+        Ok(doc) => doc,
+        Err(lopdf::Error::InvalidPassword) => return Err(wrong_password()),
+        Err(e) => {
+            let recovered = rebuild_xref(bytes).and_then(|rebuilt| load(&rebuilt).ok());
+            let Some(doc) = recovered else {
+                return Err(ResampleError::LoadError(e.to_string()));
+            };
+            trace_event!(warn, error = %e, "recovered PDF with a rebuilt xref table");
+            log(&format!(
+                "[Load] Warning: {}; recovered {} objects by rebuilding the xref table",
+                e,
+                doc.objects.len()
+            ));
+            doc
+        }
+    };
+
+    if doc.trailer.get(b"Encrypt").is_ok() {
+        return Err(match password {
+            Some(_) => wrong_password(),
+            None => ResampleError::LoadError("PDF is encrypted; a password is required".to_string()),
+        });
+    }
+
+    Ok(doc)
+}
+
+/// Rebuild a damaged PDF's cross-reference table by scanning for objects
+///
+/// lopdf has no recovery mode of its own, so this finds every `N G obj`
+/// header outside stream data, keeps the last definition of each object as an
+/// incremental update would, and appends a fresh xref table and trailer. Objects inside object
+/// streams come along when lopdf expands the streams. Returns None when no
+/// catalog can be found, or when the file is encrypted, since the trailer's
+/// encryption entries cannot be reconstructed reliably.
+fn rebuild_xref(bytes: &[u8]) -> Option<Vec<u8>> {
+    // lopdf ignores anything before the header and counts offsets from it
+    let start = bytes.windows(5).position(|w| w == b"%PDF-")?;
+    let mut pdf = bytes[start..].to_vec();
+    if pdf.windows(8).any(|w| w == b"/Encrypt") {
+        return None;
+    }
+
+    let mut offsets: BTreeMap<u32, (u16, usize)> = BTreeMap::new();
+    let mut pos = 0;
+    while pos + 3 <= pdf.len() {
+        // Stream data is opaque; bytes in it that look like a header must not
+        // replace the real offset of an object
+        if is_stream_keyword(&pdf, pos) {
+            let data = pos + b"stream".len();
+            match pdf[data..].windows(9).position(|w| w == b"endstream") {
+                Some(end) => {
+                    pos = data + end + b"endstream".len();
+                    continue;
+                }
+                None => break,
+            }
+        }
+        if &pdf[pos..pos + 3] == b"obj"
+            && !pdf.get(pos + 3).is_some_and(|b| b.is_ascii_alphanumeric())
+        {
+            if let Some((offset, id)) = object_header_before(&pdf, pos) {
+                offsets.insert(id.0, (id.1, offset));
+            }
+        }
+        pos += 1;
+    }
+
+    // The last /Root reference wins, as in a trailer chain; failing that, the
+    // last object declaring itself a catalog
+    let root = reference_after_last(&pdf, b"/Root").or_else(|| {
+        let catalog = pdf.windows(8).rposition(|w| w == b"/Catalog")?;
+        offsets
+            .iter()
+            .filter(|(_, (_, offset))| *offset < catalog)
+            .max_by_key(|(_, (_, offset))| *offset)
+            .map(|(&number, &(generation, _))| (number, generation))
+    })?;
+    // Either may sit in an object stream, so neither needs an offset of its own
+    let info = reference_after_last(&pdf, b"/Info");
+
+    let size = offsets
+        .keys()
+        .copied()
+        .chain([root.0])
+        .chain(info.map(|id| id.0))
+        .max()
+        .map_or(1, |last| last + 1);
+    pdf.push(b'\n');
+    let xref_offset = pdf.len();
+    pdf.extend_from_slice(format!("xref\n0 {}\n", size).as_bytes());
+    for number in 0..size {
+        let entry = match offsets.get(&number) {
+            Some((generation, offset)) => format!("{:010} {:05} n\r\n", offset, generation),
+            None => "0000000000 65535 f\r\n".to_string(),
+        };
+        pdf.extend_from_slice(entry.as_bytes());
+    }
+    let mut trailer = format!("trailer\n<< /Size {} /Root {} {} R", size, root.0, root.1);
+    if let Some(info) = info {
+        trailer.push_str(&format!(" /Info {} {} R", info.0, info.1));
+    }
+    trailer.push_str(&format!(" >>\nstartxref\n{}\n%%EOF\n", xref_offset));
+    pdf.extend_from_slice(trailer.as_bytes());
+    Some(pdf)
+}
+
+/// Whether a `stream` keyword following a dictionary's `>>` starts at `pos`
+fn is_stream_keyword(pdf: &[u8], pos: usize) -> bool {
+    if !pdf[pos..].starts_with(b"stream")
+        || !matches!(pdf.get(pos + 6), Some(b'\r' | b'\n'))
+    {
+        return false;
+    }
+    let mut before = pos;
+    while before > 0 && pdf[before - 1].is_ascii_whitespace() {
+        before -= 1;
+    }
+    before >= 2 && &pdf[before - 2..before] == b">>"
+}
+
+/// The offset and id of an `N G obj` header whose keyword starts at `keyword`
+fn object_header_before(pdf: &[u8], keyword: usize) -> Option<(usize, ObjectId)> {
+    let skip_back = |mut pos: usize, pred: fn(u8) -> bool| {
+        while pos > 0 && pred(pdf[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    };
+    let generation_end = skip_back(keyword, |b| b.is_ascii_whitespace());
+    let generation_start = skip_back(generation_end, |b| b.is_ascii_digit());
+    let number_end = skip_back(generation_start, |b| b.is_ascii_whitespace());
+    let number_start = skip_back(number_end, |b| b.is_ascii_digit());
+    if generation_start == generation_end
+        || number_end == generation_start
+        || number_start == number_end
+        || (number_start > 0 && !pdf[number_start - 1].is_ascii_whitespace())
+    {
+        return None;
+    }
+    let number = std::str::from_utf8(&pdf[number_start..number_end]).ok()?.parse().ok()?;
+    let generation = std::str::from_utf8(&pdf[generation_start..generation_end])
+        .ok()?
+        .parse()
+        .ok()?;
+    Some((number_start, (number, generation)))
+}
+
+/// The `N G R` reference following the last occurrence of `key`
+fn reference_after_last(pdf: &[u8], key: &[u8]) -> Option<ObjectId> {
+    let pos = pdf.windows(key.len()).rposition(|w| w == key)? + key.len();
+    let text = String::from_utf8_lossy(&pdf[pos..pdf.len().min(pos + 32)]);
+    let mut parts = text.split_ascii_whitespace();
+    let number = parts.next()?.parse().ok()?;
+    let generation = parts.next()?.parse().ok()?;
+    parts.next().filter(|r| r.starts_with('R'))?;
+    Some((number, generation))
+}
+
+/// Write a document, packing objects into object streams if requested
+fn save_document<W: std::io::Write>(
+    doc: &mut Document,
+    target: &mut W,
+    options: &ResampleOptions,
+) -> std::io::Result<()> {
+    let mut object_streams_allowed = true;
+    if let Some(version) = &options.output_version {
+        let (major, minor) = parse_pdf_version(version).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid PDF version: {:?}", version),
+            )
+        })?;
+        object_streams_allowed = (major, minor) >= (1, 5);
+        doc.version = version.clone();
+    }
+
+    if options.use_object_streams && object_streams_allowed {
+        // lopdf sizes the xref before numbering the object streams it creates, so
+        // only the first one gets an entry; keep everything in a single stream
+        let save_options = lopdf::SaveOptions::builder()
+            .use_object_streams(true)
+            .use_xref_streams(true)
+            .max_objects_per_stream(doc.objects.len().max(1))
+            .compression_level(6)
+            .build();
+        doc.save_with_options(target, save_options)
+    } else {
+        doc.save_to(target)
+    }
+}
+
+/// Parse a "major.minor" PDF version such as "1.7"
+fn parse_pdf_version(version: &str) -> Option<(u8, u8)> {
+    let (major, minor) = version.split_once('.')?;
+    let major: u8 = major.parse().ok()?;
+    let minor: u8 = minor.parse().ok()?;
+    matches!((major, minor), (1, 0..=7) | (2, 0)).then_some((major, minor))
+}
+
+/// Resample PDF from bytes and return resampled PDF bytes
+pub fn resample_pdf_bytes(
+    input_bytes: &[u8],
+    options: &ResampleOptions,
+) -> Result<(Vec<u8>, ResampleResult), ResampleError> {
+    resample_pdf_bytes_with_cancel(input_bytes, options, None)
+}
+
+/// Like [`resample_pdf_bytes`], stopping early when `should_cancel` returns true
+///
+/// Native callers can share an `AtomicBool` with another thread and pass
+/// `&|| flag.load(Ordering::Relaxed)`.
+pub fn resample_pdf_bytes_with_cancel(
+    input_bytes: &[u8],
+    options: &ResampleOptions,
+    should_cancel: Option<&dyn Fn() -> bool>,
+) -> Result<(Vec<u8>, ResampleResult), ResampleError> {
+    resample_pdf_bytes_with_logger(input_bytes, options, should_cancel, |msg| {
+        log_verbose(options, msg)
+    })
+}
+
+/// Like [`resample_pdf_bytes_with_cancel`], sending verbose output to `log`
+///
+/// See [`resample_document_with_logger`].
+pub fn resample_pdf_bytes_with_logger(
+    input_bytes: &[u8],
+    options: &ResampleOptions,
+    should_cancel: Option<&dyn Fn() -> bool>,
+    log: impl Fn(&str),
+) -> Result<(Vec<u8>, ResampleResult), ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    let load_start = now_ms();
+    let mut doc = load_document_with_logger(input_bytes, options.password.as_deref(), |msg| {
+        if options.verbose {
+            log(msg)
+        }
+    })?;
+    let load_ms = now_ms() - load_start;
+
+    let result = resample_document_with_logger(&mut doc, options, should_cancel, &log)?;
+
+    // Save to bytes
+    let save_start = now_ms();
+    let mut output_bytes = Vec::new();
+    save_document(&mut doc, &mut output_bytes, options)
+        .map_err(|e| ResampleError::SaveError(e.to_string()))?;
+
+    if options.verbose {
+        log(&format!("[Timing] load {:.0} ms, save {:.0} ms", load_ms, now_ms() - save_start));
+    }
+
+    Ok((output_bytes, result))
+}
+
+/// Resample a PDF and split it into one single-page PDF per page, in page order
+///
+/// Each output keeps only the objects its page uses, so an image shared by
+/// several pages is resampled once and copied into every file that draws it.
+/// Only those objects are copied per page, not the whole document. Outlines
+/// and links pointing at other pages lose their destination.
+pub fn split_pages(
+    pdf_bytes: &[u8],
+    options: &ResampleOptions,
+) -> Result<Vec<Vec<u8>>, ResampleError> {
+    let mut doc = load_document(pdf_bytes, options.password.as_deref())?;
+    resample_document(&mut doc, options)?;
+
+    let pages = doc.get_pages();
+    let page_ids: HashSet<ObjectId> = pages.values().copied().collect();
+    pages
+        .values()
+        .map(|&page_id| {
+            let mut page_doc = single_page_document(&doc, page_id, &page_ids);
+            let mut bytes = Vec::new();
+            save_document(&mut page_doc, &mut bytes, options)
+                .map_err(|e| ResampleError::SaveError(e.to_string()))?;
+            Ok(bytes)
+        })
+        .collect()
+}
+
+/// Page attributes a page inherits from the page tree when it does not set them
+const INHERITED_PAGE_KEYS: &[&[u8]] = &[b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Build a document holding only `page_id` and the objects it, the catalog and
+/// /Info refer to
+///
+/// Inherited attributes are copied onto the page, and references to the other
+/// pages in `page_ids` become null.
+fn single_page_document(doc: &Document, page_id: ObjectId, page_ids: &HashSet<ObjectId>) -> Document {
+    let mut page = doc.get_dictionary(page_id).cloned().unwrap_or_default();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    while let Some(parent_id) = parent {
+        let Ok(node) = doc.get_dictionary(parent_id) else {
+            break;
+        };
+        for key in INHERITED_PAGE_KEYS {
+            if !page.has(key) {
+                if let Ok(value) = node.get(key) {
+                    page.set(*key, value.clone());
+                }
+            }
+        }
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    page.remove(b"Parent");
+
+    let mut catalog = doc.catalog().cloned().unwrap_or_default();
+    catalog.remove(b"Pages");
+
+    // Copy everything reachable, stopping at other pages
+    let mut page_doc = Document::with_version(doc.version.as_str());
+    let mut refs = HashSet::new();
+    collect_references(&Object::Dictionary(page.clone()), &mut refs);
+    collect_references(&Object::Dictionary(catalog.clone()), &mut refs);
+    if let Ok(info) = doc.trailer.get(b"Info") {
+        collect_references(info, &mut refs);
+        page_doc.trailer.set("Info", info.clone());
+    }
+    let mut copied = HashSet::from([page_id]);
+    let mut pending: Vec<ObjectId> = refs.drain().collect();
+    while let Some(id) = pending.pop() {
+        if page_ids.contains(&id) || !copied.insert(id) {
+            continue;
+        }
+        let Some(object) = doc.objects.get(&id) else {
+            continue;
+        };
+        collect_references(object, &mut refs);
+        pending.extend(refs.drain());
+        page_doc.objects.insert(id, object.clone());
+    }
+
+    let other_pages: HashSet<ObjectId> =
+        page_ids.iter().copied().filter(|&id| id != page_id).collect();
+    let values = page_doc
+        .objects
+        .values_mut()
+        .chain(page.iter_mut().map(|(_, value)| value))
+        .chain(catalog.iter_mut().map(|(_, value)| value));
+    for value in values {
+        null_references(value, &other_pages);
+    }
+
+    page_doc.max_id = doc.max_id;
+    let pages_id = page_doc.new_object_id();
+    page.set("Parent", pages_id);
+    page_doc.objects.insert(page_id, Object::Dictionary(page));
+    let mut pages = Dictionary::new();
+    pages.set("Type", Object::Name(b"Pages".to_vec()));
+    pages.set("Kids", Object::Array(vec![Object::Reference(page_id)]));
+    pages.set("Count", Object::Integer(1));
+    page_doc.objects.insert(pages_id, Object::Dictionary(pages));
+    catalog.set("Pages", pages_id);
+    let catalog_id = page_doc.add_object(catalog);
+    page_doc.trailer.set("Root", catalog_id);
+
+    page_doc.renumber_objects();
+    page_doc
+}
+
+/// What resampling would do to one image, see [`analyze_pdf_bytes`]
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageAnalysis {
+    pub object_id: (u32, u16),
+    pub width: u32,
+    pub height: u32,
+    /// Size after resampling; the current size unless `decision` is "resample"
+    pub target_width: u32,
+    pub target_height: u32,
+    /// "resample", "reencode" (same size, new encoding) or "skip"
+    pub decision: &'static str,
+    /// Why the image is skipped, as in [`SkipReason::as_str`]
+    pub reason: Option<&'static str>,
+    /// Encoded size now
+    pub current_bytes: usize,
+    /// Approximate encoded size afterwards; see [`SavingsEstimate`] for accuracy
+    pub estimated_bytes: usize,
+}
+
+/// Resample several PDFs with the same options
+///
+/// Each input gets its own result, in input order, so one corrupt file does
+/// not fail the rest. With the `parallel` feature the files are processed
+/// across the rayon thread pool.
+pub fn resample_pdf_batch(
+    inputs: Vec<&[u8]>,
+    options: &ResampleOptions,
+) -> Vec<Result<(Vec<u8>, ResampleResult), ResampleError>> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        inputs.into_par_iter().map(|input| resample_pdf_bytes(input, options)).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        inputs.into_iter().map(|input| resample_pdf_bytes(input, options)).collect()
+    }
+}
+
+/// Estimate how much resampling would shrink a PDF's images, without producing output
+///
+/// Applies the same selection rules as [`resample_pdf_bytes`] but approximates
+/// each re-encoded image's size instead of encoding it. See [`SavingsEstimate`]
+/// for the expected accuracy.
+pub fn estimate_savings(
+    pdf_bytes: &[u8],
+    options: &ResampleOptions,
+) -> Result<SavingsEstimate, ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    let mut doc = load_document(pdf_bytes, options.password.as_deref())?;
+    resolve_named_color_spaces(&mut doc);
+
+    let display_info_map = {
+        let mut scanner = ContentScanner::new(&doc, false).with_limits(options);
+        scanner.scan_all_pages();
+        scanner.get_display_info_map()
+    };
+
+    let mut estimate = SavingsEstimate::default();
+    for id in collect_image_objects(&doc) {
+        if let Ok(Object::Stream(stream)) = doc.get_object(id) {
+            estimate.current_image_bytes += stream.content.len();
+        }
+    }
+
+    for image in analyze_images(&mut doc, display_info_map, options) {
+        estimate.projected_image_bytes += image.estimated_bytes;
+        if image.decision != "skip" {
+            estimate.resamplable_images += 1;
+        }
+    }
+
+    Ok(estimate)
+}
+
+/// Work out what resampling would do to each image of a PDF, without decoding pixels
+///
+/// Images are listed after duplicates are merged, in object order. Estimated
+/// sizes use the same rough model as [`estimate_savings`].
+pub fn analyze_pdf_bytes(
+    pdf_bytes: &[u8],
+    options: &ResampleOptions,
+) -> Result<Vec<ImageAnalysis>, ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    let mut doc = load_document(pdf_bytes, options.password.as_deref())?;
+    resolve_named_color_spaces(&mut doc);
+
+    let display_info_map = {
+        let mut scanner = ContentScanner::new(&doc, false).with_limits(options);
+        scanner.scan_all_pages();
+        scanner.get_display_info_map()
+    };
+
+    Ok(analyze_images(&mut doc, display_info_map, options))
+}
+
+/// Plan every image the way `process_images_in_doc` would, merging duplicates first
+fn analyze_images(
+    doc: &mut Document,
+    display_info_map: HashMap<ObjectId, ImageDisplayInfo>,
+    options: &ResampleOptions,
+) -> Vec<ImageAnalysis> {
+    // Duplicates are merged before processing, so only surviving images count
+    let (mut display_info_map, _) = merge_duplicate_images(doc, &display_info_map);
+    if options.split_multiuse_images {
+        display_info_map = split_multiuse_images(doc, display_info_map, options).0;
+    }
+    let quiet = |_: &str| {};
+    let soft_masks = collect_soft_masks(doc);
+
+    let mut images = Vec::new();
+    for id in collect_image_objects(doc) {
+        let Ok(Object::Stream(stream)) = doc.get_object(id) else {
+            continue;
+        };
+        let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
+        let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
+        let mut image = ImageAnalysis {
+            object_id: (id.0, id.1),
+            width,
+            height,
+            target_width: width,
+            target_height: height,
+            decision: "skip",
+            reason: None,
+            current_bytes: stream.content.len(),
+            estimated_bytes: stream.content.len(),
+        };
+
+        let plan = if soft_masks.contains(&id) {
+            Err(SkipReason::SoftMask)
+        } else {
+            plan_image(doc, id, stream, &display_info_map, options, &quiet)
+        };
+        match plan {
+            Ok(plan) => {
+                let is_grayscale = match options.force_color_space {
+                    Some(forced) => forced == ForcedCs::Gray,
+                    None => stream
+                        .dict
+                        .get(b"ColorSpace")
+                        .ok()
+                        .is_some_and(|cs| get_color_space_name(cs, doc) == "DeviceGray"),
+                };
+                let from_jpeg = first_filter(&stream.dict).as_deref() == Some("DCTDecode");
+                let quality = image_quality(options, is_grayscale, from_jpeg);
+                image.target_width = plan.target_width;
+                image.target_height = plan.target_height;
+                image.decision = if plan.needs_resampling { "resample" } else { "reencode" };
+                image.estimated_bytes =
+                    estimate_jpeg_bytes(plan.target_width, plan.target_height, quality);
+            }
+            Err(reason) => image.reason = Some(reason.as_str()),
+        }
+        images.push(image);
+    }
+    images
+}
+
+/// Lowest DPI `resample_to_target_size` will go to before lowering quality
+const BUDGET_MIN_DPI: f32 = 36.0;
+/// Lowest JPEG quality `resample_to_target_size` will go to
+const BUDGET_MIN_QUALITY: u8 = 30;
+/// Most full resampling passes `resample_to_target_size` will run
+const BUDGET_MAX_RUNS: usize = 10;
+
+/// Resample a PDF so the output is at most `target_bytes`, if possible
+///
+/// Starts from `options` and lowers the target DPI, then the JPEG quality,
+/// until the output fits. Savings estimates seed the first DPI to try, and
+/// real passes then bisect towards the highest DPI (or quality) that fits.
+/// The search stops at [`BUDGET_MIN_DPI`] and [`BUDGET_MIN_QUALITY`] or after
+/// [`BUDGET_MAX_RUNS`] passes; if nothing fits, the smallest output produced
+/// is returned, so callers should check its length against the budget.
+pub fn resample_to_target_size(
+    pdf_bytes: &[u8],
+    target_bytes: usize,
+    options: &ResampleOptions,
+) -> Result<(Vec<u8>, ResampleResult), ResampleError> {
+    if options.quality == 0 || options.quality > 100 {
+        return Err(ResampleError::InvalidQuality);
+    }
+
+    let log = |msg: &str| log_verbose(options, msg);
+    // A separate gray target is lowered in proportion with the main one
+    let at_dpi = |target_dpi: f32| ResampleOptions {
+        target_dpi,
+        gray_target_dpi: options
+            .gray_target_dpi
+            .map(|gray_dpi| gray_dpi * target_dpi / options.target_dpi),
+        ..options.clone()
+    };
+    let run = |target_dpi: f32, quality: u8| {
+        let pass_options = ResampleOptions {
+            quality,
+            verbose: false,
+            ..at_dpi(target_dpi)
+        };
+        let (output, result) = resample_pdf_bytes(pdf_bytes, &pass_options)?;
+        if options.verbose {
+            log(&format!(
+                "[Budget] {:.0} DPI, quality {}: {} bytes",
+                target_dpi,
+                quality,
+                output.len()
+            ));
+        }
+        Ok::<_, ResampleError>((output, result))
+    };
+
+    let first = run(options.target_dpi, options.quality)?;
+    let mut runs = 1;
+    if first.0.len() <= target_bytes || options.target_dpi <= BUDGET_MIN_DPI {
+        return Ok(first);
+    }
+
+    // Highest DPI whose estimated output fits, found without encoding anything.
+    // Estimates are scaled so the one for the first pass matches its real size
+    let estimate = estimate_savings(pdf_bytes, options)?;
+    let other_bytes = pdf_bytes.len().saturating_sub(estimate.current_image_bytes);
+    let calibration =
+        first.0.len() as f64 / (other_bytes + estimate.projected_image_bytes).max(1) as f64;
+    let fits_estimate = |target_dpi: f32| -> Result<bool, ResampleError> {
+        let options = at_dpi(target_dpi);
+        let projected = other_bytes + estimate_savings(pdf_bytes, &options)?.projected_image_bytes;
+        Ok(projected as f64 * calibration <= target_bytes as f64)
+    };
+    let (mut low, mut high) = (BUDGET_MIN_DPI, options.target_dpi);
+    for _ in 0..6 {
+        let mid = (low + high) / 2.0;
+        if fits_estimate(mid)? {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let seed_dpi = low;
+
+    // Bisect real passes between a DPI that fits and one that does not
+    let mut smallest = first;
+    let mut best: Option<(Vec<u8>, ResampleResult)> = None;
+    let (mut fits_dpi, mut too_big_dpi) = (None, options.target_dpi);
+    let mut next_dpi = Some(seed_dpi);
+    while let Some(target_dpi) = next_dpi.take() {
+        if runs >= BUDGET_MAX_RUNS {
+            break;
+        }
+        runs += 1;
+        let output = run(target_dpi, options.quality)?;
+        if output.0.len() <= target_bytes {
+            fits_dpi = Some(target_dpi);
+            best = Some(output);
+        } else {
+            too_big_dpi = target_dpi;
+            if output.0.len() < smallest.0.len() {
+                smallest = output;
+            }
+        }
+
+        next_dpi = match fits_dpi {
+            // Stop refining once the remaining gap is a few DPI
+            Some(low) if too_big_dpi - low > 5.0 => Some((low + too_big_dpi) / 2.0),
+            Some(_) => None,
+            None if too_big_dpi > BUDGET_MIN_DPI => Some(BUDGET_MIN_DPI),
+            None => None,
+        };
+    }
+    if let Some(best) = best {
+        return Ok(best);
+    }
+
+    // Even the lowest DPI is too big: lower the quality at that DPI
+    let (mut fits_quality, mut too_big_quality) = (None, options.quality);
+    let mut next_quality =
+        (options.quality > BUDGET_MIN_QUALITY).then_some(BUDGET_MIN_QUALITY);
+    while let Some(quality) = next_quality.take() {
+        if runs >= BUDGET_MAX_RUNS {
+            break;
+        }
+        runs += 1;
+        let output = run(BUDGET_MIN_DPI, quality)?;
+        if output.0.len() <= target_bytes {
+            fits_quality = Some(quality);
+            best = Some(output);
+        } else {
+            too_big_quality = quality;
+            if output.0.len() < smallest.0.len() {
+                smallest = output;
+            }
+        }
+        next_quality = fits_quality
+            .filter(|&low| too_big_quality - low > 5)
+            .map(|low| (low + too_big_quality) / 2);
+    }
+
+    if best.is_none() && options.verbose {
+        log(&format!(
+            "[Budget] Could not reach {} bytes; smallest output is {} bytes",
+            target_bytes,
+            smallest.0.len()
+        ));
+    }
+    Ok(best.unwrap_or(smallest))
+}
+
+/// Compute the display info of every image drawn in a PDF
+///
+/// Keys are image object IDs. When an image is drawn more than once, the
+/// largest placement is reported, the same one resampling uses. Images that
+/// are never drawn are absent from the map.
+pub fn compute_display_info(
+    pdf_bytes: &[u8],
+) -> Result<HashMap<ObjectId, ImageDisplayInfo>, ResampleError> {
+    let doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+
+    let mut scanner = ContentScanner::new(&doc, false);
+    scanner.scan_all_pages();
+    Ok(scanner.get_display_info_map())
+}
+
+/// List every display size each image is drawn at
+///
+/// Unlike [`compute_display_info`], which keeps only the largest placement,
+/// this reports all of them, e.g. an image drawn at 2"x2" on one page and
+/// 8"x8" on another. Images inside a Form XObject report a placement for
+/// every use of the form.
+pub fn compute_image_usages(pdf_bytes: &[u8]) -> Result<Vec<ImageUsage>, ResampleError> {
+    let doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+
+    let mut scanner = ContentScanner::new(&doc, false);
+    scanner.scan_all_pages();
+    Ok(scanner.get_image_usages())
+}
+
+/// List where each image is drawn, with its page and bounding box
+///
+/// Coordinates are in the page's default user space (origin at the lower
+/// left of the page, scaled by /UserUnit), e.g. for matching images against
+/// OCR text positions. As with [`compute_image_usages`], every use of a Form
+/// XObject is reported, and placements entirely outside a form's /BBox are
+/// left out.
+pub fn compute_image_placements(
+    pdf_bytes: &[u8],
+) -> Result<Vec<ImagePlacement>, ResampleError> {
+    let doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+
+    let mut scanner = ContentScanner::new(&doc, false);
+    scanner.scan_all_pages();
+    Ok(scanner.get_image_placements())
+}
+
+/// Extract detailed image information from a PDF, organized by page
+pub fn extract_pdf_images_info(pdf_bytes: &[u8]) -> Result<Vec<PageImages>, ResampleError> {
+    extract_pdf_images_info_with_threshold(pdf_bytes, DEFAULT_UPSCALED_BELOW_DPI)
+}
+
+/// Like [`extract_pdf_images_info`], flagging images displayed below
+/// `upscaled_below_dpi` as upscaled
+pub fn extract_pdf_images_info_with_threshold(
+    pdf_bytes: &[u8],
+    upscaled_below_dpi: f32,
+) -> Result<Vec<PageImages>, ResampleError> {
+    let mut doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    resolve_named_color_spaces(&mut doc);
+
+    // Get display info for DPI calculation
+    let mut scanner = ContentScanner::new(&doc, false);
+    scanner.scan_all_pages();
+    let display_info_map = scanner.get_display_info_map();
+
+    // Build a map of which images appear on which pages
+    let mut page_image_map: HashMap<u32, Vec<ObjectId>> = HashMap::new();
+    
+    let pages = doc.get_pages();
+    for (page_num, &page_id) in pages.iter() {
+        let page_images = collect_page_images(&doc, page_id);
+        page_image_map.insert(*page_num, page_images);
+    }
+
+    // Collect all image info
+    let mut result: Vec<PageImages> = Vec::new();
+
+    for (page_num, image_ids) in page_image_map.iter() {
+        let mut images: Vec<ImageInfo> = Vec::new();
+
+        for &obj_id in image_ids {
+            if let Ok(Object::Stream(stream)) = doc.get_object(obj_id) {
+                let info = extract_image_info_from_stream(
+                    obj_id,
+                    stream,
+                    &doc,
+                    display_info_map.get(&obj_id),
+                    false,
+                    upscaled_below_dpi,
+                );
+                images.push(info);
+
+                // Check for SMask
+                if let Ok(Object::Reference(smask_id)) = stream.dict.get(b"SMask") {
+                    if let Ok(Object::Stream(smask_stream)) = doc.get_object(*smask_id) {
+                        let smask_info = extract_image_info_from_stream(
+                            *smask_id,
+                            smask_stream,
+                            &doc,
+                            None,
+                            true,
+                            upscaled_below_dpi,
+                        );
+                        images.push(smask_info);
+                    }
+                }
+            }
+        }
+
+        if !images.is_empty() {
+            result.push(PageImages {
+                page_number: *page_num,
+                images,
+            });
+        }
+    }
+
+    // Sort by page number
+    result.sort_by_key(|p| p.page_number);
+
+    Ok(result)
+}
+
+/// List images that the current build cannot decode (and so will never be resampled)
+///
+/// Images are classified from their dictionaries by the same rules as
+/// [`validate_pdf`]; an image that is supported but has corrupt data is not listed.
+pub fn unsupported_images(pdf_bytes: &[u8]) -> Result<Vec<ImageInfo>, ResampleError> {
+    let mut doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    resolve_named_color_spaces(&mut doc);
+
+    let mut scanner = ContentScanner::new(&doc, false);
+    scanner.scan_all_pages();
+    let display_info_map = scanner.get_display_info_map();
+
+    let mut result = Vec::new();
+
+    for (id, object) in doc.objects.iter() {
+        if let Object::Stream(stream) = object {
+            if !matches!(stream.dict.get(b"Subtype"), Ok(Object::Name(n)) if n == b"Image") {
+                continue;
+            }
+            let info = extract_image_info_from_stream(
+                *id,
+                stream,
+                &doc,
+                display_info_map.get(id),
+                false,
+                DEFAULT_UPSCALED_BELOW_DPI,
+            );
+            if unsupported_image_reason(&doc, stream, &info).is_some() {
+                result.push(info);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Extracted image data with format information
+#[derive(Debug, Clone)]
+pub struct ExtractedImage {
+    /// Image data bytes
+    pub data: Vec<u8>,
+    /// Format: "jpeg" or "png"
+    pub format: String,
+    /// MIME type
+    pub mime_type: String,
+}
+
+/// Extract a single image from a PDF in its native format when possible
+/// Returns JPEG for DCTDecode images, PNG for others
+/// object_id format: "num gen" e.g. "12 0"
+pub fn extract_image_native(pdf_bytes: &[u8], object_id_str: &str) -> Result<ExtractedImage, ResampleError> {
+    let mut doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    resolve_named_color_spaces(&mut doc);
+
+    // Parse object ID
+    let parts: Vec<&str> = object_id_str.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(ResampleError::ProcessingError("Invalid object ID format".to_string()));
+    }
+    
+    let obj_num: u32 = parts[0].parse()
+        .map_err(|_| ResampleError::ProcessingError("Invalid object number".to_string()))?;
+    let gen_num: u16 = parts[1].parse()
+        .map_err(|_| ResampleError::ProcessingError("Invalid generation number".to_string()))?;
+    
+    let obj_id: ObjectId = (obj_num, gen_num);
+
+    extract_image_from_doc(&doc, obj_id)
+}
+
+/// Extract every image XObject from a PDF in its native format
+/// Loads the document once; images that cannot be decoded are skipped
+/// Each entry is keyed by its object ID in "num gen" format
+pub fn extract_all_images_native(
+    pdf_bytes: &[u8],
+) -> Result<Vec<(String, ExtractedImage)>, ResampleError> {
+    let mut doc = Document::load_mem(pdf_bytes)
+        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    resolve_named_color_spaces(&mut doc);
+
+    let mut result = Vec::new();
+
+    for (id, object) in doc.objects.iter() {
+        if let Object::Stream(stream) = object {
+            let subtype = stream.dict.get(b"Subtype").ok().and_then(|s| match s {
+                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+                _ => None,
+            });
+
+            if subtype.as_deref() == Some("Image") {
+                if let Ok(image) = extract_image_from_doc(&doc, *id) {
+                    result.push((format!("{} {}", id.0, id.1), image));
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Extract a single image from an already-loaded document
+fn extract_image_from_doc(doc: &Document, obj_id: ObjectId) -> Result<ExtractedImage, ResampleError> {
+    // Get the stream
+    let stream = match doc.get_object(obj_id) {
+        Ok(Object::Stream(s)) if matches!(s.dict.get(b"Subtype"), Ok(Object::Name(n)) if n == b"Image") => s,
+        _ => return Err(ResampleError::ProcessingError("Object is not an image stream".to_string())),
+    };
+
+    // Check filter type
+    let filter = stream
+        .dict
+        .get(b"Filter")
+        .ok()
+        .and_then(|f| match f {
+            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+            Object::Array(arr) => arr.first().and_then(|f| match f {
+                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+                _ => None,
+            }),
+            _ => None,
+        });
+
+    // Check for SMask (alpha channel)
+    let has_smask = stream.dict.get(b"SMask").is_ok();
+
+    let color_space = stream
+        .dict
+        .get(b"ColorSpace")
+        .ok()
+        .map(|cs| get_color_space_name(cs, doc))
+        .unwrap_or_else(|| "DeviceRGB".to_string());
+
+    // Extracted pixels are viewed without the PDF's /Decode, so an inverting one
+    // has to be applied to them; a plain JPEG copy cannot carry it
+    let polarity_inverted = match color_space.as_str() {
+        "DeviceGray" | "Gray" | "CalGray" => decode_is_inverted(&stream.dict, 1),
+        "DeviceRGB" | "RGB" | "CalRGB" => decode_is_inverted(&stream.dict, 3),
+        _ => false,
+    };
+
+    // If it's a JPEG without SMask, return the raw JPEG data
+    if filter.as_deref() == Some("DCTDecode") && !has_smask && !polarity_inverted {
+        return Ok(ExtractedImage {
+            data: stream.content.clone(),
+            format: "jpeg".to_string(),
+            mime_type: "image/jpeg".to_string(),
+        });
+    }
+
+    // Otherwise, decode and convert to PNG
+    let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
+
+    let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
+
+    if width == 0 || height == 0 {
+        return Err(ResampleError::ProcessingError("Invalid image dimensions".to_string()));
+    }
+
+    let bits_per_component = get_int_resolved(&stream.dict, b"BitsPerComponent", doc).unwrap_or(8);
+
+    // Keep 16-bit and 1/2/4-bit samples at their own depth rather than
+    // truncating or widening them to 8 bits
+    if bits_per_component != 8 && !has_smask {
+        if let Some(png_bytes) =
+            encode_png_native_depth(stream, width, height, &color_space, bits_per_component)
+        {
+            return Ok(ExtractedImage {
+                data: png_bytes,
+                format: "png".to_string(),
+                mime_type: "image/png".to_string(),
+            });
+        }
+    }
+
+    // Decode the image
+    let mut img = decode_image_stream(
+        doc,
+        stream,
+        width,
+        height,
+        &color_space,
+        bits_per_component,
+        DEFAULT_MAX_DECOMPRESSED_BYTES,
+    )
+    .map_err(|reason| ResampleError::DecodeError { object_id: obj_id, reason })?;
+    if polarity_inverted {
+        img.invert();
+    }
+
+    // Check for SMask and apply alpha
+    let final_img = if let Ok(Object::Reference(smask_id)) = stream.dict.get(b"SMask") {
+        if let Ok(Object::Stream(smask_stream)) = doc.get_object(*smask_id) {
+            apply_smask(doc, &img, smask_stream, DEFAULT_MAX_DECOMPRESSED_BYTES).unwrap_or(img)
+        } else {
+            img
+        }
+    } else {
+        img
+    };
+
+    // Encode as PNG
+    let mut png_bytes = Vec::new();
+    final_img.write_to(&mut std::io::Cursor::new(&mut png_bytes), image::ImageFormat::Png)
+        .map_err(|e| ResampleError::ProcessingError(format!("Failed to encode PNG: {}", e)))?;
+
+    Ok(ExtractedImage {
+        data: png_bytes,
+        format: "png".to_string(),
+        mime_type: "image/png".to_string(),
+    })
+}
+
+/// Encode a gray or RGB image as PNG at its original bit depth
+///
+/// PDF and PNG pack samples the same way (rows padded to a whole byte, most
+/// significant bit first, 16-bit samples big-endian), so the decoded rows are
+/// written out unchanged. Image masks are written as 1-bit gray with painted
+/// areas black. Returns None for anything this cannot represent exactly, which
+/// then goes through the regular 8-bit path.
+fn encode_png_native_depth(
+    stream: &Stream,
+    width: u32,
+    height: u32,
+    color_space: &str,
+    bits_per_component: u32,
+) -> Option<Vec<u8>> {
+    let bit_depth = match bits_per_component {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        16 => png::BitDepth::Sixteen,
+        _ => return None,
+    };
+
+    // Predictors would leave filter bytes in the rows
+    if stream.dict.get(b"DecodeParms").is_ok() {
+        return None;
+    }
+    let data = match stream.dict.get(b"Filter").ok() {
+        None => stream.content.clone(),
+        Some(Object::Name(n)) if n == b"FlateDecode" => {
+            inflate(&stream.content, DEFAULT_MAX_DECOMPRESSED_BYTES).ok()?
+        }
+        Some(Object::Array(arr))
+            if arr.len() == 1 && matches!(&arr[0], Object::Name(n) if n == b"FlateDecode") =>
+        {
+            inflate(&stream.content, DEFAULT_MAX_DECOMPRESSED_BYTES).ok()?
+        }
+        _ => return None,
+    };
+
+    let is_mask = matches!(stream.dict.get(b"ImageMask"), Ok(Object::Boolean(true)));
+    let row_bytes = |components: u32| (width * components * bits_per_component).div_ceil(8) as usize;
+    let components = match color_space {
+        _ if is_mask => 1,
+        "DeviceGray" | "Gray" | "CalGray" => 1,
+        "DeviceRGB" | "RGB" | "CalRGB" => 3,
+        // Same size-based guess as the 8-bit decoder
+        "ICCBased" if data.len() >= row_bytes(3) * height as usize => 3,
+        "ICCBased" => 1,
+        _ => return None,
+    };
+    // PNG has no low-bit-depth RGB
+    if components == 3 && bits_per_component < 8 {
+        return None;
+    }
+    let expected_size = row_bytes(components) * height as usize;
+    if data.len() < expected_size {
+        return None;
+    }
+    let mut data = data[..expected_size].to_vec();
+
+    // Inverting every bit inverts every sample, whatever the packing
+    if decode_is_inverted(&stream.dict, components as usize) {
+        data.iter_mut().for_each(|b| *b = !*b);
+    }
+
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, width, height);
+    encoder.set_color(if components == 3 {
+        png::ColorType::Rgb
+    } else {
+        png::ColorType::Grayscale
+    });
+    encoder.set_depth(bit_depth);
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&data).ok()?;
+    writer.finish().ok()?;
+    Some(png_bytes)
+}
+
+/// Aggregate [`extract_pdf_images_info`] into document-wide statistics
+pub fn summarize_pdf_images(pdf_bytes: &[u8]) -> Result<DocumentImageSummary, ResampleError> {
+    let pages = extract_pdf_images_info(pdf_bytes)?;
+
+    let mut summary = DocumentImageSummary::default();
+    let mut seen: HashSet<(u32, u16)> = HashSet::new();
+    let mut dpis: Vec<f32> = Vec::new();
+    for img in pages.iter().flat_map(|page| &page.images) {
+        if !seen.insert(img.object_id) {
+            continue;
+        }
+        summary.total_image_bytes += img.size_bytes;
+        if img.image_type == "smask" {
+            summary.smask_count += 1;
+            continue;
+        }
+        summary.image_count += 1;
+        match (img.dpi_x, img.dpi_y) {
+            (Some(x), Some(y)) => {
+                summary.images_with_dpi += 1;
+                dpis.push(x.max(y));
+            }
+            _ => summary.images_without_dpi += 1,
+        }
+        *summary.by_color_space.entry(img.color_space.clone()).or_default() += 1;
+        *summary.by_filter.entry(img.filter.clone()).or_default() += 1;
+    }
+
+    dpis.sort_by(|a, b| a.total_cmp(b));
+    summary.min_dpi = dpis.first().copied();
+    summary.max_dpi = dpis.last().copied();
+    summary.median_dpi = match dpis.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(dpis[n / 2]),
+        n => Some((dpis[n / 2 - 1] + dpis[n / 2]) / 2.0),
+    };
+    Ok(summary)
+}
+
+/// Check that a PDF loads and report which images the resampler cannot decode
+///
+/// Only dictionaries are inspected, so this is cheap enough to triage large
+/// batches; an image reported as supported can still fail on corrupt data.
+/// The file is loaded as for resampling, so a damaged xref table is rebuilt
+/// and an encrypted file opens with its `password`.
+pub fn validate_pdf(
+    pdf_bytes: &[u8],
+    password: Option<&str>,
+) -> Result<ValidationReport, ResampleError> {
+    let mut doc = load_document(pdf_bytes, password)?;
+    resolve_named_color_spaces(&mut doc);
+
+    let smask_ids: HashSet<ObjectId> = doc
+        .objects
+        .values()
+        .filter_map(|object| match object {
+            Object::Stream(stream) => match stream.dict.get(b"SMask") {
+                Ok(Object::Reference(id)) => Some(*id),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+
+    let mut report = ValidationReport {
+        page_count: doc.get_pages().len(),
+        ..Default::default()
+    };
+    for (&obj_id, object) in doc.objects.iter() {
+        let Object::Stream(stream) = object else {
+            continue;
+        };
+        if !matches!(stream.dict.get(b"Subtype"), Ok(Object::Name(n)) if n == b"Image") {
+            continue;
+        }
+
+        let is_smask = smask_ids.contains(&obj_id);
+        let info = extract_image_info_from_stream(obj_id, stream, &doc, None, is_smask, 0.0);
+        let reason = unsupported_image_reason(&doc, stream, &info);
+        if reason.is_some() {
+            report.unsupported_count += 1;
+        }
+        report.images.push(ImageValidation {
+            object_id: info.object_id,
+            image_type: info.image_type,
+            width: info.width,
+            height: info.height,
+            color_space: info.color_space,
+            bits_per_component: info.bits_per_component,
+            filter: info.filter,
+            supported: reason.is_none(),
+            reason,
+        });
+    }
+    Ok(report)
+}
+
+/// Why an image listed by [`validate_pdf`] or [`unsupported_images`] cannot be resampled
+fn unsupported_image_reason(doc: &Document, stream: &Stream, info: &ImageInfo) -> Option<String> {
+    if info.width == 0 || info.height == 0 {
+        return Some("missing or zero dimensions".to_string());
+    }
+    // Soft masks are decoded as gray, other images without /ColorSpace as DeviceRGB
+    let color_space = match stream.dict.get(b"ColorSpace") {
+        _ if info.image_type == "smask" => "DeviceGray".to_string(),
+        Ok(cs) => get_color_space_name(cs, doc),
+        Err(_) => "DeviceRGB".to_string(),
+    };
+    undecodable_reason(doc, &stream.dict, &color_space, info.bits_per_component)
+}
+
+/// Convert page images to a JSON-serializable structure
+pub fn page_images_to_json(pages: &[PageImages]) -> Vec<serde_json::Value> {
+    pages.iter().map(|page| {
+        serde_json::json!({
+            "page": page.page_number,
+            "images": page.images.iter().map(image_info_to_json).collect::<Vec<_>>()
+        })
+    }).collect()
+}
+
+/// Convert a single image's info to a JSON-serializable structure
+pub fn image_info_to_json(img: &ImageInfo) -> serde_json::Value {
+    serde_json::json!({
+        "objectId": format!("{} {}", img.object_id.0, img.object_id.1),
+        "type": img.image_type,
+        "width": img.width,
+        "height": img.height,
+        "colorSpace": img.color_space,
+        "bpc": img.bits_per_component,
+        "filter": img.filter,
+        "size": img.size_bytes,
+        "dpiX": img.dpi_x,
+        "dpiY": img.dpi_y,
+        "skipReason": img.skip_reason,
+        "isUpscaled": img.is_upscaled
+    })
+}
+
+/// Collect all image object IDs referenced from a page
+fn collect_page_images(doc: &Document, page_id: ObjectId) -> Vec<ObjectId> {
+    let mut images: Vec<ObjectId> = Vec::new();
+    let mut seen: HashSet<ObjectId> = HashSet::new();
+
+    let page_dict = match doc.get_object(page_id) {
+        Ok(Object::Dictionary(d)) => d,
+        _ => return images,
+    };
+
+    // Get resources
+    let resources = get_page_resources_static(doc, page_id);
+
+    // Get XObjects from resources
+    let xobjects = get_xobjects_static(doc, &resources);
+
+    // Check each XObject
+    for (_, &obj_id) in xobjects.iter() {
+        collect_xobject_images(doc, obj_id, &mut images, &mut seen);
+    }
+
+    // Appearance streams are forms, but often without /Subtype /Form
+    for appearance_id in annotation_appearances(doc, page_dict) {
+        if !seen.insert(appearance_id) {
+            continue;
+        }
+        if let Ok(Object::Stream(stream)) = doc.get_object(appearance_id) {
+            if let Ok(res) = stream.dict.get(b"Resources") {
+                let xobjects = get_xobjects_static(doc, res);
+                for (_, &child_id) in xobjects.iter() {
+                    collect_xobject_images(doc, child_id, &mut images, &mut seen);
+                }
+            }
+        }
+    }
+
+    images
+}
+
+/// Appearance streams of a page's annotations, in every state (N, R and D)
+///
+/// Matches what the content scanner measures in `scan_annotation`.
+fn annotation_appearances(doc: &Document, page_dict: &Dictionary) -> Vec<ObjectId> {
+    let resolve = |obj: &Object| -> Option<Object> {
+        match obj {
+            Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+            other => Some(other.clone()),
+        }
+    };
+
+    let mut appearances = Vec::new();
+    let Some(Object::Array(annots)) = page_dict.get(b"Annots").ok().and_then(resolve) else {
+        return appearances;
+    };
+    for annot in annots {
+        let Ok(annot_id) = annot.as_reference() else {
+            continue;
+        };
+        let Ok(Object::Dictionary(annot_dict)) = doc.get_object(annot_id) else {
+            continue;
+        };
+        let Some(Object::Dictionary(ap)) = annot_dict.get(b"AP").ok().and_then(resolve) else {
+            continue;
+        };
+        for key in [b"N".as_slice(), b"R".as_slice(), b"D".as_slice()] {
+            let Ok(appearance) = ap.get(key) else {
+                continue;
+            };
+            match appearance {
+                Object::Reference(id) => match doc.get_object(*id) {
+                    Ok(Object::Stream(_)) => appearances.push(*id),
+                    // A dictionary of appearance states
+                    Ok(Object::Dictionary(states)) => appearances
+                        .extend(states.iter().filter_map(|(_, state)| state.as_reference().ok())),
+                    _ => {}
+                },
+                Object::Dictionary(states) => appearances
+                    .extend(states.iter().filter_map(|(_, state)| state.as_reference().ok())),
+                _ => {}
+            }
+        }
+    }
+    appearances
+}
+
+/// Collect images from an XObject, following nested Form XObjects
+///
+/// Forms are walked with a worklist rather than recursion, so a chain of
+/// forms nested thousands deep cannot overflow the stack.
+fn collect_xobject_images(
+    doc: &Document,
+    obj_id: ObjectId,
+    images: &mut Vec<ObjectId>,
+    seen: &mut HashSet<ObjectId>,
+) {
+    let mut pending = vec![obj_id];
+    while let Some(obj_id) = pending.pop() {
+        if !seen.insert(obj_id) {
+            continue;
+        }
+
+        let stream = match doc.get_object(obj_id) {
+            Ok(Object::Stream(s)) => s,
+            _ => continue,
+        };
+
+        let subtype = stream.dict.get(b"Subtype").ok().and_then(|s| match s {
+            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+            _ => None,
+        });
+
+        match subtype.as_deref() {
+            Some("Image") => {
+                images.push(obj_id);
+            }
+            Some("Form") => {
+                // Get resources from Form XObject and walk its XObjects next
+                if let Ok(res) = stream.dict.get(b"Resources") {
+                    pending.extend(get_xobjects_static(doc, res).into_values());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Replace image /ColorSpace names that refer to resource entries with the color space they name
+///
+/// Image XObjects should name a color space family directly, but some producers
+/// use a resource name such as /CS0 instead. Resolving these once up front lets
+/// every later stage read the color space from the image dictionary alone.
+fn resolve_named_color_spaces(doc: &mut Document) {
+    let mut resolved: HashMap<ObjectId, Object> = HashMap::new();
+    let mut seen: HashSet<ObjectId> = HashSet::new();
+
+    for (_, page_id) in doc.get_pages() {
+        let resources = get_page_resources_static(doc, page_id);
+        collect_named_color_spaces(doc, &resources, &mut resolved, &mut seen);
+    }
+
+    for (image_id, color_space) in resolved {
+        if let Ok(Object::Stream(stream)) = doc.get_object_mut(image_id) {
+            stream.dict.set("ColorSpace", color_space);
+        }
+    }
+}
+
+/// Find images under a resource dictionary whose /ColorSpace is a name defined in that dictionary
+fn collect_named_color_spaces(
+    doc: &Document,
+    resources: &Object,
+    resolved: &mut HashMap<ObjectId, Object>,
+    seen: &mut HashSet<ObjectId>,
+) {
+    // Nested forms go on a worklist rather than the call stack
+    let mut pending = vec![resources.clone()];
+    while let Some(resources) = pending.pop() {
+        let res_dict = match &resources {
+            Object::Dictionary(d) => Some(d),
+            Object::Reference(id) => match doc.get_object(*id) {
+                Ok(Object::Dictionary(d)) => Some(d),
+                _ => None,
+            },
+            _ => None,
+        };
+        let color_spaces = res_dict
+            .and_then(|d| d.get(b"ColorSpace").ok())
+            .and_then(|cs| match cs {
+                Object::Dictionary(d) => Some(d),
+                Object::Reference(id) => match doc.get_object(*id) {
+                    Ok(Object::Dictionary(d)) => Some(d),
+                    _ => None,
+                },
+                _ => None,
+            });
+
+        for (_, obj_id) in get_xobjects_static(doc, &resources) {
+            if !seen.insert(obj_id) {
+                continue;
+            }
+            let stream = match doc.get_object(obj_id) {
+                Ok(Object::Stream(s)) => s,
+                _ => continue,
+            };
+
+            match stream.dict.get(b"Subtype") {
+                Ok(Object::Name(n)) if n == b"Image" => {
+                    let name = match stream.dict.get(b"ColorSpace") {
+                        Ok(Object::Name(name)) => name,
+                        _ => continue,
+                    };
+                    let is_family = matches!(
+                        name.as_slice(),
+                        b"DeviceGray" | b"DeviceRGB" | b"DeviceCMYK" | b"Pattern"
+                    );
+                    if let Some(color_space) = color_spaces.and_then(|d| d.get(name).ok()) {
+                        if !is_family {
+                            resolved.insert(obj_id, color_space.clone());
+                        }
+                    }
+                }
+                Ok(Object::Name(n)) if n == b"Form" => {
+                    // Forms without their own resources use the enclosing ones
+                    let form_resources = stream
+                        .dict
+                        .get(b"Resources")
+                        .cloned()
+                        .unwrap_or_else(|_| resources.clone());
+                    pending.push(form_resources);
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
-/// Check if an image has meaningful alpha
-fn has_alpha(img: &DynamicImage) -> bool {
-    match img {
-        DynamicImage::ImageRgba8(rgba) => {
-            let sample_rate = std::cmp::max(1, rgba.pixels().len() / 10000);
-            rgba.pixels().step_by(sample_rate).any(|p| p.0[3] < 255)
+/// Get page resources, inherited from the nearest node up the page tree that has them
+///
+/// Falls back to the page tree root's resources for a page with a broken /Parent.
+fn get_page_resources_static(doc: &Document, page_id: ObjectId) -> Object {
+    let mut node = page_id;
+    let mut visited = HashSet::new();
+    while visited.insert(node) {
+        let Ok(dict) = doc.get_dictionary(node) else {
+            break;
+        };
+        if let Ok(resources) = dict.get(b"Resources") {
+            return resources.clone();
         }
-        DynamicImage::ImageLumaA8(la) => {
-            let sample_rate = std::cmp::max(1, la.pixels().len() / 10000);
-            la.pixels().step_by(sample_rate).any(|p| p.0[1] < 255)
+        match dict.get(b"Parent") {
+            Ok(Object::Reference(parent)) => node = *parent,
+            _ => break,
         }
-        _ => false,
     }
+
+    doc.catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Pages").ok())
+        .and_then(|pages| pages.as_reference().ok())
+        .and_then(|pages_id| doc.get_dictionary(pages_id).ok())
+        .and_then(|pages| pages.get(b"Resources").ok())
+        .cloned()
+        .unwrap_or(Object::Null)
 }
 
-/// Resample an image to target dimensions
-fn resample_image(img: &DynamicImage, target_width: u32, target_height: u32) -> DynamicImage {
-    img.resize_exact(
-        target_width,
-        target_height,
-        image::imageops::FilterType::Lanczos3,
-    )
+/// Get XObjects from resources (static version)
+///
+/// The resources, their /XObject subdictionary and each entry may all be
+/// indirect, through any number of references; entries map to the ID of the
+/// stream the chain ends at. PDF requires streams to be indirect objects, and
+/// lopdf only parses them as such, so an entry that is not a reference cannot
+/// name an XObject and is ignored.
+fn get_xobjects_static(doc: &Document, resources: &Object) -> HashMap<String, ObjectId> {
+    let mut result = HashMap::new();
+
+    let res_dict = match doc.dereference(resources) {
+        Ok((_, Object::Dictionary(d))) => d,
+        _ => return result,
+    };
+    let xobj_dict = match res_dict.get(b"XObject").map(|x| doc.dereference(x)) {
+        Ok(Ok((_, Object::Dictionary(d)))) => d,
+        _ => return result,
+    };
+
+    for (name, value) in xobj_dict.iter() {
+        if let Ok((Some(obj_id), Object::Stream(_))) = doc.dereference(value) {
+            result.insert(String::from_utf8_lossy(name).to_string(), obj_id);
+        }
+    }
+
+    result
 }
 
-/// Process images in PDF document (in-memory version)
-fn process_images_in_doc(
-    doc: &mut Document,
-    display_info_map: &HashMap<ObjectId, ImageDisplayInfo>,
-    options: &ResampleOptions,
-    log: impl Fn(&str),
-) -> Result<ResampleResult, String> {
-    let mut total_images = 0;
-    let mut resampled_images = 0;
-    let mut skipped_images = 0;
+/// Extract image info from a stream object
+fn extract_image_info_from_stream(
+    obj_id: ObjectId,
+    stream: &Stream,
+    doc: &Document,
+    display_info: Option<&ImageDisplayInfo>,
+    is_smask: bool,
+    upscaled_below_dpi: f32,
+) -> ImageInfo {
+    let width = get_int_resolved(&stream.dict, b"Width", doc).unwrap_or(0);
 
-    // Collect all image XObjects
-    let mut image_objects: Vec<ObjectId> = Vec::new();
+    let height = get_int_resolved(&stream.dict, b"Height", doc).unwrap_or(0);
 
-    for (id, object) in doc.objects.iter() {
-        if let Object::Stream(stream) = object {
-            let subtype = stream.dict.get(b"Subtype").ok().and_then(|s| match s {
+    let color_space = if is_smask {
+        "DeviceGray".to_string()
+    } else {
+        stream
+            .dict
+            .get(b"ColorSpace")
+            .ok()
+            .map(|cs| get_color_space_name(cs, doc))
+            .unwrap_or_else(|| "Unknown".to_string())
+    };
+
+    let bits_per_component = get_int_resolved(&stream.dict, b"BitsPerComponent", doc).unwrap_or(8);
+
+    let filter = stream
+        .dict
+        .get(b"Filter")
+        .ok()
+        .and_then(|f| match f {
+            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
+            Object::Array(arr) => arr.first().and_then(|f| match f {
                 Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
                 _ => None,
+            }),
+            _ => None,
+        })
+        .unwrap_or_else(|| "raw".to_string());
+    let skip_reason = unsupported_filter_reason(&filter).map(str::to_string);
+
+    let dpi_x = display_info.map(|info| info.effective_dpi_x());
+    let dpi_y = display_info.map(|info| info.effective_dpi_y());
+    let is_upscaled = match (dpi_x, dpi_y) {
+        (Some(x), Some(y)) => x.min(y) < upscaled_below_dpi,
+        _ => false,
+    };
+
+    ImageInfo {
+        object_id: (obj_id.0, obj_id.1),
+        image_type: if is_smask { "smask".to_string() } else { "image".to_string() },
+        width,
+        height,
+        color_space,
+        bits_per_component,
+        filter,
+        size_bytes: stream.content.len(),
+        dpi_x,
+        dpi_y,
+        skip_reason,
+        is_upscaled,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod file_ops {
+    use super::*;
+    use std::path::Path;
+
+    /// Write a file through a temporary sibling that is renamed over `path`
+    ///
+    /// Readers never see a half-written file, and `path` is left untouched if
+    /// writing fails, so it is safe to use on the file that was read.
+    pub fn write_atomically(
+        path: &Path,
+        write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let file_name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "output path has no file name")
+        })?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+
+        let result = std::fs::File::create(&temp_path)
+            .map(std::io::BufWriter::new)
+            .and_then(|mut file| {
+                write(&mut file)?;
+                let file = file.into_inner().map_err(|e| e.into_error())?;
+                file.sync_all()
+            })
+            .and_then(|()| {
+                // Keep the permissions of a file being replaced
+                if let Ok(metadata) = std::fs::metadata(path) {
+                    std::fs::set_permissions(&temp_path, metadata.permissions())?;
+                }
+                std::fs::rename(&temp_path, path)
             });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
 
-            if subtype.as_deref() == Some("Image") {
-                image_objects.push(*id);
+    /// Resample PDF from file path to file path
+    ///
+    /// The output is written atomically, so `output_path` may be the same as
+    /// `input_path`.
+    pub fn resample_pdf_file(
+        input_path: &Path,
+        output_path: &Path,
+        options: &ResampleOptions,
+    ) -> Result<ResampleResult, ResampleError> {
+        if options.quality == 0 || options.quality > 100 {
+            return Err(ResampleError::InvalidQuality);
+        }
+
+        let load_start = now_ms();
+        let bytes = std::fs::read(input_path)
+            .map_err(|e| ResampleError::LoadError(format!("{:?}: {}", input_path, e)))?;
+        let mut doc = load_document_with_logger(&bytes, options.password.as_deref(), |msg| {
+            log_verbose(options, msg)
+        })
+        .map_err(|e| match e {
+            ResampleError::LoadError(msg) => {
+                ResampleError::LoadError(format!("{:?}: {}", input_path, msg))
             }
+            other => other,
+        })?;
+        let load_ms = now_ms() - load_start;
+
+        let result = resample_document(&mut doc, options)?;
+
+        // Save
+        let save_start = now_ms();
+        write_atomically(output_path, |file| save_document(&mut doc, file, options))
+            .map_err(|e| ResampleError::SaveError(format!("{:?}: {}", output_path, e)))?;
+
+        if options.verbose {
+            println!("[Timing] load {:.0} ms, save {:.0} ms", load_ms, now_ms() - save_start);
         }
+
+        Ok(result)
     }
+}
 
-    if options.verbose {
-        log(&format!("[Process] Found {} image XObjects", image_objects.len()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// Uncompressed 8-bit RGB image XObject holding a smooth gradient
+    fn rgb_image(width: u32, height: u32) -> Stream {
+        let pixels = RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        });
+        Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+            },
+            pixels.into_raw(),
+        )
     }
 
-    // Process each image
-    for object_id in image_objects {
-        let stream = match doc.get_object(object_id) {
-            Ok(Object::Stream(s)) => s.clone(),
-            _ => continue,
-        };
+    /// Add a page with `xobjects` as its XObject resources, returning the page's ID
+    fn add_page(doc: &mut Document, pages_id: ObjectId, xobjects: Dictionary, content: &str) -> ObjectId {
+        let content_id = doc.add_object(Stream::new(Dictionary::new(), content.as_bytes().to_vec()));
+        doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            "Resources" => dictionary! { "XObject" => xobjects },
+            "Contents" => content_id,
+        })
+    }
 
-        total_images += 1;
+    /// Add a page drawing `image` as /Im0 with `content`, returning the image's ID
+    fn add_image_page(doc: &mut Document, pages_id: ObjectId, image: Stream, content: &str) -> ObjectId {
+        let image_id = doc.add_object(image);
+        add_page(doc, pages_id, dictionary! { "Im0" => image_id }, content);
+        image_id
+    }
 
-        // Get image dimensions
-        let width = stream
-            .dict
-            .get(b"Width")
-            .ok()
-            .and_then(|w| match w {
-                Object::Integer(n) => Some(*n as u32),
-                _ => None,
-            })
-            .unwrap_or(0);
+    /// Form XObject with the given content, XObject resources and /BBox
+    fn form(content: &str, xobjects: Dictionary, bbox: [i64; 4]) -> Stream {
+        Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                "BBox" => bbox.iter().map(|&v| v.into()).collect::<Vec<Object>>(),
+                "Resources" => dictionary! { "XObject" => xobjects },
+            },
+            content.as_bytes().to_vec(),
+        )
+    }
 
-        let height = stream
-            .dict
-            .get(b"Height")
-            .ok()
-            .and_then(|h| match h {
-                Object::Integer(n) => Some(*n as u32),
-                _ => None,
+    /// Finish a document whose pages were added under `pages_id`
+    fn finish_document(doc: &mut Document, pages_id: ObjectId) -> Vec<u8> {
+        let kids: Vec<Object> = doc
+            .objects
+            .iter()
+            .filter(|(_, obj)| {
+                obj.as_dict().is_ok_and(|d| d.get(b"Type").ok() == Some(&Object::Name(b"Page".to_vec())))
             })
-            .unwrap_or(0);
+            .map(|(id, _)| Object::Reference(*id))
+            .collect();
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
 
-        if width == 0 || height == 0 {
-            if options.verbose {
-                log(&format!("[Process] Skipping {:?}: invalid dimensions", object_id));
+    /// One-page PDF drawing `image` with `content`
+    fn single_image_pdf(image: Stream, content: &str) -> (Vec<u8>, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = add_image_page(&mut doc, pages_id, image, content);
+        (finish_document(&mut doc, pages_id), image_id)
+    }
+
+    /// Image stream with `id` in a saved PDF
+    fn image_stream(pdf: &[u8], id: ObjectId) -> Stream {
+        let doc = Document::load_mem(pdf).unwrap();
+        doc.get_object(id).unwrap().as_stream().unwrap().clone()
+    }
+
+    /// Image dictionary with `id` in a saved PDF
+    fn image_dict(pdf: &[u8], id: ObjectId) -> Dictionary {
+        image_stream(pdf, id).dict
+    }
+
+    #[test]
+    fn intent_survives_reencoding() {
+        let mut image = rgb_image(400, 400);
+        image.dict.set("Intent", Object::Name(b"Perceptual".to_vec()));
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let dict = image_dict(&output, id);
+        assert_eq!(dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+        assert_eq!(dict.get(b"Intent").unwrap(), &Object::Name(b"Perceptual".to_vec()));
+    }
+
+    #[test]
+    fn color_key_masked_image_is_skipped() {
+        let mut image = rgb_image(400, 400);
+        let key: Vec<Object> = vec![0.into(), 10.into(), 0.into(), 10.into(), 120.into(), 130.into()];
+        image.dict.set("Mask", key.clone());
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 0);
+        assert_eq!(result.skip_reasons.get(&SkipReason::ColorKeyMask), Some(&1));
+        let dict = image_dict(&output, id);
+        assert_eq!(dict.get(b"Mask").unwrap(), &Object::Array(key));
+        assert_eq!(dict.get(b"Width").unwrap(), &Object::Integer(400));
+    }
+
+    /// Display size of each image as recorded by the scanner
+    fn display_sizes(pdf: &[u8]) -> HashMap<ObjectId, (f32, f32)> {
+        compute_display_info(pdf)
+            .unwrap()
+            .into_iter()
+            .map(|(id, info)| (id, (info.display_width_points, info.display_height_points)))
+            .collect()
+    }
+
+    #[test]
+    fn unbalanced_q_in_form_keeps_parent_ctm() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let inner_id = doc.add_object(rgb_image(40, 20));
+        let outer_id = doc.add_object(rgb_image(40, 20));
+        let form_id = doc.add_object(form(
+            "Q Q /Im1 Do Q 5 0 0 5 0 0 cm",
+            dictionary! { "Im1" => inner_id },
+            [0, 0, 1, 1],
+        ));
+        add_page(
+            &mut doc,
+            pages_id,
+            dictionary! { "Fm0" => form_id, "Im0" => outer_id },
+            "q 200 0 0 100 0 0 cm /Fm0 Do /Im0 Do Q",
+        );
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let sizes = display_sizes(&pdf);
+        assert_eq!(sizes[&inner_id], (200.0, 100.0));
+        assert_eq!(sizes[&outer_id], (200.0, 100.0));
+    }
+
+
+    /// Encrypt a PDF with RC4 under the user password "user"
+    fn encrypt_pdf(pdf: &[u8]) -> Vec<u8> {
+        use lopdf::{EncryptionState, EncryptionVersion, Permissions};
+
+        let mut doc = Document::load_mem(pdf).unwrap();
+        doc.trailer.set(
+            "ID",
+            vec![
+                Object::string_literal(b"0123456789abcdef".to_vec()),
+                Object::string_literal(b"0123456789abcdef".to_vec()),
+            ],
+        );
+        let state = EncryptionState::try_from(EncryptionVersion::V2 {
+            document: &doc,
+            owner_password: "owner",
+            user_password: "user",
+            key_length: 128,
+            permissions: Permissions::all(),
+        })
+        .unwrap();
+        doc.encrypt(&state).unwrap();
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn encrypted_pdf_resamples_with_its_password() {
+        let (pdf, id) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let encrypted = encrypt_pdf(&pdf);
+
+        let options = ResampleOptions { password: Some("user".into()), ..Default::default() };
+        let (output, result) = resample_pdf_bytes(&encrypted, &options).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let doc = Document::load_mem(&output).unwrap();
+        assert!(doc.trailer.get(b"Encrypt").is_err());
+        let dict = &doc.get_object(id).unwrap().as_stream().unwrap().dict;
+        assert_eq!(dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+    }
+
+    #[test]
+    fn encrypted_pdf_without_its_password_fails_to_load() {
+        let (pdf, _) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let encrypted = encrypt_pdf(&pdf);
+
+        let wrong = ResampleOptions { password: Some("wrong".into()), ..Default::default() };
+        let cases = [
+            (ResampleOptions::default(), "PDF is encrypted; a password is required"),
+            (wrong, "PDF is encrypted and the password is incorrect"),
+        ];
+        for (options, expected) in cases {
+            match resample_pdf_bytes(&encrypted, &options) {
+                Err(ResampleError::LoadError(msg)) => assert_eq!(msg, expected),
+                other => panic!("expected a load error, got {:?}", other.map(|(_, r)| r.resampled_images)),
             }
-            skipped_images += 1;
-            continue;
         }
+    }
 
-        // Check current encoding
-        let current_filter = stream.dict.get(b"Filter").ok().and_then(|f| match f {
-            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-            Object::Array(arr) => arr.first().and_then(|f| match f {
-                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-                _ => None,
-            }),
-            _ => None,
-        });
-        let is_already_jpeg = current_filter.as_deref() == Some("DCTDecode");
 
-        // Look up display info
-        let display_info = display_info_map.get(&object_id).cloned().unwrap_or_else(|| {
-            if options.verbose {
-                log(&format!(
-                    "[Process] Image {:?} ({}x{}): No display info found, using pixel dims",
-                    object_id, width, height
-                ));
+    /// Uncompressed 8-bit soft mask
+    fn gray_smask(width: u32, height: u32, samples: Vec<u8>) -> Stream {
+        Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => "DeviceGray",
+                "BitsPerComponent" => 8,
+            },
+            samples,
+        )
+    }
+
+    #[test]
+    fn half_resolution_smask_is_scaled_into_alignment() {
+        // Transparent left half, opaque right half
+        let samples = (0..4 * 4).map(|i| if i % 4 < 2 { 0 } else { 255 }).collect();
+        let smask = gray_smask(4, 4, samples);
+        let img = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+
+        let rgba = apply_smask(&Document::new(), &img, &smask, usize::MAX).unwrap().to_rgba8();
+        assert_eq!(rgba.dimensions(), (8, 8));
+        for y in 0..8 {
+            assert!(rgba.get_pixel(0, y)[3] < 64, "row {} left edge", y);
+            assert!(rgba.get_pixel(2, y)[3] < 128, "row {} left half", y);
+            assert!(rgba.get_pixel(5, y)[3] > 128, "row {} right half", y);
+            assert!(rgba.get_pixel(7, y)[3] > 192, "row {} right edge", y);
+        }
+    }
+
+    #[test]
+    fn oversized_smask_dimensions_are_an_error() {
+        let smask = gray_smask(u32::MAX, u32::MAX, vec![255; 16]);
+        assert!(decode_smask_stream(&Document::new(), &smask, 4, 4, usize::MAX).is_err());
+    }
+
+
+    /// One-page PDF drawing `image` as /Im0 inside a form with /BBox 0 0 100 100
+    fn clipped_image_pdf(image_content: &str) -> (Vec<u8>, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(40, 40));
+        let form_id = doc.add_object(form(
+            image_content,
+            dictionary! { "Im0" => image_id },
+            [0, 0, 100, 100],
+        ));
+        add_page(&mut doc, pages_id, dictionary! { "Fm0" => form_id }, "q 1 0 0 1 10 10 cm /Fm0 Do Q");
+        (finish_document(&mut doc, pages_id), image_id)
+    }
+
+    #[test]
+    fn placement_outside_form_bbox_is_ignored() {
+        let (pdf, image_id) = clipped_image_pdf("q 50 0 0 50 150 20 cm /Im0 Do Q");
+        assert!(!display_sizes(&pdf).contains_key(&image_id));
+        assert!(compute_image_placements(&pdf).unwrap().is_empty());
+    }
+
+    #[test]
+    fn partly_clipped_placement_records_its_visible_extent() {
+        // The /BBox lets through 50x50 of the 200x200 drawing, and 100x40 of the rotated one
+        let (pdf, image_id) = clipped_image_pdf("q 200 0 0 200 50 50 cm /Im0 Do Q");
+        assert_eq!(display_sizes(&pdf)[&image_id], (50.0, 50.0));
+        let (pdf, image_id) = clipped_image_pdf("q 0 200 -200 0 200 60 cm /Im0 Do Q");
+        let (w, h) = display_sizes(&pdf)[&image_id];
+        assert!((w - 40.0).abs() < 0.01 && (h - 100.0).abs() < 0.01, "{w}x{h}");
+    }
+
+
+    #[test]
+    fn small_transparent_patch_counts_as_alpha() {
+        let mut rgba = image::RgbaImage::from_pixel(2000, 1500, image::Rgba([200, 100, 50, 255]));
+        for y in 700..703 {
+            for x in 1001..1004 {
+                rgba.put_pixel(x, y, image::Rgba([0, 0, 0, 0]));
+            }
+        }
+        assert!(has_alpha(&DynamicImage::ImageRgba8(rgba.clone())));
+
+        for y in 700..703 {
+            for x in 1001..1004 {
+                rgba.put_pixel(x, y, image::Rgba([0, 0, 0, 255]));
+            }
+        }
+        assert!(!has_alpha(&DynamicImage::ImageRgba8(rgba)));
+    }
+
+
+    #[test]
+    fn batch_reports_each_input_in_order() {
+        let (good, _) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let corrupt = b"%PDF-1.5\nnot really a PDF".to_vec();
+
+        let results = resample_pdf_batch(vec![&corrupt, &good], &ResampleOptions::default());
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Err(ResampleError::LoadError(_))));
+        let (output, result) = results[1].as_ref().unwrap();
+        assert_eq!(result.resampled_images, 1);
+        assert!(Document::load_mem(output).is_ok());
+    }
+
+
+    /// Compress data with zlib, as FlateDecode expects
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reencoded_image_dict_has_only_its_own_keys() {
+        let mut image = rgb_image(400, 400);
+        image.set_content(deflate(&image.content));
+        image.dict.set("Filter", vec![Object::Name(b"FlateDecode".to_vec())]);
+        image.dict.set(
+            "DecodeParms",
+            vec![Object::Dictionary(dictionary! { "Predictor" => 1, "Columns" => 400 })],
+        );
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let dict = image_dict(&output, id);
+        let mut keys: Vec<String> =
+            dict.iter().map(|(key, _)| String::from_utf8_lossy(key).to_string()).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["BitsPerComponent", "ColorSpace", "Filter", "Height", "Length", "Subtype", "Type", "Width"]
+        );
+        assert_eq!(dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+    }
+
+
+    /// RGB samples, 5 columns by 4 rows, with no two neighbors alike
+    fn predictor_samples() -> Vec<u8> {
+        (0..5 * 4 * 3).map(|i: u32| (i * 97 + i * i * 31) as u8).collect()
+    }
+
+    fn predictor_params(predictor: i64) -> Dictionary {
+        dictionary! { "Predictor" => predictor, "Colors" => 3, "BitsPerComponent" => 8, "Columns" => 5 }
+    }
+
+    /// Apply a PNG row filter (2 = Up, 4 = Paeth) to every row
+    fn png_predict(samples: &[u8], filter: u8) -> Vec<u8> {
+        let (row_bytes, bpp) = (15, 3);
+        let mut out = Vec::new();
+        let mut previous = vec![0u8; row_bytes];
+        for row in samples.chunks(row_bytes) {
+            out.push(filter);
+            for i in 0..row_bytes {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+                let prediction = match filter {
+                    2 => previous[i],
+                    4 => paeth(left, previous[i], up_left),
+                    _ => unreachable!(),
+                };
+                out.push(row[i].wrapping_sub(prediction));
+            }
+            previous = row.to_vec();
+        }
+        out
+    }
+
+    #[test]
+    fn png_up_and_paeth_predictors_round_trip() {
+        let samples = predictor_samples();
+        for filter in [2, 4] {
+            let predicted = png_predict(&samples, filter);
+            // The row filter byte decides, whatever PNG predictor value is given
+            let decoded = undo_predictor(predicted, Some(&predictor_params(15))).unwrap();
+            assert_eq!(decoded, samples, "PNG filter {}", filter);
+        }
+    }
+
+    #[test]
+    fn tiff_predictor_round_trips() {
+        let samples = predictor_samples();
+        let mut predicted = samples.clone();
+        for row in predicted.chunks_mut(15) {
+            for i in (3..15).rev() {
+                row[i] = row[i].wrapping_sub(row[i - 3]);
+            }
+        }
+        assert_eq!(undo_predictor(predicted, Some(&predictor_params(2))).unwrap(), samples);
+    }
+
+    #[test]
+    fn predictor_is_applied_when_decoding_flate_images() {
+        let samples = predictor_samples();
+        let mut stream = rgb_image(5, 4);
+        stream.set_content(deflate(&png_predict(&samples, 4)));
+        stream.dict.set("Filter", "FlateDecode");
+        stream.dict.set("DecodeParms", predictor_params(12));
+
+        let doc = Document::with_version("1.5");
+        let img = decode_image_stream(&doc, &stream, 5, 4, "DeviceRGB", 8, 1 << 20).unwrap();
+        assert_eq!(img.to_rgb8().into_raw(), samples);
+    }
+
+
+    #[test]
+    fn translate_then_scale_composes_in_spec_order() {
+        let translate = Matrix { a: 1.0, b: 0.0, c: 0.0, d: 1.0, e: 100.0, f: 50.0 };
+        let scale = Matrix { a: 2.0, b: 0.0, c: 0.0, d: 3.0, e: 0.0, f: 0.0 };
+        // cm pre-multiplies: CTM' = M x CTM
+        let ctm = scale.concat(&translate.concat(&Matrix::identity()));
+        assert_eq!(ctm.apply(1.0, 1.0), (102.0, 53.0));
+        assert_eq!(ctm.apply(0.0, 0.0), (100.0, 50.0));
+
+        let (pdf, image_id) =
+            single_image_pdf(rgb_image(40, 40), "q 1 0 0 1 100 50 cm 2 0 0 3 0 0 cm /Im0 Do Q");
+        let placements = compute_image_placements(&pdf).unwrap();
+        assert_eq!(placements.len(), 1);
+        let p = &placements[0];
+        assert_eq!(p.object_id, image_id);
+        assert_eq!((p.x, p.y, p.w, p.h), (100.0, 50.0, 2.0, 3.0));
+    }
+
+
+    #[test]
+    fn split_pages_yields_one_resampled_pdf_per_page() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        for size in [400, 500, 600] {
+            add_image_page(&mut doc, pages_id, rgb_image(size, size), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        }
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let outputs = split_pages(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(outputs.len(), 3);
+        for (output, size) in outputs.iter().zip([400, 500, 600]) {
+            let doc = Document::load_mem(output).unwrap();
+            assert_eq!(doc.get_pages().len(), 1);
+            let images: Vec<&Stream> = doc
+                .objects
+                .values()
+                .filter_map(|obj| obj.as_stream().ok())
+                .filter(|s| s.dict.get(b"Subtype").ok() == Some(&Object::Name(b"Image".to_vec())))
+                .collect();
+            assert_eq!(images.len(), 1, "page drawing a {}px image", size);
+            assert_eq!(images[0].dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+            // 100pt at 150 DPI
+            assert_eq!(images[0].dict.get(b"Width").unwrap(), &Object::Integer(208));
+        }
+    }
+
+
+    #[test]
+    fn referenced_dimensions_are_resolved_for_image_and_smask() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let mut image = rgb_image(400, 400);
+        let mut smask = gray_smask(200, 200, (0..200 * 200).map(|i| if i % 200 < 100 { 0 } else { 255 }).collect());
+        for (stream, size) in [(&mut image, 400), (&mut smask, 200)] {
+            for key in ["Width", "Height"] {
+                let id = doc.add_object(Object::Integer(size));
+                stream.dict.set(key, id);
             }
-            // Fall back to assuming 72 DPI (1 pixel = 1 point)
-            ImageDisplayInfo {
-                pixel_width: width,
-                pixel_height: height,
-                display_width_points: width as f32,
-                display_height_points: height as f32,
+        }
+        let smask_id = doc.add_object(smask);
+        image.dict.set("SMask", smask_id);
+        let image_id = add_image_page(&mut doc, pages_id, image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let doc = Document::load_mem(&output).unwrap();
+        let dict = &doc.get_object(image_id).unwrap().as_stream().unwrap().dict;
+        assert_eq!(dict.get(b"Width").unwrap(), &Object::Integer(208));
+        let smask_id = dict.get(b"SMask").unwrap().as_reference().unwrap();
+        let smask = doc.get_object(smask_id).unwrap().as_stream().unwrap();
+        let alpha = image::load_from_memory_with_format(&smask.content, ImageFormat::Jpeg)
+            .unwrap()
+            .to_luma8();
+        assert_eq!(alpha.dimensions(), (208, 208));
+        for y in [0, 100, 207] {
+            assert!(alpha.get_pixel(10, y)[0] < 32, "row {} left", y);
+            assert!(alpha.get_pixel(197, y)[0] > 223, "row {} right", y);
+        }
+    }
+
+
+    #[test]
+    fn mirrored_matrix_gives_positive_size_and_ordered_bounds() {
+        let flip = Matrix { a: 1.0, b: 0.0, c: 0.0, d: -1.0, e: 0.0, f: 792.0 };
+        let rect = Rect::unit().transformed(&flip);
+        assert!(rect.x0 <= rect.x1 && rect.y0 <= rect.y1);
+        assert_eq!((rect.y0, rect.y1), (791.0, 792.0));
+
+        let (pdf, image_id) = single_image_pdf(
+            rgb_image(40, 40),
+            "q 1 0 0 -1 0 792 cm 200 0 0 100 50 50 cm /Im0 Do Q",
+        );
+        assert_eq!(display_sizes(&pdf)[&image_id], (200.0, 100.0));
+        let placements = compute_image_placements(&pdf).unwrap();
+        let p = &placements[0];
+        assert_eq!((p.x, p.y, p.w, p.h), (50.0, 642.0, 200.0, 100.0));
+    }
+
+
+    /// JPEG whose left half is red and right half blue, tagged with EXIF Orientation=6
+    fn rotated_exif_jpeg(width: u16, height: u16) -> Vec<u8> {
+        let pixels = RgbImage::from_fn(width as u32, height as u32, |x, _| {
+            if x < width as u32 / 2 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
             }
         });
+        // Big-endian TIFF header and one IFD entry: Orientation (0x0112), SHORT, 6
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut jpeg = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 90);
+        encoder.add_app_segment(1, exif).unwrap();
+        encoder.encode(pixels.as_raw(), width, height, jpeg_encoder::ColorType::Rgb).unwrap();
+        jpeg
+    }
 
-        let current_dpi = display_info.max_effective_dpi();
-
-        if options.verbose {
-            log(&format!(
-                "[Process] Image {:?}: {}x{} px, {:.1}x{:.1} pt, {:.1} DPI ({})",
-                object_id,
-                width,
-                height,
-                display_info.display_width_points,
-                display_info.display_height_points,
-                current_dpi,
-                current_filter.as_deref().unwrap_or("raw")
-            ));
+    #[test]
+    fn exif_orientation_is_ignored_when_reencoding() {
+        let jpeg = rotated_exif_jpeg(400, 200);
+        {
+            use image::ImageDecoder;
+            let mut decoder =
+                image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(&jpeg)).unwrap();
+            assert_eq!(decoder.orientation().unwrap(), image::metadata::Orientation::Rotate90);
         }
+        let image = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 400,
+                "Height" => 200,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+                "Filter" => "DCTDecode",
+            },
+            jpeg,
+        );
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 50 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let doc = Document::load_mem(&output).unwrap();
+        let stream = doc.get_object(id).unwrap().as_stream().unwrap();
+        assert_eq!(stream.dict.get(b"Width").unwrap(), &Object::Integer(208));
+        assert_eq!(stream.dict.get(b"Height").unwrap(), &Object::Integer(104));
+
+        let rgb = image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(rgb.dimensions(), (208, 104));
+        for y in [0, 52, 103] {
+            let (left, right) = (rgb.get_pixel(20, y), rgb.get_pixel(187, y));
+            assert!(left[0] > 200 && left[2] < 60, "row {} left {:?}", y, left);
+            assert!(right[2] > 200 && right[0] < 60, "row {} right {:?}", y, right);
+        }
+    }
 
-        // Check if resampling is needed
-        let needs_resampling = current_dpi > options.target_dpi + 1.0 && current_dpi > options.min_dpi;
 
-        // Calculate target dimensions
-        let (target_width, target_height) = if needs_resampling {
-            display_info.target_pixels_for_dpi(options.target_dpi)
-        } else {
-            (width, height)
+    /// Point `startxref` at a bogus offset so the xref table cannot be read
+    fn break_xref(pdf: &[u8]) -> Vec<u8> {
+        let pos = pdf.windows(9).rposition(|w| w == b"startxref").unwrap() + 9;
+        let mut broken = pdf[..pos].to_vec();
+        broken.extend_from_slice(b"\n999999\n%%EOF\n");
+        broken
+    }
+
+    #[test]
+    fn broken_xref_is_rebuilt_and_resampled() {
+        let (pdf, id) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let broken = break_xref(&pdf);
+        assert!(Document::load_mem(&broken).is_err());
+
+        let (output, result) = resample_pdf_bytes(&broken, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let dict = image_dict(&output, id);
+        assert_eq!(dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+    }
+
+    #[test]
+    fn header_like_bytes_in_a_stream_do_not_move_an_object() {
+        let content = |id: ObjectId| {
+            format!("q 100 0 0 100 0 0 cm /Im0 Do Q\n% {} {} obj << /Type /Bogus >> endobj\n", id.0, id.1)
         };
+        // The image is written before the content stream that mentions it
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        add_page(&mut doc, pages_id, dictionary! { "Im0" => image_id }, &content(image_id));
+        let pdf = finish_document(&mut doc, pages_id);
+        let broken = break_xref(&pdf);
+
+        let rebuilt = Document::load_mem(&rebuild_xref(&broken).unwrap()).unwrap();
+        let image = rebuilt.get_object(image_id).unwrap().as_stream().unwrap();
+        assert_eq!(image.dict.get(b"Subtype").unwrap(), &Object::Name(b"Image".to_vec()));
+
+        let (_, result) = resample_pdf_bytes(&broken, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+    }
 
-        // Skip if already JPEG and no resampling needed
-        if !needs_resampling && is_already_jpeg {
-            if options.verbose {
-                log("  Skipping: Already JPEG at target DPI");
-            }
-            skipped_images += 1;
-            continue;
-        }
 
-        // Skip if resampling would make image larger
-        if needs_resampling && target_width >= width && target_height >= height {
-            if options.verbose {
-                log("  Skipping: Target dimensions not smaller");
-            }
-            skipped_images += 1;
-            continue;
-        }
+    #[test]
+    fn opi_dictionary_survives_reencoding() {
+        let opi = dictionary! {
+            "2.0" => dictionary! { "Type" => "OPI", "Version" => 2, "F" => Object::string_literal("hires.tif") },
+        };
+        let mut image = rgb_image(400, 400);
+        image.dict.set("OPI", opi.clone());
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let dict = image_dict(&output, id);
+        assert_eq!(dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+        assert_eq!(dict.get(b"OPI").unwrap(), &Object::Dictionary(opi));
+    }
 
-        // Get color space and bits per component
-        let color_space = stream
-            .dict
-            .get(b"ColorSpace")
-            .ok()
-            .map(|cs| get_color_space_name(cs, doc))
-            .unwrap_or_else(|| "DeviceRGB".to_string());
 
-        let bits_per_component = stream
-            .dict
-            .get(b"BitsPerComponent")
-            .ok()
-            .and_then(|b| match b {
-                Object::Integer(n) => Some(*n as u32),
-                _ => None,
-            })
-            .unwrap_or(8);
+    /// DCTDecode image XObject of the same gradient as [`rgb_image`]
+    fn jpeg_image(width: u32, height: u32, quality: u8) -> Stream {
+        let mut image = rgb_image(width, height);
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, quality)
+            .encode(&image.content, width as u16, height as u16, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        image.dict.set("Filter", "DCTDecode");
+        image.set_content(jpeg);
+        image
+    }
 
-        // Check for SMask
-        let smask_id = stream.dict.get(b"SMask").ok().and_then(|s| match s {
-            Object::Reference(id) => Some(*id),
-            _ => None,
-        });
+    #[test]
+    fn high_quality_jpeg_at_target_dpi_is_recompressed_only_when_asked() {
+        // 208 px over 100 pt is the 150 DPI target
+        let (pdf, id) = single_image_pdf(jpeg_image(208, 208, 95), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let original_len = image_stream(&pdf, id).content.len();
+
+        let (_, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.skip_reasons.get(&SkipReason::AlreadyAtTargetDpi), Some(&1));
+
+        let options = ResampleOptions { recompress_jpeg_below_quality: Some(80), ..Default::default() };
+        let (output, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.skipped_images, 0);
+        let stream = image_stream(&output, id);
+        assert_eq!(stream.dict.get(b"Width").unwrap(), &Object::Integer(208));
+        assert!(stream.content.len() < original_len);
+        assert!(estimate_jpeg_quality(&stream.content).unwrap() <= 80);
+
+        // Not above the threshold: left alone
+        let options = ResampleOptions { recompress_jpeg_below_quality: Some(98), ..Default::default() };
+        let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.skip_reasons.get(&SkipReason::AlreadyAtTargetDpi), Some(&1));
+    }
 
-        // Decode the image
-        let mut img =
-            match decode_image_stream(&stream, width, height, &color_space, bits_per_component) {
-                Ok(img) => img,
-                Err(e) => {
-                    if options.verbose {
-                        log(&format!("  Skipping: Could not decode: {}", e));
-                    }
-                    skipped_images += 1;
-                    continue;
-                }
-            };
 
-        // Handle SMask
-        if let Some(smask_obj_id) = smask_id {
-            if let Ok(Object::Stream(smask_stream)) = doc.get_object(smask_obj_id) {
-                match decode_smask_stream(smask_stream, width, height) {
-                    Ok(alpha_data) => {
-                        let rgb = img.to_rgb8();
-                        let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
-
-                        for (pixel, alpha) in rgb.pixels().zip(alpha_data.iter()) {
-                            rgba_data.push(pixel[0]);
-                            rgba_data.push(pixel[1]);
-                            rgba_data.push(pixel[2]);
-                            rgba_data.push(*alpha);
-                        }
+    #[test]
+    fn jbig2_image_is_listed_as_unsupported_but_corrupt_flate_is_not() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let mut jbig2 = rgb_image(100, 100);
+        jbig2.dict.set("ColorSpace", "DeviceGray");
+        jbig2.dict.set("BitsPerComponent", 1);
+        jbig2.dict.set("Filter", "JBIG2Decode");
+        jbig2.set_content(vec![0x97, 0x4a, 0x42, 0x32]);
+        let jbig2_id = add_image_page(&mut doc, pages_id, jbig2, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        // Supported by its dictionary, only the data is bad
+        let mut corrupt = rgb_image(100, 100);
+        corrupt.dict.set("Filter", "FlateDecode");
+        corrupt.set_content(b"not zlib data".to_vec());
+        add_image_page(&mut doc, pages_id, corrupt, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let unsupported = unsupported_images(&pdf).unwrap();
+        assert_eq!(unsupported.len(), 1);
+        assert_eq!(unsupported[0].object_id, jbig2_id);
+        assert_eq!(unsupported[0].filter, "JBIG2Decode");
+    }
 
-                        if let Some(rgba_img) = image::RgbaImage::from_raw(width, height, rgba_data)
-                        {
-                            img = DynamicImage::ImageRgba8(rgba_img);
-                            if options.verbose {
-                                log("    Decoded SMask alpha channel");
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        if options.verbose {
-                            log(&format!("    Warning: Could not decode SMask: {}", e));
-                        }
-                    }
-                }
-            }
-        }
 
-        // Resample if needed
-        let resampled = if needs_resampling {
-            if options.verbose {
-                log(&format!(
-                    "  Resampling from {}x{} to {}x{}",
-                    width, height, target_width, target_height
-                ));
-            }
-            resample_image(&img, target_width, target_height)
-        } else {
-            if options.verbose {
-                log("  Re-encoding as JPEG (no resize needed)");
-            }
-            img
+    #[test]
+    fn failed_images_are_reported_with_their_id_and_error() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        // Wider than a JPEG can be, so encoding fails; not drawn, so its DPI is assumed on target
+        let too_wide = add_image_page(&mut doc, pages_id, rgb_image(70_000, 1), "");
+        let mut corrupt = rgb_image(100, 100);
+        corrupt.dict.set("Filter", "FlateDecode");
+        corrupt.set_content(b"not zlib data".to_vec());
+        let corrupt = add_image_page(&mut doc, pages_id, corrupt, "q 10 0 0 10 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let options = ResampleOptions {
+            recompress_even_if_not_smaller: true,
+            collect_per_image: true,
+            verbose: true,
+            ..Default::default()
         };
+        let messages = std::cell::RefCell::new(Vec::new());
+        let (_, result) = resample_pdf_bytes_with_logger(&pdf, &options, None, |msg| {
+            messages.borrow_mut().push(msg.to_string())
+        })
+        .unwrap();
+        assert_eq!(result.failed_images, 1);
+        let record = |id| result.per_image.iter().find(|r| r.object_id == id).unwrap();
+
+        let failed = record(too_wide);
+        assert_eq!(failed.decision, "failed");
+        assert!(failed.error.as_deref().unwrap().contains("too large for JPEG"));
+        let logged = format!("Failed {:?}: could not encode", too_wide);
+        assert!(messages.borrow().iter().any(|msg| msg.contains(&logged)));
+
+        let skipped = record(corrupt);
+        assert_eq!(skipped.reason, Some("decode_failed"));
+        assert!(skipped.error.is_some());
+    }
 
-        // Encode
-        let img_has_alpha = has_alpha(&resampled);
 
-        if img_has_alpha {
-            let (mut new_stream, smask_stream, _, _) = encode_with_alpha_stream(&resampled, options.quality)?;
+    #[test]
+    fn array_operand_before_cm_does_not_shift_its_operands() {
+        // The dash array and phase are consumed by `d`; `cm` still gets exactly its six numbers
+        let (pdf, image_id) = single_image_pdf(
+            rgb_image(40, 40),
+            "q [3 5] 0 d 200 0 0 100 10 20 cm /Im0 Do Q",
+        );
+        let placements = compute_image_placements(&pdf).unwrap();
+        assert_eq!(placements.len(), 1);
+        let p = &placements[0];
+        assert_eq!(p.object_id, image_id);
+        assert_eq!((p.x, p.y, p.w, p.h), (10.0, 20.0, 200.0, 100.0));
+
+        // An operator with array operands right before `cm`, with no operator between
+        let (pdf, image_id) =
+            single_image_pdf(rgb_image(40, 40), "q [1 2 3] 200 0 0 100 10 20 cm /Im0 Do Q");
+        assert_eq!(display_sizes(&pdf)[&image_id], (200.0, 100.0));
+    }
 
-            if let Some(smask) = smask_stream {
-                let smask_id = doc.add_object(Object::Stream(smask));
-                new_stream.dict.set("SMask", Object::Reference(smask_id));
 
-                if options.verbose {
-                    log(&format!("      Preserved alpha channel with SMask {:?}", smask_id));
-                }
-            }
+    #[test]
+    fn user_unit_halves_the_effective_dpi() {
+        let content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        let (plain, _) = single_image_pdf(rgb_image(400, 400), content);
 
-            doc.objects.insert(object_id, Object::Stream(new_stream));
-        } else {
-            if options.verbose && smask_id.is_some() {
-                log("      Converting opaque image to JPEG");
-            }
-            let (new_stream, _, _) = encode_as_jpeg_stream(&resampled, options.quality)?;
-            doc.objects.insert(object_id, Object::Stream(new_stream));
-        }
+        let mut doc = Document::with_version("1.6");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let page_id = add_page(&mut doc, pages_id, dictionary! { "Im0" => image_id }, content);
+        doc.get_dictionary_mut(page_id).unwrap().set("UserUnit", 2);
+        let scaled = finish_document(&mut doc, pages_id);
 
-        resampled_images += 1;
+        let dpi = |pdf: &[u8]| {
+            let info = compute_display_info(pdf).unwrap();
+            let info = info.values().next().unwrap().clone();
+            (info.effective_dpi_x(), info.effective_dpi_y())
+        };
+        assert_eq!(dpi(&plain), (288.0, 288.0));
+        assert_eq!(dpi(&scaled), (144.0, 144.0));
     }
 
-    Ok(ResampleResult {
-        total_images,
-        resampled_images,
-        skipped_images,
-    })
-}
 
-/// Resample PDF from bytes and return resampled PDF bytes
-pub fn resample_pdf_bytes(
-    input_bytes: &[u8],
-    options: &ResampleOptions,
-) -> Result<(Vec<u8>, ResampleResult), ResampleError> {
-    if options.quality == 0 || options.quality > 100 {
-        return Err(ResampleError::InvalidQuality);
+    #[test]
+    fn identical_images_are_reencoded_once_and_shared() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        add_image_page(&mut doc, pages_id, rgb_image(400, 400), content);
+        add_image_page(&mut doc, pages_id, rgb_image(400, 400), content);
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+
+        let doc = Document::load_mem(&output).unwrap();
+        let images: Vec<ObjectId> = doc
+            .objects
+            .iter()
+            .filter(|(_, obj)| {
+                obj.as_stream().is_ok_and(|s| s.dict.get(b"Subtype").ok() == Some(&Object::Name(b"Image".to_vec())))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(images.len(), 1);
+        let stream = doc.get_object(images[0]).unwrap().as_stream().unwrap();
+        assert_eq!(stream.dict.get(b"Filter").unwrap(), &Object::Name(b"DCTDecode".to_vec()));
+
+        for page_id in doc.get_pages().into_values() {
+            let (resources, _) = doc.get_page_resources(page_id).unwrap();
+            let xobjects = resources.unwrap().get(b"XObject").unwrap().as_dict().unwrap();
+            assert_eq!(xobjects.get(b"Im0").unwrap(), &Object::Reference(images[0]));
+        }
     }
 
-    // Step 1: Scan all content streams to find image display dimensions
-    let display_info_map = {
-        let doc = Document::load_mem(input_bytes)
-            .map_err(|e| ResampleError::LoadError(e.to_string()))?;
-        let mut scanner = ContentScanner::new(&doc, options.verbose);
-        scanner.scan_all_pages();
-        scanner.get_display_info_map()
-    }; // doc is dropped here
 
-    // Step 2: Reload and process images
-    let mut doc = Document::load_mem(input_bytes)
-        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    #[test]
+    fn separation_image_resamples_through_its_tint_transform() {
+        let mut image = rgb_image(400, 400);
+        // Full tint of a red spot ink everywhere
+        image.set_content(vec![255; 400 * 400]);
+        image.dict.set(
+            "ColorSpace",
+            vec![
+                "Separation".into(),
+                "Spot".into(),
+                "DeviceCMYK".into(),
+                Object::Dictionary(dictionary! {
+                    "FunctionType" => 2,
+                    "Domain" => vec![0.into(), 1.into()],
+                    "C0" => vec![0.into(), 0.into(), 0.into(), 0.into()],
+                    "C1" => vec![0.into(), 1.into(), 1.into(), 0.into()],
+                    "N" => 1,
+                }),
+            ],
+        );
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let stream = image_stream(&output, id);
+        let decoded = image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        let [r, g, b] = decoded.get_pixel(100, 100).0;
+        assert!(r > 240 && g < 15 && b < 15, "{:?}", (r, g, b));
+    }
 
-    let log_fn = |_msg: &str| {
-        #[cfg(not(target_arch = "wasm32"))]
-        if options.verbose {
-            println!("{}", _msg);
-        }
-    };
+    #[test]
+    fn devicen_calculator_function_sees_every_colorant() {
+        let mut doc = Document::with_version("1.5");
+        let function = doc.add_object(Stream::new(
+            dictionary! {
+                "FunctionType" => 4,
+                "Domain" => vec![0.into(), 1.into(), 0.into(), 1.into()],
+                "Range" => vec![0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into()],
+            },
+            // C = first ink, M = second ink, no yellow, K = half the second ink
+            b"{ dup 0.5 mul 0 exch }".to_vec(),
+        ));
+        let mut image = rgb_image(2, 1);
+        image.set_content(vec![255, 0, 0, 255]);
+        image.dict.set(
+            "ColorSpace",
+            vec![
+                "DeviceN".into(),
+                vec!["A".into(), "B".into()].into(),
+                "DeviceCMYK".into(),
+                function.into(),
+            ],
+        );
+
+        let decoded = decode_image_stream(&doc, &image, 2, 1, "DeviceN", 8, 1 << 20).unwrap().to_rgb8();
+        assert_eq!(decoded.get_pixel(0, 0).0, [0, 255, 255]);
+        assert_eq!(decoded.get_pixel(1, 0).0, [127, 0, 127]);
+    }
 
-    let result = process_images_in_doc(&mut doc, &display_info_map, options, log_fn)
-        .map_err(|e| ResampleError::ProcessingError(e))?;
 
-    // Compress streams if requested
-    if options.compress_streams {
-        doc.compress();
+    #[test]
+    fn image_below_min_image_bytes_is_skipped_as_too_small() {
+        // 300 bytes of samples drawn at 720 DPI, so only the size threshold keeps it
+        let (pdf, _) = single_image_pdf(rgb_image(10, 10), "q 1 0 0 1 0 0 cm /Im0 Do Q");
+        let (_, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+
+        let options = ResampleOptions { min_image_bytes: Some(1024), ..Default::default() };
+        let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.resampled_images, 0);
+        assert_eq!(result.skip_reasons.get(&SkipReason::TooSmall), Some(&1));
     }
 
-    // Save to bytes
-    let mut output_bytes = Vec::new();
-    doc.save_to(&mut output_bytes)
-        .map_err(|e| ResampleError::SaveError(e.to_string()))?;
 
-    Ok((output_bytes, result))
-}
+    /// ASCII85-encode `data`, without the `z` shorthand
+    fn ascii85(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(4) {
+            let mut group = [0u8; 4];
+            group[..chunk.len()].copy_from_slice(chunk);
+            let mut value = u32::from_be_bytes(group);
+            let mut digits = [0u8; 5];
+            for digit in digits.iter_mut().rev() {
+                *digit = (value % 85) as u8 + b'!';
+                value /= 85;
+            }
+            out.extend_from_slice(&digits[..chunk.len() + 1]);
+        }
+        out.extend_from_slice(b"~>");
+        out
+    }
 
-/// Extract detailed image information from a PDF, organized by page
-pub fn extract_pdf_images_info(pdf_bytes: &[u8]) -> Result<Vec<PageImages>, ResampleError> {
-    let doc = Document::load_mem(pdf_bytes)
-        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+    #[test]
+    fn ascii_encoded_content_streams_give_display_info() {
+        let content = b"q 100 0 0 50 0 0 cm /Im0 Do Q";
+        let hex: String = content.iter().map(|b| format!("{:02x}", b)).collect();
+        let encodings: [(Object, Vec<u8>); 2] = [
+            ("ASCIIHexDecode".into(), format!("{}>", hex).into_bytes()),
+            (vec!["ASCII85Decode".into(), "FlateDecode".into()].into(), ascii85(&deflate(content))),
+        ];
+        for (filter, data) in encodings {
+            let (pdf, image_id) = single_image_pdf(rgb_image(40, 40), "");
+            let mut doc = Document::load_mem(&pdf).unwrap();
+            let page_id = *doc.get_pages().values().next().unwrap();
+            let page = doc.get_dictionary(page_id).unwrap();
+            let content_id = page.get(b"Contents").unwrap().as_reference().unwrap();
+            let mut stream = Stream::new(dictionary! { "Filter" => filter }, data);
+            stream.allows_compression = false;
+            doc.objects.insert(content_id, Object::Stream(stream));
+            let mut pdf = Vec::new();
+            doc.save_to(&mut pdf).unwrap();
+
+            assert_eq!(display_sizes(&pdf)[&image_id], (100.0, 50.0));
+        }
+    }
 
-    // Get display info for DPI calculation
-    let mut scanner = ContentScanner::new(&doc, false);
-    scanner.scan_all_pages();
-    let display_info_map = scanner.get_display_info_map();
 
-    // Build a map of which images appear on which pages
-    let mut page_image_map: HashMap<u32, Vec<ObjectId>> = HashMap::new();
-    
-    let pages = doc.get_pages();
-    for (page_num, &page_id) in pages.iter() {
-        let page_images = collect_page_images(&doc, page_id);
-        page_image_map.insert(*page_num, page_images);
+    /// DeviceCMYK JPEG image filled with one ink mix, with or without Adobe's APP14 marker
+    ///
+    /// The encoder stores inverted samples, as Adobe does; for a plain JPEG
+    /// they are inverted beforehand so the file holds the ink values as they are.
+    fn cmyk_jpeg(width: u32, height: u32, cmyk: [u8; 4], adobe: bool) -> Stream {
+        let pixel = if adobe { cmyk } else { cmyk.map(|v| 255 - v) };
+        let samples = pixel.repeat((width * height) as usize);
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 95)
+            .encode(&samples, width as u16, height as u16, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        if !adobe {
+            let app14 =
+                jpeg.windows(7).position(|w| w[..2] == [0xFF, 0xEE] && &w[4..] == b"Ado").unwrap();
+            let len = u16::from_be_bytes([jpeg[app14 + 2], jpeg[app14 + 3]]) as usize;
+            jpeg.drain(app14..app14 + 2 + len);
+        }
+        let mut image = rgb_image(width, height);
+        image.dict.set("ColorSpace", "DeviceCMYK");
+        image.dict.set("Filter", "DCTDecode");
+        image.set_content(jpeg);
+        image
     }
 
-    // Collect all image info
-    let mut result: Vec<PageImages> = Vec::new();
+    #[test]
+    fn cmyk_jpeg_colors_survive_with_and_without_adobe_inversion() {
+        for adobe in [true, false] {
+            let image = cmyk_jpeg(64, 64, [200, 0, 0, 0], adobe);
+            assert_eq!(read_jpeg_layout(&image.content).unwrap().adobe_transform.is_some(), adobe);
+            let stored = decode_jpeg_cmyk(&image.content).unwrap().0[0];
+            assert!(stored.abs_diff(if adobe { 55 } else { 200 }) <= 2);
+            let (pdf, id) = single_image_pdf(image, "q 10 0 0 10 0 0 cm /Im0 Do Q");
+
+            let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+            assert_eq!(result.resampled_images, 1);
+            let stream = image_stream(&output, id);
+            assert_eq!(stream.dict.get(b"ColorSpace").unwrap(), &Object::Name(b"DeviceRGB".to_vec()));
+            let decoded = image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg)
+                .unwrap()
+                .to_rgb8();
+            // 200/255 cyan ink leaves 55 red
+            let [r, g, b] = decoded.get_pixel(decoded.width() / 2, decoded.height() / 2).0;
+            assert!(r.abs_diff(55) <= 8 && g >= 245 && b >= 245, "adobe {}: {:?}", adobe, (r, g, b));
+        }
+    }
 
-    for (page_num, image_ids) in page_image_map.iter() {
-        let mut images: Vec<ImageInfo> = Vec::new();
 
-        for &obj_id in image_ids {
-            if let Ok(Object::Stream(stream)) = doc.get_object(obj_id) {
-                let info = extract_image_info_from_stream(
-                    obj_id,
-                    stream,
-                    &doc,
-                    display_info_map.get(&obj_id),
-                    false,
-                );
-                images.push(info);
 
-                // Check for SMask
-                if let Ok(Object::Reference(smask_id)) = stream.dict.get(b"SMask") {
-                    if let Ok(Object::Stream(smask_stream)) = doc.get_object(*smask_id) {
-                        let smask_info = extract_image_info_from_stream(
-                            *smask_id,
-                            smask_stream,
-                            &doc,
-                            None,
-                            true,
-                        );
-                        images.push(smask_info);
-                    }
-                }
+
+    #[test]
+    fn color_space_named_in_page_resources_is_resolved() {
+        let mut image = rgb_image(400, 400);
+        // Left half palette entry 0 (red), right half entry 1 (blue)
+        image.set_content((0..400 * 400).map(|i| u8::from(i % 400 >= 200)).collect());
+        image.dict.set("ColorSpace", "CS0");
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(image);
+        let page_id =
+            add_page(&mut doc, pages_id, dictionary! { "Im0" => image_id }, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let palette = Object::String(vec![255, 0, 0, 0, 0, 255], lopdf::StringFormat::Hexadecimal);
+        let indexed: Vec<Object> = vec!["Indexed".into(), "DeviceRGB".into(), 1.into(), palette];
+        let page = doc.get_dictionary_mut(page_id).unwrap();
+        let resources = page.get_mut(b"Resources").unwrap().as_dict_mut().unwrap();
+        resources.set("ColorSpace", dictionary! { "CS0" => indexed });
+        let pdf = finish_document(&mut doc, pages_id);
+
+        assert!(unsupported_images(&pdf).unwrap().is_empty());
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let stream = image_stream(&output, image_id);
+        let decoded = image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        let y = decoded.height() / 2;
+        let [r, _, b] = decoded.get_pixel(decoded.width() / 4, y).0;
+        assert!(r > 230 && b < 25, "{:?}", (r, b));
+        let [r, _, b] = decoded.get_pixel(decoded.width() * 3 / 4, y).0;
+        assert!(r < 25 && b > 230, "{:?}", (r, b));
+    }
+
+
+    /// Start-of-frame marker of a JPEG and the sampling factor byte of each component
+    fn jpeg_frame(data: &[u8]) -> (u8, Vec<u8>) {
+        let mut pos = 2;
+        loop {
+            let marker = data[pos + 1];
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+                let components = data[pos + 9] as usize;
+                let factors = (0..components).map(|i| data[pos + 11 + i * 3]).collect();
+                return (marker, factors);
             }
+            pos += 2 + len;
         }
+    }
 
-        if !images.is_empty() {
-            result.push(PageImages {
-                page_number: *page_num,
-                images,
-            });
+    #[test]
+    fn chroma_subsampling_shows_in_the_frame_header() {
+        let (pdf, id) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let factors = |chroma_subsampling| {
+            let options = ResampleOptions { chroma_subsampling, ..Default::default() };
+            let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+            jpeg_frame(&image_stream(&output, id).content).1
+        };
+        // High nibble horizontal, low nibble vertical; chroma components are always 1x1
+        assert_eq!(factors(ChromaSubsampling::S444), vec![0x11, 0x11, 0x11]);
+        assert_eq!(factors(ChromaSubsampling::S422), vec![0x21, 0x11, 0x11]);
+        assert_eq!(factors(ChromaSubsampling::S420), vec![0x22, 0x11, 0x11]);
+    }
+
+
+    #[test]
+    fn linearized_output_keeps_pages_and_nulls_dangling_references() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        let mut last_page = None;
+        for _ in 0..3 {
+            let image_id = doc.add_object(rgb_image(400, 400));
+            last_page = Some(add_page(&mut doc, pages_id, dictionary! { "Im0" => image_id }, content));
         }
+        // A low object number that renumbering hands to some other object
+        let missing = doc.add_object(dictionary! {});
+        doc.objects.remove(&missing);
+        doc.get_dictionary_mut(last_page.unwrap()).unwrap().set("PieceInfo", missing);
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let options = ResampleOptions { linearize: true, ..Default::default() };
+        let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+        let doc = Document::load_mem(&output).unwrap();
+        assert_eq!(doc.get_pages().len(), 3);
+        let last_page = *doc.get_pages().values().last().unwrap();
+        let piece_info = doc.get_dictionary(last_page).unwrap().get(b"PieceInfo").unwrap();
+        assert_eq!(piece_info, &Object::Null);
     }
 
-    // Sort by page number
-    result.sort_by_key(|p| p.page_number);
 
-    Ok(result)
-}
+    /// One form holding an image, drawn at 50 pt on page 1 and 200 pt on page 2
+    fn form_on_two_pages_pdf() -> (Vec<u8>, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let form_id = doc.add_object(form("/Im0 Do", dictionary! { "Im0" => image_id }, [0, 0, 1, 1]));
+        let xobjects = dictionary! { "Fm0" => form_id };
+        add_page(&mut doc, pages_id, xobjects.clone(), "q 50 0 0 50 10 10 cm /Fm0 Do Q");
+        add_page(&mut doc, pages_id, xobjects, "q 200 0 0 200 0 0 cm /Fm0 Do Q");
+        (finish_document(&mut doc, pages_id), image_id)
+    }
 
-/// Extracted image data with format information
-#[derive(Debug, Clone)]
-pub struct ExtractedImage {
-    /// Image data bytes
-    pub data: Vec<u8>,
-    /// Format: "jpeg" or "png"
-    pub format: String,
-    /// MIME type
-    pub mime_type: String,
-}
+    #[test]
+    fn form_drawn_at_two_scales_reports_both_usages() {
+        let (pdf, image_id) = form_on_two_pages_pdf();
 
-/// Extract a single image from a PDF in its native format when possible
-/// Returns JPEG for DCTDecode images, PNG for others
-/// object_id format: "num gen" e.g. "12 0"
-pub fn extract_image_native(pdf_bytes: &[u8], object_id_str: &str) -> Result<ExtractedImage, ResampleError> {
-    let doc = Document::load_mem(pdf_bytes)
-        .map_err(|e| ResampleError::LoadError(e.to_string()))?;
+        let usages = compute_image_usages(&pdf).unwrap();
+        assert_eq!(usages.len(), 1);
+        let mut sizes = usages[0].placements.clone();
+        sizes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(sizes, vec![(50.0, 50.0), (200.0, 200.0)]);
 
-    // Parse object ID
-    let parts: Vec<&str> = object_id_str.split_whitespace().collect();
-    if parts.len() != 2 {
-        return Err(ResampleError::ProcessingError("Invalid object ID format".to_string()));
+        assert_eq!(display_sizes(&pdf)[&image_id], (200.0, 200.0));
     }
-    
-    let obj_num: u32 = parts[0].parse()
-        .map_err(|_| ResampleError::ProcessingError("Invalid object number".to_string()))?;
-    let gen_num: u16 = parts[1].parse()
-        .map_err(|_| ResampleError::ProcessingError("Invalid generation number".to_string()))?;
-    
-    let obj_id: ObjectId = (obj_num, gen_num);
-
-    // Get the stream
-    let stream = match doc.get_object(obj_id) {
-        Ok(Object::Stream(s)) => s,
-        _ => return Err(ResampleError::ProcessingError("Object is not an image stream".to_string())),
-    };
 
-    // Check filter type
-    let filter = stream
-        .dict
-        .get(b"Filter")
-        .ok()
-        .and_then(|f| match f {
-            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-            Object::Array(arr) => arr.first().and_then(|f| match f {
-                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-                _ => None,
-            }),
-            _ => None,
-        });
+    #[test]
+    fn form_invoking_itself_is_not_reentered() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(40, 40));
+        let form_id = doc.new_object_id();
+        let xobjects = dictionary! { "Im0" => image_id, "Fm0" => form_id };
+        let content = "/Im0 Do 0.5 0 0 0.5 0 0 cm /Fm0 Do";
+        doc.objects.insert(form_id, Object::Stream(form(content, xobjects, [0, 0, 1, 1])));
+        let page_xobjects = dictionary! { "Fm0" => form_id };
+        add_page(&mut doc, pages_id, page_xobjects, "q 100 0 0 100 0 0 cm /Fm0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let usages = compute_image_usages(&pdf).unwrap();
+        assert_eq!(usages[0].placements, vec![(100.0, 100.0)]);
+    }
 
-    // Check for SMask (alpha channel)
-    let has_smask = stream.dict.get(b"SMask").is_ok();
 
-    // If it's a JPEG without SMask, return the raw JPEG data
-    if filter.as_deref() == Some("DCTDecode") && !has_smask {
-        return Ok(ExtractedImage {
-            data: stream.content.clone(),
-            format: "jpeg".to_string(),
-            mime_type: "image/jpeg".to_string(),
-        });
+    #[test]
+    fn image_used_large_and_small_is_split_into_two_sizes() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(800, 800));
+        let xobjects = dictionary! { "Im0" => image_id };
+        // 4 inches and 2/3 of an inch: 600 and 100 px at 150 DPI
+        add_page(&mut doc, pages_id, xobjects.clone(), "q 288 0 0 288 0 0 cm /Im0 Do Q");
+        add_page(&mut doc, pages_id, xobjects, "q 48 0 0 48 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let page_image_widths = |options: &ResampleOptions| {
+            let (output, _) = resample_pdf_bytes(&pdf, options).unwrap();
+            let doc = Document::load_mem(&output).unwrap();
+            let mut widths = Vec::new();
+            for page_id in doc.get_pages().into_values() {
+                let (resources, _) = doc.get_page_resources(page_id).unwrap();
+                let xobjects = resources.unwrap().get(b"XObject").unwrap().as_dict().unwrap();
+                let id = xobjects.get(b"Im0").unwrap().as_reference().unwrap();
+                let image = doc.get_object(id).unwrap().as_stream().unwrap();
+                widths.push((id, image.dict.get(b"Width").unwrap().as_i64().unwrap()));
+            }
+            widths
+        };
+
+        let shared = page_image_widths(&ResampleOptions::default());
+        assert_eq!(shared[0], shared[1]);
+        assert_eq!(shared[0].1, 600);
+
+        let options = ResampleOptions { split_multiuse_images: true, ..Default::default() };
+        let split = page_image_widths(&options);
+        assert_ne!(split[0].0, split[1].0);
+        assert_eq!((split[0].1, split[1].1), (600, 100));
     }
 
-    // Otherwise, decode and convert to PNG
-    let width = stream
-        .dict
-        .get(b"Width")
-        .ok()
-        .and_then(|w| match w {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(0);
 
-    let height = stream
-        .dict
-        .get(b"Height")
-        .ok()
-        .and_then(|h| match h {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(0);
+    #[test]
+    fn title_is_kept_by_default_and_stripped_without_preserve_metadata() {
+        let (pdf, _) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let mut doc = Document::load_mem(&pdf).unwrap();
+        let info_id = doc.add_object(dictionary! {
+            "Title" => Object::string_literal("Quarterly report"),
+        });
+        doc.trailer.set("Info", info_id);
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        let title = |options: &ResampleOptions| {
+            let (output, _) = resample_pdf_bytes(&pdf, options).unwrap();
+            let doc = Document::load_mem(&output).unwrap();
+            let info = doc.trailer.get(b"Info").ok()?.as_reference().ok()?;
+            let title = doc.get_dictionary(info).ok()?.get(b"Title").ok()?;
+            Some(title.as_str().unwrap().to_vec())
+        };
+        assert_eq!(title(&ResampleOptions::default()), Some(b"Quarterly report".to_vec()));
+        let options = ResampleOptions { preserve_metadata: false, ..Default::default() };
+        assert_eq!(title(&options), None);
+    }
 
-    if width == 0 || height == 0 {
-        return Err(ResampleError::ProcessingError("Invalid image dimensions".to_string()));
+
+    #[test]
+    fn matte_preblending_is_removed_from_colors() {
+        let doc = Document::with_version("1.5");
+        // (200, 100, 0) at half opacity pre-blended over a white matte, then an opaque pixel
+        let pixels = vec![227, 177, 127, 10, 20, 30];
+        let img = DynamicImage::ImageRgb8(RgbImage::from_raw(2, 1, pixels).unwrap());
+        let mut smask = gray_smask(2, 1, vec![128, 255]);
+        smask.dict.set("Matte", vec![1.into(), 1.into(), 1.into()]);
+
+        let rgba = apply_smask(&doc, &img, &smask, 1 << 20).unwrap().to_rgba8();
+        let [r, g, b, a] = rgba.get_pixel(0, 0).0;
+        assert_eq!(a, 128);
+        assert!(r.abs_diff(200) <= 2 && g.abs_diff(100) <= 2 && b <= 2, "{:?}", (r, g, b));
+        assert_eq!(rgba.get_pixel(1, 0).0, [10, 20, 30, 255]);
+
+        // Without /Matte the colors are already straight
+        smask.dict.remove(b"Matte");
+        let rgba = apply_smask(&doc, &img, &smask, 1 << 20).unwrap().to_rgba8();
+        assert_eq!(rgba.get_pixel(0, 0).0, [227, 177, 127, 128]);
     }
 
-    let color_space = stream
-        .dict
-        .get(b"ColorSpace")
-        .ok()
-        .map(|cs| get_color_space_name(cs, &doc))
-        .unwrap_or_else(|| "DeviceRGB".to_string());
 
-    let bits_per_component = stream
-        .dict
-        .get(b"BitsPerComponent")
-        .ok()
-        .and_then(|b| match b {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(8);
+    #[test]
+    fn cancel_after_the_first_image_stops_resampling() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        add_image_page(&mut doc, pages_id, rgb_image(400, 400), content);
+        add_image_page(&mut doc, pages_id, rgb_image(300, 300), content);
+        let pdf = finish_document(&mut doc, pages_id);
+
+        // Checked once before each image: let the first through, then cancel
+        let checks = std::cell::Cell::new(0);
+        let cancel = || {
+            checks.set(checks.get() + 1);
+            checks.get() > 1
+        };
+        let result = resample_pdf_bytes_with_cancel(&pdf, &ResampleOptions::default(), Some(&cancel));
+        assert!(matches!(result, Err(ResampleError::Cancelled)));
+        assert_eq!(checks.get(), 2);
 
-    // Decode the image
-    let img = decode_image_stream(stream, width, height, &color_space, bits_per_component)
-        .map_err(|e| ResampleError::ProcessingError(e))?;
+        let never = || false;
+        let result = resample_pdf_bytes_with_cancel(&pdf, &ResampleOptions::default(), Some(&never));
+        assert_eq!(result.unwrap().1.resampled_images, 2);
+    }
 
-    // Check for SMask and apply alpha
-    let final_img = if let Ok(Object::Reference(smask_id)) = stream.dict.get(b"SMask") {
-        if let Ok(Object::Stream(smask_stream)) = doc.get_object(*smask_id) {
-            match decode_smask_stream(smask_stream, width, height) {
-                Ok(alpha_data) => {
-                    let rgb = img.to_rgb8();
-                    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
-                    for (pixel, alpha) in rgb.pixels().zip(alpha_data.iter()) {
-                        rgba_data.push(pixel[0]);
-                        rgba_data.push(pixel[1]);
-                        rgba_data.push(pixel[2]);
-                        rgba_data.push(*alpha);
-                    }
-                    if let Some(rgba_img) = image::RgbaImage::from_raw(width, height, rgba_data) {
-                        DynamicImage::ImageRgba8(rgba_img)
-                    } else {
-                        img
-                    }
-                }
-                Err(_) => img,
-            }
-        } else {
-            img
-        }
-    } else {
-        img
-    };
 
-    // Encode as PNG
-    let mut png_bytes = Vec::new();
-    final_img.write_to(&mut std::io::Cursor::new(&mut png_bytes), image::ImageFormat::Png)
-        .map_err(|e| ResampleError::ProcessingError(format!("Failed to encode PNG: {}", e)))?;
+    #[test]
+    fn progressive_jpeg_writes_a_progressive_frame() {
+        let mut gray = rgb_image(400, 400);
+        gray.dict.set("ColorSpace", "DeviceGray");
+        gray.set_content(vec![90; 400 * 400]);
+        for image in [rgb_image(400, 400), gray] {
+            let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+            let marker = |progressive_jpeg| {
+                let options = ResampleOptions { progressive_jpeg, ..Default::default() };
+                let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+                jpeg_frame(&image_stream(&output, id).content).0
+            };
+            assert_eq!(marker(false), 0xC0);
+            assert_eq!(marker(true), 0xC2);
+        }
+    }
 
-    Ok(ExtractedImage {
-        data: png_bytes,
-        format: "png".to_string(),
-        mime_type: "image/png".to_string(),
-    })
-}
 
-/// Collect all image object IDs referenced from a page
-fn collect_page_images(doc: &Document, page_id: ObjectId) -> Vec<ObjectId> {
-    let mut images: Vec<ObjectId> = Vec::new();
-    let mut seen: HashSet<ObjectId> = HashSet::new();
+    #[test]
+    fn embed_srgb_shares_one_profile_between_images() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        let first = add_image_page(&mut doc, pages_id, rgb_image(400, 400), content);
+        let second = add_image_page(&mut doc, pages_id, rgb_image(300, 300), content);
+        let pdf = finish_document(&mut doc, pages_id);
 
-    let page_dict = match doc.get_object(page_id) {
-        Ok(Object::Dictionary(d)) => d.clone(),
-        _ => return images,
-    };
+        let options = ResampleOptions { embed_srgb: true, ..Default::default() };
+        let (output, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.resampled_images, 2);
 
-    // Get resources
-    let resources = get_page_resources_static(doc, &page_dict, page_id);
+        let profile = |id| match image_dict(&output, id).get(b"ColorSpace").unwrap() {
+            Object::Array(cs) if cs[0] == Object::Name(b"ICCBased".to_vec()) => {
+                cs[1].as_reference().unwrap()
+            }
+            other => panic!("not ICCBased: {:?}", other),
+        };
+        assert_eq!(profile(first), profile(second));
+
+        let doc = Document::load_mem(&output).unwrap();
+        let profiles = doc
+            .objects
+            .values()
+            .filter(|obj| obj.as_stream().is_ok_and(|s| s.dict.get(b"N").is_ok()))
+            .count();
+        assert_eq!(profiles, 1);
+        let profile = doc.get_object(profile(first)).unwrap().as_stream().unwrap();
+        assert_eq!(profile.dict.get(b"N").unwrap(), &Object::Integer(3));
+    }
 
-    // Get XObjects from resources
-    let xobjects = get_xobjects_static(doc, &resources);
 
-    // Check each XObject
-    for (_, &obj_id) in xobjects.iter() {
-        collect_images_recursive(doc, obj_id, &mut images, &mut seen);
+    #[test]
+    fn inverted_decode_gray_image_extracts_with_its_polarity() {
+        // Samples 0 and 255 under /Decode [1 0] are white and black
+        let mut image = gray_smask(2, 1, vec![0, 255]);
+        image.dict.set("Decode", vec![1.into(), 0.into()]);
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let extracted = extract_image_native(&pdf, &format!("{} {}", id.0, id.1)).unwrap();
+        assert_eq!(extracted.format, "png");
+        let pixels = image::load_from_memory(&extracted.data).unwrap().to_luma8();
+        assert_eq!(pixels.into_raw(), vec![255, 0]);
+
+        // A JPEG cannot carry the inversion, so it is decoded rather than copied
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 100)
+            .encode(&[0; 64], 8, 8, jpeg_encoder::ColorType::Luma)
+            .unwrap();
+        let mut image = gray_smask(8, 8, jpeg);
+        image.dict.set("Filter", "DCTDecode");
+        image.dict.set("Decode", vec![1.into(), 0.into()]);
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let extracted = extract_image_native(&pdf, &format!("{} {}", id.0, id.1)).unwrap();
+        assert_eq!(extracted.format, "png");
+        let pixels = image::load_from_memory(&extracted.data).unwrap().to_luma8();
+        assert!(pixels.pixels().all(|p| p.0[0] > 250));
     }
 
-    images
-}
 
-/// Recursively collect images from an object (handles Form XObjects)
-fn collect_images_recursive(
-    doc: &Document,
-    obj_id: ObjectId,
-    images: &mut Vec<ObjectId>,
-    seen: &mut HashSet<ObjectId>,
-) {
-    if seen.contains(&obj_id) {
-        return;
+    #[test]
+    fn images_in_pages_root_resources_are_found_two_levels_up() {
+        // Root -> intermediate Pages node -> page; only the root has /Resources
+        let mut doc = Document::with_version("1.5");
+        let root_id = doc.new_object_id();
+        let middle_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let content_id =
+            doc.add_object(Stream::new(Dictionary::new(), b"q 100 0 0 100 0 0 cm /Im0 Do Q".to_vec()));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => middle_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            "Contents" => content_id,
+        });
+        doc.objects.insert(
+            middle_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Parent" => root_id,
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        doc.objects.insert(
+            root_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![middle_id.into()],
+                "Count" => 1,
+                "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } },
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => root_id });
+        doc.trailer.set("Root", catalog_id);
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        assert_eq!(display_sizes(&pdf)[&image_id], (100.0, 100.0));
+        let pages = extract_pdf_images_info(&pdf).unwrap();
+        let ids: Vec<_> = pages[0].images.iter().map(|info| info.object_id).collect();
+        assert_eq!(ids, vec![image_id]);
     }
-    seen.insert(obj_id);
 
-    let stream = match doc.get_object(obj_id) {
-        Ok(Object::Stream(s)) => s,
-        _ => return,
-    };
 
-    let subtype = stream.dict.get(b"Subtype").ok().and_then(|s| match s {
-        Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-        _ => None,
-    });
+    #[test]
+    fn image_in_an_indirect_xobject_dictionary_is_found() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let xobjects_id = doc.add_object(dictionary! { "Im0" => image_id });
+        let resources_id = doc.add_object(dictionary! { "XObject" => xobjects_id });
+        let content_id =
+            doc.add_object(Stream::new(Dictionary::new(), b"q 100 0 0 100 0 0 cm /Im0 Do Q".to_vec()));
+        doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            "Resources" => resources_id,
+            "Contents" => content_id,
+        });
+        let pdf = finish_document(&mut doc, pages_id);
 
-    match subtype.as_deref() {
-        Some("Image") => {
-            images.push(obj_id);
-        }
-        Some("Form") => {
-            // Get resources from Form XObject and recurse
-            if let Ok(res) = stream.dict.get(b"Resources") {
-                let xobjects = get_xobjects_static(doc, res);
-                for (_, &child_id) in xobjects.iter() {
-                    collect_images_recursive(doc, child_id, images, seen);
-                }
-            }
+        assert_eq!(display_sizes(&pdf)[&image_id], (100.0, 100.0));
+        let pages = extract_pdf_images_info(&pdf).unwrap();
+        let ids: Vec<_> = pages[0].images.iter().map(|info| info.object_id).collect();
+        assert_eq!(ids, vec![image_id]);
+    }
+
+
+    /// One-page PDF drawing a 400x400 image at the end of a chain of `depth` nested forms
+    fn form_chain_pdf(depth: usize) -> (Vec<u8>, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let mut xobjects = dictionary! { "Im0" => image_id };
+        let mut content = "q 100 0 0 100 0 0 cm /Im0 Do Q";
+        for _ in 0..depth {
+            let form_id = doc.add_object(form(content, xobjects, [0, 0, 1000, 1000]));
+            xobjects = dictionary! { "Fm0" => form_id };
+            content = "/Fm0 Do";
         }
-        _ => {}
+        add_page(&mut doc, pages_id, xobjects, content);
+        (finish_document(&mut doc, pages_id), image_id)
     }
-}
 
-/// Get page resources (static version)
-fn get_page_resources_static(doc: &Document, page_dict: &Dictionary, page_id: ObjectId) -> Object {
-    if let Ok(resources) = page_dict.get(b"Resources") {
-        return resources.clone();
+    #[test]
+    fn deep_form_chain_stops_at_max_form_depth() {
+        let (pdf, image_id) = form_chain_pdf(5);
+        assert_eq!(display_sizes(&pdf)[&image_id], (100.0, 100.0));
+
+        let options = ResampleOptions {
+            on_unknown_dpi: UnknownDpiPolicy::Skip,
+            max_form_depth: 4,
+            ..Default::default()
+        };
+        let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.skip_reasons.get(&SkipReason::NoDisplayInfo), Some(&1));
+
+        // Far past the default limit, scanning ends without overflowing the stack
+        let (pdf, image_id) = form_chain_pdf(1000);
+        assert!(!display_sizes(&pdf).contains_key(&image_id));
+        let options = ResampleOptions {
+            on_unknown_dpi: UnknownDpiPolicy::Skip,
+            linearize: true,
+            ..Default::default()
+        };
+        let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        assert_eq!(result.skip_reasons.get(&SkipReason::NoDisplayInfo), Some(&1));
+    }
+
+
+    #[test]
+    fn zip_bomb_stops_at_max_decompressed_bytes() {
+        // 17 MB of zeros deflate to a few kilobytes
+        let mut image = rgb_image(2400, 2400);
+        let bomb = deflate(&vec![0; image.content.len()]);
+        assert!(bomb.len() < 64 * 1024);
+        assert!(inflate(&bomb, 1 << 20).unwrap_err().contains("exceeds the limit"));
+        assert_eq!(inflate(&bomb, usize::MAX).unwrap().len(), image.content.len());
+
+        image.dict.set("Filter", "FlateDecode");
+        image.set_content(bomb);
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let options = ResampleOptions {
+            max_decompressed_bytes: 1 << 20,
+            collect_per_image: true,
+            ..Default::default()
+        };
+        let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+        let record = result.per_image.iter().find(|r| r.object_id == id).unwrap();
+        assert_eq!(record.reason, Some("decode_failed"));
+        assert!(record.error.as_deref().unwrap().contains("exceeds the limit"));
     }
 
-    if let Ok(Object::Reference(parent_id)) = page_dict.get(b"Parent") {
-        if let Ok(Object::Dictionary(parent_dict)) = doc.get_object(*parent_id) {
-            if let Ok(resources) = parent_dict.get(b"Resources") {
-                return resources.clone();
-            }
+
+    #[test]
+    fn stencil_mask_of_a_dropped_image_is_removed_only_with_gc() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let layer_id = doc.add_object(dictionary! { "Type" => "OCG", "Name" => Object::string_literal("Hidden") });
+        let stencil_id = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 8,
+                "Height" => 8,
+                "ImageMask" => true,
+            },
+            vec![0x0f; 8],
+        ));
+        let mut image = rgb_image(400, 400);
+        image.dict.set("OC", layer_id);
+        image.dict.set("Mask", stencil_id);
+        let image_id = add_image_page(&mut doc, pages_id, image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+        let mut doc = Document::load_mem(&pdf).unwrap();
+        doc.catalog_mut().unwrap().set(
+            "OCProperties",
+            dictionary! {
+                "OCGs" => vec![layer_id.into()],
+                "D" => dictionary! { "OFF" => vec![layer_id.into()] },
+            },
+        );
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        // The placeholder replacing the image no longer refers to the stencil mask
+        for gc in [true, false] {
+            let options = ResampleOptions { drop_hidden_oc: true, gc, ..Default::default() };
+            let (output, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+            assert_eq!(result.dropped_images, 1);
+            assert!(!image_dict(&output, image_id).has(b"Mask"));
+            let doc = Document::load_mem(&output).unwrap();
+            assert_eq!(doc.objects.contains_key(&stencil_id), !gc);
         }
     }
 
-    let _ = page_id;
-    Object::Null
-}
 
-/// Get XObjects from resources (static version)
-fn get_xobjects_static(doc: &Document, resources: &Object) -> HashMap<String, ObjectId> {
-    let mut result = HashMap::new();
+    #[test]
+    fn half_output_scale_halves_the_target_size() {
+        let (pdf, id) = single_image_pdf(rgb_image(800, 800), "q 200 0 0 200 0 0 cm /Im0 Do Q");
+        let target = |output_scale: f32| {
+            let options = ResampleOptions { output_scale, collect_per_image: true, ..Default::default() };
+            let (_, result) = resample_pdf_bytes(&pdf, &options).unwrap();
+            let record = result.per_image.iter().find(|r| r.object_id == id).unwrap();
+            assert_eq!(record.decision, "resampled");
+            (record.target_width, record.target_height)
+        };
 
-    let res_dict = match resources {
-        Object::Dictionary(d) => Some(d),
-        Object::Reference(id) => {
-            if let Ok(Object::Dictionary(d)) = doc.get_object(*id) {
-                Some(d)
-            } else {
-                None
-            }
+        // 200 pt at 150 DPI is 417 px; printed at half size it needs 208
+        assert_eq!(target(1.0), (417, 417));
+        assert_eq!(target(0.5), (208, 208));
+    }
+
+
+    /// Subscriber keeping the message of every event and the name of every span
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct RecordingSubscriber {
+        events: std::sync::Mutex<Vec<String>>,
+        spans: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for RecordingSubscriber {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
         }
-        _ => None,
-    };
 
-    if let Some(res_dict) = res_dict {
-        if let Ok(xobjects) = res_dict.get(b"XObject") {
-            let xobj_dict = match xobjects {
-                Object::Dictionary(d) => Some(d),
-                Object::Reference(id) => {
-                    if let Ok(Object::Dictionary(d)) = doc.get_object(*id) {
-                        Some(d)
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
-            };
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata().name());
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
 
-            if let Some(xobj_dict) = xobj_dict {
-                for (name, value) in xobj_dict.iter() {
-                    let name_str = String::from_utf8_lossy(name).to_string();
-                    if let Object::Reference(obj_id) = value {
-                        result.insert(name_str, *obj_id);
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Message<'a>(&'a mut String);
+            impl tracing::field::Visit for Message<'_> {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        *self.0 = format!("{:?}", value);
                     }
                 }
             }
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.events.lock().unwrap().push(message);
         }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
     }
 
-    result
-}
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_feature_emits_spans_and_image_events() {
+        let (pdf, _) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let subscriber = std::sync::Arc::new(RecordingSubscriber::default());
+        tracing::subscriber::with_default(subscriber.clone(), || {
+            resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        });
 
-/// Extract image info from a stream object
-fn extract_image_info_from_stream(
-    obj_id: ObjectId,
-    stream: &Stream,
-    doc: &Document,
-    display_info: Option<&ImageDisplayInfo>,
-    is_smask: bool,
-) -> ImageInfo {
-    let width = stream
-        .dict
-        .get(b"Width")
-        .ok()
-        .and_then(|w| match w {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(0);
+        assert!(subscriber.spans.lock().unwrap().contains(&"resample_document"));
+        let events = subscriber.events.lock().unwrap();
+        for message in ["image placement", "image resampled", "images processed"] {
+            assert!(events.iter().any(|e| e == message), "no {:?} in {:?}", message, events);
+        }
+    }
 
-    let height = stream
-        .dict
-        .get(b"Height")
-        .ok()
-        .and_then(|h| match h {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(0);
 
-    let color_space = if is_smask {
-        "DeviceGray".to_string()
-    } else {
-        stream
-            .dict
-            .get(b"ColorSpace")
-            .ok()
-            .map(|cs| get_color_space_name(cs, doc))
-            .unwrap_or_else(|| "Unknown".to_string())
-    };
+    #[test]
+    fn pdf_number_forms_feed_cm_operands() {
+        // The malformed `--2` still counts as an operand, so the six stay aligned
+        let content = "q 400. 0 0 +400 --2 0 cm .5 0 0 .5 0 0 cm /Im0 Do Q";
+        let (pdf, image_id) = single_image_pdf(rgb_image(40, 40), content);
+        assert_eq!(display_sizes(&pdf)[&image_id], (200.0, 200.0));
+    }
 
-    let bits_per_component = stream
-        .dict
-        .get(b"BitsPerComponent")
-        .ok()
-        .and_then(|b| match b {
-            Object::Integer(n) => Some(*n as u32),
-            _ => None,
-        })
-        .unwrap_or(8);
 
-    let filter = stream
-        .dict
-        .get(b"Filter")
-        .ok()
-        .and_then(|f| match f {
-            Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-            Object::Array(arr) => arr.first().and_then(|f| match f {
-                Object::Name(n) => Some(String::from_utf8_lossy(n).to_string()),
-                _ => None,
-            }),
-            _ => None,
-        })
-        .unwrap_or_else(|| "raw".to_string());
+    #[test]
+    fn form_drawn_on_two_pages_reports_a_placement_on_each() {
+        let (pdf, image_id) = form_on_two_pages_pdf();
+        let mut placements: Vec<_> = compute_image_placements(&pdf)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.page, p.object_id, p.x, p.y, p.w, p.h))
+            .collect();
+        placements.sort_by_key(|p| p.0);
+        assert_eq!(
+            placements,
+            vec![(1, image_id, 10.0, 10.0, 50.0, 50.0), (2, image_id, 0.0, 0.0, 200.0, 200.0)]
+        );
+    }
 
-    let dpi_x = display_info.map(|info| info.effective_dpi_x());
-    let dpi_y = display_info.map(|info| info.effective_dpi_y());
 
-    ImageInfo {
-        object_id: (obj_id.0, obj_id.1),
-        image_type: if is_smask { "smask".to_string() } else { "image".to_string() },
-        width,
-        height,
-        color_space,
-        bits_per_component,
-        filter,
-        size_bytes: stream.content.len(),
-        dpi_x,
-        dpi_y,
+    #[test]
+    fn indexed_palette_in_a_flate_stream_is_decoded() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let palette = Stream::new(dictionary! { "Filter" => "FlateDecode" }, deflate(&[255, 0, 0, 0, 0, 255]));
+        let palette_id = doc.add_object(palette);
+        let mut image = rgb_image(4, 1);
+        image.set_content(vec![0, 1, 1, 0]);
+        image.dict.set(
+            "ColorSpace",
+            vec!["Indexed".into(), "DeviceRGB".into(), 1.into(), palette_id.into()],
+        );
+        let image_id = add_image_page(&mut doc, pages_id, image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let pdf = finish_document(&mut doc, pages_id);
+
+        assert!(unsupported_images(&pdf).unwrap().is_empty());
+        let extracted = extract_image_native(&pdf, &format!("{} {}", image_id.0, image_id.1)).unwrap();
+        let pixels = image::load_from_memory(&extracted.data).unwrap().to_rgb8();
+        let red = [255, 0, 0];
+        let blue = [0, 0, 255];
+        let expected: Vec<u8> = [red, blue, blue, red].concat();
+        assert_eq!(pixels.into_raw(), expected);
     }
-}
 
-#[cfg(not(target_arch = "wasm32"))]
-pub mod file_ops {
-    use super::*;
-    use std::path::Path;
 
-    /// Resample PDF from file path to file path
-    pub fn resample_pdf_file(
-        input_path: &Path,
-        output_path: &Path,
-        options: &ResampleOptions,
-    ) -> Result<ResampleResult, ResampleError> {
-        if options.quality == 0 || options.quality > 100 {
-            return Err(ResampleError::InvalidQuality);
+    #[test]
+    fn thumbnails_are_removed_only_under_remove() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let thumb_id = doc.add_object(rgb_image(76, 99));
+        let page_id = add_page(&mut doc, pages_id, Dictionary::new(), "");
+        doc.get_dictionary_mut(page_id).unwrap().set("Thumb", thumb_id);
+        let pdf = finish_document(&mut doc, pages_id);
+
+        for (thumbnails, kept) in [(ThumbPolicy::Keep, true), (ThumbPolicy::Remove, false)] {
+            let options = ResampleOptions { thumbnails, ..Default::default() };
+            let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+            let doc = Document::load_mem(&output).unwrap();
+            assert_eq!(doc.get_dictionary(page_id).unwrap().has(b"Thumb"), kept);
+            assert_eq!(doc.objects.contains_key(&thumb_id), kept);
         }
+    }
 
-        // Step 1: Scan all content streams to find image display dimensions
-        let display_info_map = {
-            let doc = Document::load(input_path)
-                .map_err(|e| ResampleError::LoadError(format!("{:?}: {}", input_path, e)))?;
-            let mut scanner = ContentScanner::new(&doc, options.verbose);
-            scanner.scan_all_pages();
-            let map = scanner.get_display_info_map();
 
-            if options.verbose {
-                println!("\nFound display info for {} images", map.len());
-                for (id, info) in &map {
-                    println!(
-                        "  {:?}: {}x{} px @ {:.1}x{:.1} pt = {:.1} DPI",
-                        id,
-                        info.pixel_width,
-                        info.pixel_height,
-                        info.display_width_points,
-                        info.display_height_points,
-                        info.max_effective_dpi()
-                    );
-                }
-            }
-            map
-        }; // doc is dropped here
+    #[test]
+    fn validate_opens_files_the_resampler_opens_and_agrees_with_the_decoder() {
+        let mut image = rgb_image(4, 4);
+        image.dict.set("BitsPerComponent", 16);
+        image.set_content(vec![0; 4 * 4 * 6]);
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+
+        let report = validate_pdf(&pdf, None).unwrap();
+        let reason = report.images[0].reason.clone().unwrap();
+        assert_eq!(reason, "16-bit DeviceRGB (only 8-bit is supported)");
+        let stream = image_stream(&pdf, id);
+        let decoded = decode_image_stream(&Document::new(), &stream, 4, 4, "DeviceRGB", 16, usize::MAX);
+        assert_eq!(decoded.unwrap_err(), reason);
+
+        assert_eq!(validate_pdf(&break_xref(&pdf), None).unwrap().unsupported_count, 1);
+        let encrypted = encrypt_pdf(&pdf);
+        assert!(matches!(validate_pdf(&encrypted, None), Err(ResampleError::LoadError(_))));
+        assert_eq!(validate_pdf(&encrypted, Some("user")).unwrap().unsupported_count, 1);
+    }
 
-        // Step 2: Process images
-        let mut doc = Document::load(input_path)
-            .map_err(|e| ResampleError::LoadError(format!("{:?}: {}", input_path, e)))?;
 
-        let log_fn = |msg: &str| {
-            if options.verbose {
-                println!("{}", msg);
-            }
-        };
+    #[test]
+    fn output_version_sets_the_header_and_gates_object_streams() {
+        let (pdf, _) = single_image_pdf(rgb_image(400, 400), "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+
+        for (version, object_streams) in [("1.4", false), ("1.7", true)] {
+            let options = ResampleOptions {
+                output_version: Some(version.to_string()),
+                use_object_streams: true,
+                ..Default::default()
+            };
+            let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+            assert!(output.starts_with(format!("%PDF-{}", version).as_bytes()));
+            assert_eq!(contains(&output, b"/ObjStm"), object_streams, "{}", version);
+            assert_eq!(Document::load_mem(&output).unwrap().get_pages().len(), 1);
+        }
+
+        let options = ResampleOptions { output_version: Some("3.0".to_string()), ..Default::default() };
+        assert!(matches!(resample_pdf_bytes(&pdf, &options), Err(ResampleError::SaveError(_))));
+    }
+
+
+    #[test]
+    fn resampled_one_bit_scan_is_stored_lossless() {
+        // 800x800 1-bit scan, black left half and white right half
+        let row = [vec![0x00; 50], vec![0xff; 50]].concat();
+        let mut image = gray_smask(800, 800, row.repeat(800));
+        image.dict.set("BitsPerComponent", 1);
+        let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+        assert!(validate_pdf(&pdf, None).unwrap().images[0].supported);
+
+        let (output, result) = resample_pdf_bytes(&pdf, &ResampleOptions::default()).unwrap();
+        assert_eq!(result.resampled_images, 1);
+        let stream = image_stream(&output, id);
+        assert_eq!(stream.dict.get(b"Filter").unwrap(), &Object::Name(b"FlateDecode".to_vec()));
+        assert_eq!(stream.dict.get(b"ColorSpace").unwrap(), &Object::Name(b"DeviceGray".to_vec()));
+        assert_eq!(stream.dict.get(b"Width").unwrap(), &Object::Integer(208));
+        let samples = inflate(&stream.content, usize::MAX).unwrap();
+        assert_eq!((samples[0], samples[207]), (0, 255));
+    }
+
+
+    #[test]
+    fn odd_contents_entries_are_reported_or_read() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image_id = doc.add_object(rgb_image(400, 400));
+        let xobjects = dictionary! { "Im0" => image_id };
+        let first = add_page(&mut doc, pages_id, xobjects.clone(), "q 50 0 0 50 0 0 cm /Im0 Do Q");
+        let second = add_page(&mut doc, pages_id, xobjects, "");
+        let page = doc.get_dictionary_mut(first).unwrap();
+        let content = page.get(b"Contents").unwrap().clone();
+        page.set("Contents", vec![content, 7.into()]);
+        doc.get_dictionary_mut(second)
+            .unwrap()
+            .set("Contents", Object::string_literal("q 100 0 0 100 0 0 cm /Im0 Do Q"));
+        let pdf = finish_document(&mut doc, pages_id);
+
+        let messages = std::cell::RefCell::new(Vec::new());
+        let options = ResampleOptions { verbose: true, ..Default::default() };
+        resample_pdf_bytes_with_logger(&pdf, &options, None, |msg| {
+            messages.borrow_mut().push(msg.to_string())
+        })
+        .unwrap();
+        let messages = messages.into_inner();
+        let warned = |text: &str| messages.iter().any(|m| m.contains("[Scanner] Warning") && m.contains(text));
+        assert!(warned("unexpected Integer in /Contents"), "{:#?}", messages);
+        assert!(warned("content stored as a string"), "{:#?}", messages);
+        // The string content is still read, so the larger placement on page 2 counts
+        assert_eq!(display_sizes(&pdf)[&image_id], (100.0, 100.0));
+    }
 
-        let result = process_images_in_doc(&mut doc, &display_info_map, options, log_fn)
-            .map_err(|e| ResampleError::ProcessingError(e))?;
 
-        // Compress streams if requested
-        if options.compress_streams {
-            doc.compress();
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn mozjpeg_output_is_smaller_than_jpeg_encoder_at_equal_quality() {
+        // Photo-like content: smooth gradients with fine pseudo-random texture
+        let (width, height) = (256u32, 256u32);
+        let mut samples = Vec::with_capacity((width * height * 3) as usize);
+        let mut seed = 0x2545_f491u32;
+        for y in 0..height {
+            for x in 0..width {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let noise = (seed >> 27) as i32 - 16;
+                for base in [x as i32, y as i32, ((x + y) / 2) as i32] {
+                    samples.push((base + noise).clamp(0, 255) as u8);
+                }
+            }
         }
+        let gray: Vec<u8> = samples.chunks(3).map(|p| p[0]).collect();
+
+        let reference = |data: &[u8], color_type| {
+            let mut jpeg = Vec::new();
+            let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 75);
+            encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_0);
+            encoder.set_optimized_huffman_tables(true);
+            encoder.encode(data, width as u16, height as u16, color_type).unwrap();
+            jpeg
+        };
+        let sampling = jpeg_encoder::SamplingFactor::R_4_2_0;
+        let rgb = encode_jpeg(JpegPixels::Rgb(&samples, sampling), width, height, 75, false, true)
+            .unwrap();
+        let luma = encode_jpeg(JpegPixels::Gray(&gray), width, height, 75, false, true).unwrap();
+
+        assert!(rgb.len() < reference(&samples, jpeg_encoder::ColorType::Rgb).len());
+        assert!(luma.len() < reference(&gray, jpeg_encoder::ColorType::Luma).len());
+        assert!(image::load_from_memory(&luma).unwrap().color() == image::ColorType::L8);
+    }
 
-        // Save
-        doc.save(output_path)
-            .map_err(|e| ResampleError::SaveError(format!("{:?}: {}", output_path, e)))?;
 
-        Ok(result)
+    #[test]
+    fn optimized_huffman_tables_are_no_larger_than_the_defaults() {
+        let mut gray = rgb_image(400, 400);
+        gray.dict.set("ColorSpace", "DeviceGray");
+        gray.set_content((0..400 * 400).map(|i| (i % 400 / 2 + i / 400 / 4) as u8).collect());
+        for image in [rgb_image(400, 400), gray] {
+            let (pdf, id) = single_image_pdf(image, "q 100 0 0 100 0 0 cm /Im0 Do Q");
+            let size = |optimize_huffman| {
+                let options = ResampleOptions { optimize_huffman, ..Default::default() };
+                let (output, _) = resample_pdf_bytes(&pdf, &options).unwrap();
+                let stream = image_stream(&output, id);
+                assert_eq!(stream.dict.get(b"Filter").unwrap().as_name().unwrap(), b"DCTDecode");
+                stream.content.len()
+            };
+            assert!(size(true) <= size(false));
+        }
     }
-}
 
+}
@@ -2,26 +2,66 @@
 //!
 //! Command-line interface for resampling images in PDFs.
 
-use clap::Parser;
-use resample_pdf::{file_ops::resample_pdf_file, ResampleOptions};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use resample_pdf::{
+    extract_image_native, extract_pdf_images_info_with_threshold,
+    file_ops::{resample_pdf_file, write_atomically},
+    page_images_to_json, resample_to_target_size, ChromaSubsampling, ForcedCs, ResampleOptions,
+    ThumbPolicy, UnknownDpiPolicy,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 /// Resample images in a PDF to a target DPI
+///
+/// Running without a subcommand is the same as `resample`.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    resample: Option<ResampleArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Resample images in a PDF to a target DPI
+    Resample(ResampleArgs),
+    /// List images in a PDF without resampling
+    Info(InfoArgs),
+    /// Extract a single image to a file
+    Extract(ExtractArgs),
+}
+
+#[derive(Args, Debug)]
+struct ResampleArgs {
     /// Input PDF file path
     #[arg(short, long)]
     input: PathBuf,
 
     /// Output PDF file path
-    #[arg(short, long)]
-    output: PathBuf,
+    #[arg(short, long, required_unless_present = "in_place", conflicts_with = "in_place")]
+    output: Option<PathBuf>,
+
+    /// Overwrite the input file with the result (written to a temporary file first)
+    #[arg(long)]
+    in_place: bool,
 
     /// Target DPI for images (based on display dimensions)
     #[arg(short, long, default_value = "150")]
     dpi: f32,
 
+    /// Target DPI for grayscale images (defaults to --dpi)
+    #[arg(long)]
+    gray_dpi: Option<f32>,
+
+    /// Scale the output will be printed or shown at, e.g. 0.8 for 80%
+    #[arg(long, default_value = "1.0")]
+    output_scale: f32,
+
     /// JPEG quality (1-100, only affects images without alpha)
     #[arg(short, long, default_value = "75")]
     quality: u8,
@@ -34,19 +74,347 @@ struct Args {
     #[arg(short, long, default_value = "true")]
     compress_streams: bool,
 
+    /// Re-encode already-JPEG images at target DPI if their estimated quality is above this value
+    #[arg(long)]
+    recompress_jpeg_below_quality: Option<u8>,
+
+    /// Re-encode at --quality even when an image needs no downsampling
+    #[arg(long)]
+    recompress_even_if_not_smaller: bool,
+
+    /// Enlarge images drawn below --dpi up to it
+    #[arg(long)]
+    allow_upscaling: bool,
+
+    /// Area-average images shrinking by more than this factor before the final Lanczos3 pass (0 disables)
+    #[arg(long, default_value = "4")]
+    area_average_above_ratio: f32,
+
+    /// Downsample any image with more than this many pixels, regardless of DPI
+    #[arg(long)]
+    max_pixels: Option<u32>,
+
+    /// Downsample any image whose longest side exceeds this many pixels, regardless of DPI
+    #[arg(long)]
+    max_long_edge: Option<u32>,
+
+    /// How to treat images whose display size could not be determined
+    #[arg(long, value_enum, default_value = "assume-low")]
+    on_unknown_dpi: UnknownDpiArg,
+
+    /// Leave images untouched when their effective DPI exceeds this value (0 disables the check)
+    #[arg(long, default_value = "9600")]
+    sanity_max_dpi: f32,
+
+    /// Stop following Form XObjects nested deeper than this
+    #[arg(long, default_value = "32")]
+    max_form_depth: usize,
+
+    /// Fail on streams that inflate past this many megabytes
+    #[arg(long, default_value = "512")]
+    max_decompressed_mb: usize,
+
+    /// Skip images whose encoded size is below this many bytes
+    #[arg(long)]
+    min_image_bytes: Option<usize>,
+
+    /// Skip images with fewer than this many pixels
+    #[arg(long)]
+    min_image_pixels: Option<u32>,
+
+    /// Keep the original image unless re-encoding makes it at least this fraction smaller (e.g. 0.1)
+    #[arg(long, default_value = "0")]
+    min_savings_ratio: f32,
+
+    /// Raise JPEG quality for grayscale images (likely scanned text) to --gray-quality-floor
+    #[arg(long)]
+    adaptive_quality: bool,
+
+    /// Minimum JPEG quality for grayscale images when --adaptive-quality is set
+    #[arg(long, default_value = "60")]
+    gray_quality_floor: u8,
+
+    /// Raise JPEG quality by this much for images that were already JPEG
+    #[arg(long, default_value = "0")]
+    jpeg_source_quality_bonus: u8,
+
+    /// JPEG chroma subsampling
+    #[arg(long, value_enum, default_value = "420")]
+    chroma_subsampling: ChromaArg,
+
+    /// Write progressive JPEGs instead of baseline
+    #[arg(long)]
+    progressive: bool,
+
+    /// Compute optimized Huffman tables for each JPEG (a few percent smaller, slower)
+    #[arg(long)]
+    optimize_huffman: bool,
+
+    /// Tag re-encoded color images with an embedded sRGB ICC profile
+    #[arg(long)]
+    embed_srgb: bool,
+
+    /// Put the first page's objects at the start of the file for faster web display
+    #[arg(long)]
+    linearize: bool,
+
+    /// Password for encrypted PDFs (output is written decrypted)
+    #[arg(long)]
+    password: Option<String>,
+
+    /// Pack objects into object streams with an xref stream (smaller structure)
+    #[arg(long)]
+    use_object_streams: bool,
+
+    /// PDF version for the output header; object streams are not used below 1.5
+    #[arg(long, value_parser = ["1.3", "1.4", "1.5", "1.6", "1.7", "2.0"])]
+    output_version: Option<String>,
+
+    /// Give images drawn both large and small a separate, smaller copy for the small uses
+    #[arg(long)]
+    split_multiuse_images: bool,
+
+    /// Remove the /Info dictionary and XMP metadata from the output
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Keep image objects that are no longer referenced
+    #[arg(long)]
+    no_gc: bool,
+
+    /// What to do with embedded page thumbnails
+    #[arg(long, value_enum, default_value = "keep")]
+    thumbnails: ThumbArg,
+
+    /// Store color images that contain only gray pixels as grayscale
+    #[arg(long)]
+    detect_grayscale: bool,
+
+    /// Convert every image to this color space, even ones that need no downsampling
+    #[arg(long, value_enum)]
+    force_color_space: Option<ForcedCsArg>,
+
+    /// Replace images in layers that are hidden by default with an empty placeholder
+    #[arg(long)]
+    drop_hidden_oc: bool,
+
+    /// Flatten transparent images over this background color (RRGGBB hex, e.g. ffffff)
+    /// and drop their soft masks
+    #[arg(long, value_parser = parse_hex_color)]
+    flatten_alpha: Option<[u8; 3]>,
+
+    /// Lower DPI and quality as needed to get the output under this many bytes
+    #[arg(long)]
+    target_size: Option<usize>,
+
+    /// Print what happened to each image, with its size before and after
+    #[arg(long)]
+    per_image: bool,
+
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum UnknownDpiArg {
+    /// Assume 72 DPI, which usually leaves the image unresized
+    AssumeLow,
+    /// Leave the image untouched
+    Skip,
+    /// Assume the image is at target DPI and re-encode without resizing
+    AssumeTarget,
+}
+
+impl From<UnknownDpiArg> for UnknownDpiPolicy {
+    fn from(arg: UnknownDpiArg) -> Self {
+        match arg {
+            UnknownDpiArg::AssumeLow => UnknownDpiPolicy::AssumeLow,
+            UnknownDpiArg::Skip => UnknownDpiPolicy::Skip,
+            UnknownDpiArg::AssumeTarget => UnknownDpiPolicy::AssumeTarget,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ThumbArg {
+    /// Leave thumbnails as they are
+    Keep,
+    /// Delete them
+    Remove,
+    /// Render new thumbnails (not supported yet; thumbnails are kept)
+    Regenerate,
+}
+
+impl From<ThumbArg> for ThumbPolicy {
+    fn from(arg: ThumbArg) -> Self {
+        match arg {
+            ThumbArg::Keep => ThumbPolicy::Keep,
+            ThumbArg::Remove => ThumbPolicy::Remove,
+            ThumbArg::Regenerate => ThumbPolicy::Regenerate,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ChromaArg {
+    /// 4:4:4 for small or nearly colorless images, 4:2:0 otherwise
+    Auto,
+    /// Full chroma resolution
+    #[value(name = "444")]
+    S444,
+    /// Chroma halved horizontally
+    #[value(name = "422")]
+    S422,
+    /// Chroma halved in both directions
+    #[value(name = "420")]
+    S420,
+}
+
+impl From<ChromaArg> for ChromaSubsampling {
+    fn from(arg: ChromaArg) -> Self {
+        match arg {
+            ChromaArg::Auto => ChromaSubsampling::Auto,
+            ChromaArg::S444 => ChromaSubsampling::S444,
+            ChromaArg::S422 => ChromaSubsampling::S422,
+            ChromaArg::S420 => ChromaSubsampling::S420,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ForcedCsArg {
+    /// DeviceRGB
+    Rgb,
+    /// DeviceGray
+    Gray,
+}
+
+impl From<ForcedCsArg> for ForcedCs {
+    fn from(arg: ForcedCsArg) -> Self {
+        match arg {
+            ForcedCsArg::Rgb => ForcedCs::Rgb,
+            ForcedCsArg::Gray => ForcedCs::Gray,
+        }
+    }
+}
+
+/// Parse an RRGGBB color, with or without a leading '#'
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| format!("expected a color as RRGGBB hex, got {:?}", value))
+    };
+    if hex.len() != 6 {
+        return Err(format!("expected a color as RRGGBB hex, got {:?}", value));
+    }
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Whether two paths name the same existing file
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Input PDF file path
+    input: PathBuf,
+
+    /// Print image information as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Flag images displayed below this effective DPI as upscaled
+    #[arg(long, default_value = "72")]
+    upscaled_below_dpi: f32,
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    /// Input PDF file path
+    input: PathBuf,
+
+    /// Image object ID in "num gen" format, e.g. "12 0"
+    #[arg(long)]
+    id: String,
+
+    /// Output file path (defaults to image_<num>_<gen>.<format>)
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+
+    match (cli.command, cli.resample) {
+        (Some(Command::Resample(args)), _) | (None, Some(args)) => run_resample(args),
+        (Some(Command::Info(args)), _) => run_info(args),
+        (Some(Command::Extract(args)), _) => run_extract(args),
+        (None, None) => {
+            use clap::CommandFactory;
+            Cli::command().print_help()?;
+            Ok(())
+        }
+    }
+}
+
+fn run_resample(args: ResampleArgs) -> anyhow::Result<()> {
+    let output_path = match args.output {
+        Some(output) if same_file(&args.input, &output) => anyhow::bail!(
+            "input and output are the same file ({:?}); pass --in-place to overwrite it",
+            output
+        ),
+        Some(output) => output,
+        None => args.input.clone(),
+    };
 
     let options = ResampleOptions {
         target_dpi: args.dpi,
+        gray_target_dpi: args.gray_dpi,
+        output_scale: args.output_scale,
         quality: args.quality,
         min_dpi: args.min_dpi,
         compress_streams: args.compress_streams,
+        recompress_jpeg_below_quality: args.recompress_jpeg_below_quality,
+        recompress_even_if_not_smaller: args.recompress_even_if_not_smaller,
+        allow_upscaling: args.allow_upscaling,
+        area_average_above_ratio: (args.area_average_above_ratio > 0.0)
+            .then_some(args.area_average_above_ratio),
+        max_pixels: args.max_pixels,
+        max_long_edge: args.max_long_edge,
+        on_unknown_dpi: args.on_unknown_dpi.into(),
+        sanity_max_dpi: (args.sanity_max_dpi > 0.0).then_some(args.sanity_max_dpi),
+        max_form_depth: args.max_form_depth,
+        max_decompressed_bytes: args.max_decompressed_mb.saturating_mul(1024 * 1024),
+        min_image_bytes: args.min_image_bytes,
+        min_image_pixels: args.min_image_pixels,
+        min_savings_ratio: args.min_savings_ratio,
+        adaptive_quality: args.adaptive_quality,
+        gray_quality_floor: args.gray_quality_floor,
+        jpeg_source_quality_bonus: args.jpeg_source_quality_bonus,
+        chroma_subsampling: args.chroma_subsampling.into(),
+        progressive_jpeg: args.progressive,
+        optimize_huffman: args.optimize_huffman,
+        embed_srgb: args.embed_srgb,
+        linearize: args.linearize,
+        password: args.password,
+        use_object_streams: args.use_object_streams,
+        output_version: args.output_version,
+        split_multiuse_images: args.split_multiuse_images,
+        preserve_metadata: !args.strip_metadata,
+        thumbnails: args.thumbnails.into(),
+        gc: !args.no_gc,
+        detect_grayscale: args.detect_grayscale,
+        force_color_space: args.force_color_space.map(Into::into),
+        drop_hidden_oc: args.drop_hidden_oc,
+        flatten_alpha: args.flatten_alpha,
+        collect_per_image: args.per_image,
         verbose: args.verbose,
     };
 
@@ -57,13 +425,160 @@ fn main() -> anyhow::Result<()> {
         println!("\nStep 1: Scanning content streams for image display dimensions...");
     }
 
-    let result = resample_pdf_file(&args.input, &args.output, &options)?;
+    let result = match args.target_size {
+        Some(target_size) => {
+            let bytes = std::fs::read(&args.input)?;
+            let (output, result) = resample_to_target_size(&bytes, target_size, &options)?;
+            if output.len() > target_size {
+                println!(
+                    "\nWarning: could not reach {} bytes; output is {} bytes",
+                    target_size,
+                    output.len()
+                );
+            }
+            write_atomically(&output_path, |file| file.write_all(&output))?;
+            result
+        }
+        None => resample_pdf_file(&args.input, &output_path, &options)?,
+    };
 
     println!(
-        "\nDone! Processed {} images: {} resampled, {} skipped",
-        result.total_images, result.resampled_images, result.skipped_images
+        "\nDone! Processed {} images: {} resampled, {} skipped, {} failed",
+        result.total_images, result.resampled_images, result.skipped_images, result.failed_images
     );
-    println!("Output saved to: {:?}", args.output);
+    if result.dropped_images > 0 {
+        println!("Dropped {} images in hidden layers", result.dropped_images);
+    }
+    if result.skipped_images > 0 {
+        let mut reasons: Vec<_> = result.skip_reasons.iter().collect();
+        reasons.sort_by_key(|(reason, count)| (std::cmp::Reverse(**count), reason.as_str()));
+        let reasons: Vec<String> =
+            reasons.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+        println!("Skipped: {}", reasons.join(", "));
+    }
+    for image in &result.per_image {
+        let decision = match (image.reason, &image.error) {
+            (Some(reason), Some(error)) => format!("{} ({}: {})", image.decision, reason, error),
+            (Some(reason), None) => format!("{} ({})", image.decision, reason),
+            (None, Some(error)) => format!("{} ({})", image.decision, error),
+            (None, None) => image.decision.to_string(),
+        };
+        println!(
+            "  {} {} R: {} {}x{} -> {}x{}, {} -> {} bytes",
+            image.object_id.0,
+            image.object_id.1,
+            decision,
+            image.original_width,
+            image.original_height,
+            image.target_width,
+            image.target_height,
+            image.original_bytes,
+            image.new_bytes
+        );
+    }
+    println!("Output saved to: {:?}", output_path);
+
+    Ok(())
+}
+
+fn run_info(args: InfoArgs) -> anyhow::Result<()> {
+    let bytes = std::fs::read(&args.input)?;
+    let pages = extract_pdf_images_info_with_threshold(&bytes, args.upscaled_below_dpi)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&page_images_to_json(&pages))?);
+        return Ok(());
+    }
+
+    println!(
+        "{:>5} {:>10} {:>6} {:>12} {:>14} {:>4} {:>12} {:>10} {:>12}  note",
+        "page", "object", "type", "size (px)", "color", "bpc", "filter", "bytes", "dpi"
+    );
+    for page in &pages {
+        for img in &page.images {
+            let dpi = match (img.dpi_x, img.dpi_y) {
+                (Some(x), Some(y)) => format!("{:.0}x{:.0}", x, y),
+                _ => "-".to_string(),
+            };
+            println!(
+                "{:>5} {:>10} {:>6} {:>12} {:>14} {:>4} {:>12} {:>10} {:>12}  {}",
+                page.page_number,
+                format!("{} {}", img.object_id.0, img.object_id.1),
+                img.image_type,
+                format!("{}x{}", img.width, img.height),
+                img.color_space,
+                img.bits_per_component,
+                img.filter,
+                img.size_bytes,
+                dpi,
+                match (&img.skip_reason, img.is_upscaled) {
+                    (Some(reason), _) => reason.as_str(),
+                    (None, true) => "upscaled (low DPI)",
+                    (None, false) => "",
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run_extract(args: ExtractArgs) -> anyhow::Result<()> {
+    let bytes = std::fs::read(&args.input)?;
+    let image = extract_image_native(&bytes, &args.id)?;
+
+    let out = args.out.unwrap_or_else(|| {
+        let id = args.id.split_whitespace().collect::<Vec<_>>().join("_");
+        PathBuf::from(format!("image_{}.{}", id, image.format))
+    });
+
+    std::fs::write(&out, &image.data)?;
+    println!("Extracted {} ({} bytes) to {:?}", image.mime_type, image.data.len(), out);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copy a sample PDF into a fresh temporary directory
+    fn sample_copy(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("resample-pdf-{}-{}", test_name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sample.pdf");
+        std::fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("input/input7.pdf"), &path).unwrap();
+        path
+    }
+
+    fn resample_args(args: &[&str]) -> ResampleArgs {
+        let cli = Cli::try_parse_from(std::iter::once("resample-pdf").chain(args.iter().copied())).unwrap();
+        cli.resample.unwrap()
+    }
+
+    #[test]
+    fn in_place_resample_leaves_a_valid_pdf() {
+        let path = sample_copy("in-place");
+        let pages = lopdf::Document::load(&path).unwrap().get_pages().len();
+
+        run_resample(resample_args(&["-i", path.to_str().unwrap(), "--in-place"])).unwrap();
+
+        let doc = lopdf::Document::load(&path).unwrap();
+        assert_eq!(doc.get_pages().len(), pages);
+        let leftovers = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(leftovers, 1, "temporary file left behind");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn same_output_path_without_in_place_is_refused() {
+        let path = sample_copy("same-output");
+        let original = std::fs::read(&path).unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let err = run_resample(resample_args(&["-i", path_str, "-o", path_str])).unwrap_err();
+        assert!(err.to_string().contains("--in-place"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+//! PDF content stream tokenizer
+//!
+//! Splits a decompressed content stream into typed tokens following the
+//! whitespace and delimiter rules of the PDF specification (ISO 32000-1, 7.2).
+
+/// A lexical token from a content stream
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// Integer or real number
+    Number(f32),
+    /// Name with the leading `/` removed and `#xx` escapes decoded
+    Name(String),
+    /// Literal string `( ... )` with escapes decoded
+    String(Vec<u8>),
+    /// Hex string `< ... >` decoded to bytes
+    HexString(Vec<u8>),
+    ArrayOpen,
+    ArrayClose,
+    DictOpen,
+    DictClose,
+    /// Any other keyword (content stream operators, `true`, `false`, `null`)
+    Operator(String),
+}
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\0' | b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn is_delimiter(b: u8) -> bool {
+    matches!(
+        b,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn is_regular(b: u8) -> bool {
+    !is_whitespace(b) && !is_delimiter(b)
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a word made of number characters the way PDF readers do
+///
+/// Returns None for words that are not numbers. Besides the plain forms (`.5`,
+/// `4.`, `+3`), malformed numbers seen in the wild are read leniently instead
+/// of becoming bogus operators that would also swallow the real operator's
+/// operands: repeated leading signs count once (`--2` is -2), reading stops at
+/// a second `.` or a sign inside the number, and nothing numeric at all is 0.
+/// The flag is set when that leniency was needed.
+fn parse_number(word: &str) -> Option<(f32, bool)> {
+    if !word.bytes().all(|c| c.is_ascii_digit() || matches!(c, b'+' | b'-' | b'.')) {
+        return None;
+    }
+    if let Ok(n) = word.parse::<f32>() {
+        return Some((n, false));
+    }
+
+    let signs = word.bytes().take_while(|c| matches!(c, b'+' | b'-')).count();
+    let negative = word.as_bytes()[..signs].contains(&b'-');
+    let rest = &word[signs..];
+    let mut seen_dot = false;
+    let end = rest
+        .bytes()
+        .position(|c| match c {
+            b'.' if !seen_dot => {
+                seen_dot = true;
+                false
+            }
+            c => !c.is_ascii_digit(),
+        })
+        .unwrap_or(rest.len());
+    let value = rest[..end].parse::<f32>().unwrap_or(0.0);
+    Some((if negative { -value } else { value }, true))
+}
+
+/// Lexer over a content stream, yielding tokens in order
+pub(crate) struct Lexer<'a> {
+    data: &'a [u8],
+    pos: usize,
+    malformed_numbers: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Lexer {
+            data,
+            pos: 0,
+            malformed_numbers: 0,
+        }
+    }
+
+    /// Number of malformed numbers read so far, see [`parse_number`]
+    pub(crate) fn malformed_numbers(&self) -> usize {
+        self.malformed_numbers
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    /// Skip whitespace and `%` comments
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(b) = self.peek() {
+            if is_whitespace(b) {
+                self.pos += 1;
+            } else if b == b'%' {
+                while let Some(c) = self.peek() {
+                    if c == b'\n' || c == b'\r' {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Read a literal string; the opening `(` has already been consumed
+    fn read_literal_string(&mut self) -> Vec<u8> {
+        let mut result = Vec::new();
+        let mut depth = 1;
+
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            match b {
+                b'(' => {
+                    depth += 1;
+                    result.push(b);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    result.push(b);
+                }
+                b'\\' => {
+                    let Some(escaped) = self.peek() else { break };
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => result.push(b'\n'),
+                        b'r' => result.push(b'\r'),
+                        b't' => result.push(b'\t'),
+                        b'b' => result.push(0x08),
+                        b'f' => result.push(0x0C),
+                        b'0'..=b'7' => {
+                            // Up to three octal digits
+                            let mut value = (escaped - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        value = value * 8 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            result.push(value as u8);
+                        }
+                        b'\r' => {
+                            // Line continuation: backslash followed by EOL is ignored
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => {}
+                        other => result.push(other),
+                    }
+                }
+                _ => result.push(b),
+            }
+        }
+
+        result
+    }
+
+    /// Read a hex string; the opening `<` has already been consumed
+    fn read_hex_string(&mut self) -> Vec<u8> {
+        let mut result = Vec::new();
+        let mut high: Option<u8> = None;
+
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            if b == b'>' {
+                break;
+            }
+            if let Some(v) = hex_value(b) {
+                match high.take() {
+                    Some(h) => result.push((h << 4) | v),
+                    None => high = Some(v),
+                }
+            }
+        }
+
+        // An odd final digit is treated as if followed by 0
+        if let Some(h) = high {
+            result.push(h << 4);
+        }
+
+        result
+    }
+
+    /// Read a name; the leading `/` has already been consumed
+    fn read_name(&mut self) -> String {
+        let mut bytes = Vec::new();
+
+        while let Some(b) = self.peek() {
+            if !is_regular(b) {
+                break;
+            }
+            self.pos += 1;
+            if b == b'#' {
+                let hi = self.data.get(self.pos).copied().and_then(hex_value);
+                let lo = self.data.get(self.pos + 1).copied().and_then(hex_value);
+                if let (Some(hi), Some(lo)) = (hi, lo) {
+                    bytes.push((hi << 4) | lo);
+                    self.pos += 2;
+                    continue;
+                }
+            }
+            bytes.push(b);
+        }
+
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    /// Skip inline image data following an `ID` operator, up to the `EI` operator
+    fn skip_inline_image_data(&mut self) {
+        // A single whitespace byte separates ID from the data
+        if self.peek().is_some_and(is_whitespace) {
+            self.pos += 1;
+        }
+
+        while self.pos < self.data.len() {
+            let at_ei = self.data[self.pos..].starts_with(b"EI")
+                && (self.pos == 0 || is_whitespace(self.data[self.pos - 1]))
+                && self
+                    .data
+                    .get(self.pos + 2)
+                    .is_none_or(|&b| is_whitespace(b));
+            if at_ei {
+                return;
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            self.skip_whitespace_and_comments();
+            let b = self.peek()?;
+            self.pos += 1;
+
+            let token = match b {
+                b'(' => Token::String(self.read_literal_string()),
+                b'<' => {
+                    if self.peek() == Some(b'<') {
+                        self.pos += 1;
+                        Token::DictOpen
+                    } else {
+                        Token::HexString(self.read_hex_string())
+                    }
+                }
+                b'>' => {
+                    if self.peek() == Some(b'>') {
+                        self.pos += 1;
+                        Token::DictClose
+                    } else {
+                        // Stray '>' - ignore
+                        continue;
+                    }
+                }
+                b'[' => Token::ArrayOpen,
+                b']' => Token::ArrayClose,
+                b'/' => Token::Name(self.read_name()),
+                b')' | b'{' | b'}' => {
+                    // Unbalanced ')' or PostScript braces - not meaningful in content streams
+                    continue;
+                }
+                _ => {
+                    let start = self.pos - 1;
+                    while self.peek().is_some_and(is_regular) {
+                        self.pos += 1;
+                    }
+                    let word = String::from_utf8_lossy(&self.data[start..self.pos]).to_string();
+
+                    match parse_number(&word) {
+                        Some((n, malformed)) => {
+                            if malformed {
+                                self.malformed_numbers += 1;
+                            }
+                            Token::Number(n)
+                        }
+                        None => {
+                            if word == "ID" {
+                                self.skip_inline_image_data();
+                            }
+                            Token::Operator(word)
+                        }
+                    }
+                }
+            };
+
+            return Some(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(data: &[u8]) -> Vec<Token> {
+        Lexer::new(data).collect()
+    }
+
+    #[test]
+    fn hex_string_pads_odd_final_digit() {
+        assert_eq!(
+            tokens(b"<48 65 6c6C6f> <901FA>"),
+            vec![
+                Token::HexString(b"Hello".to_vec()),
+                Token::HexString(vec![0x90, 0x1F, 0xA0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn comment_with_parentheses_is_skipped() {
+        assert_eq!(
+            tokens(b"q % not a string ( or ) here\n1 0 0 1 0 0 cm"),
+            vec![
+                Token::Operator("q".into()),
+                Token::Number(1.0),
+                Token::Number(0.0),
+                Token::Number(0.0),
+                Token::Number(1.0),
+                Token::Number(0.0),
+                Token::Number(0.0),
+                Token::Operator("cm".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn name_escapes_are_decoded() {
+        assert_eq!(
+            tokens(b"/A#20B Do /Im#zz"),
+            vec![
+                Token::Name("A B".into()),
+                Token::Operator("Do".into()),
+                Token::Name("Im#zz".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_literal_strings_keep_inner_parentheses() {
+        assert_eq!(
+            tokens(b"(a (b (c)) \\) d\\101) Tj"),
+            vec![
+                Token::String(b"a (b (c)) ) dA".to_vec()),
+                Token::Operator("Tj".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_image_data_is_skipped() {
+        assert_eq!(
+            tokens(b"BI /W 2 /H 1 ID \x00Q cm(<EIx>) EI /Im0 Do"),
+            vec![
+                Token::Operator("BI".into()),
+                Token::Name("W".into()),
+                Token::Number(2.0),
+                Token::Name("H".into()),
+                Token::Number(1.0),
+                Token::Operator("ID".into()),
+                Token::Operator("EI".into()),
+                Token::Name("Im0".into()),
+                Token::Operator("Do".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_numbers_are_read_leniently() {
+        assert_eq!(parse_number("--2"), Some((-2.0, true)));
+        assert_eq!(parse_number("1.2.3"), Some((1.2, true)));
+        assert_eq!(parse_number("."), Some((0.0, true)));
+        assert_eq!(parse_number(".5"), Some((0.5, false)));
+        assert_eq!(parse_number("cm"), None);
+
+        let mut lexer = Lexer::new(b"--2 1.2.3 . 4 w");
+        assert_eq!(
+            lexer.by_ref().collect::<Vec<_>>(),
+            vec![
+                Token::Number(-2.0),
+                Token::Number(1.2),
+                Token::Number(0.0),
+                Token::Number(4.0),
+                Token::Operator("w".into()),
+            ]
+        );
+        assert_eq!(lexer.malformed_numbers(), 3);
+    }
+}
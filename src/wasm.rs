@@ -1,7 +1,19 @@
 //! WebAssembly bindings for PDF Image Resampler
 
 use wasm_bindgen::prelude::*;
-use crate::{resample_pdf_bytes, extract_pdf_images_info, extract_image_native, ResampleOptions};
+use crate::{
+    resample_pdf_bytes_with_logger, extract_pdf_images_info, extract_pdf_images_info_with_threshold,
+    analyze_pdf_bytes, extract_image_native, summarize_pdf_images, unsupported_images,
+    DEFAULT_UPSCALED_BELOW_DPI, page_images_to_json, image_info_to_json, skip_reasons_to_json,
+    ChromaSubsampling,
+    ForcedCs, ResampleOptions, ThumbPolicy, UnknownDpiPolicy,
+};
+use serde::Deserialize;
+
+/// Send verbose output to the browser console
+fn console_log(msg: &str) {
+    web_sys::console::log_1(&msg.into());
+}
 
 /// Initialize panic hook for better error messages in browser console
 #[wasm_bindgen(start)]
@@ -11,10 +23,18 @@ pub fn init() {
 
 /// Get image information from a PDF without processing
 /// Returns JSON string with page-by-page image details
+///
+/// Images displayed below `upscaled_below_dpi` (default: 72) are flagged `isUpscaled`
 #[wasm_bindgen]
-pub fn get_pdf_image_info(pdf_bytes: &[u8]) -> Result<String, JsError> {
-    let page_images = extract_pdf_images_info(pdf_bytes)
-        .map_err(|e| JsError::new(&e.to_string()))?;
+pub fn get_pdf_image_info(
+    pdf_bytes: &[u8],
+    upscaled_below_dpi: Option<f32>,
+) -> Result<String, JsError> {
+    let page_images = extract_pdf_images_info_with_threshold(
+        pdf_bytes,
+        upscaled_below_dpi.unwrap_or(DEFAULT_UPSCALED_BELOW_DPI),
+    )
+    .map_err(|e| JsError::new(&e.to_string()))?;
 
     let json = serde_json::to_string(&page_images_to_json(&page_images))
         .map_err(|e| JsError::new(&e.to_string()))?;
@@ -22,6 +42,209 @@ pub fn get_pdf_image_info(pdf_bytes: &[u8]) -> Result<String, JsError> {
     Ok(json)
 }
 
+/// Summarize the images of a PDF: DPI range and median, total bytes, and
+/// counts by color space and filter
+/// Returns JSON string of a `DocumentImageSummary`
+#[wasm_bindgen]
+pub fn get_pdf_image_summary(pdf_bytes: &[u8]) -> Result<String, JsError> {
+    let summary = summarize_pdf_images(pdf_bytes)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    let json = serde_json::to_string(&summary)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    Ok(json)
+}
+
+/// List images that cannot be decoded and so will not be resampled
+/// Returns JSON string with an array of image details
+#[wasm_bindgen]
+pub fn get_unsupported_images(pdf_bytes: &[u8]) -> Result<String, JsError> {
+    let images = unsupported_images(pdf_bytes)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    let json = serde_json::to_string(&images.iter().map(image_info_to_json).collect::<Vec<_>>())
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    Ok(json)
+}
+
+/// Preview what resampling with `options_json` (see `resample_pdf_with_options`)
+/// would do, without decoding any pixels
+/// Returns JSON string with an array of `ImageAnalysis`: object ID, current and
+/// target dimensions, decision, skip reason, and current and estimated bytes
+#[wasm_bindgen]
+pub fn analyze_pdf(pdf_bytes: &[u8], options_json: &str) -> Result<String, JsError> {
+    let options: ResampleOptionsJson = serde_json::from_str(options_json)
+        .map_err(|e| JsError::new(&format!("Invalid options: {}", e)))?;
+    let images = analyze_pdf_bytes(pdf_bytes, &options.into_options()?)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    let json = serde_json::to_string(&images)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    Ok(json)
+}
+
+/// Get image information from a PDF without processing
+/// Returns an array of `PageImagesJs` with typed per-image details
+///
+/// Images displayed below `upscaled_below_dpi` (default: 72) are flagged `is_upscaled`
+#[wasm_bindgen]
+pub fn get_pdf_image_info_structured(
+    pdf_bytes: &[u8],
+    upscaled_below_dpi: Option<f32>,
+) -> Result<Vec<PageImagesJs>, JsError> {
+    let page_images = extract_pdf_images_info_with_threshold(
+        pdf_bytes,
+        upscaled_below_dpi.unwrap_or(DEFAULT_UPSCALED_BELOW_DPI),
+    )
+    .map_err(|e| JsError::new(&e.to_string()))?;
+
+    Ok(page_images
+        .into_iter()
+        .map(|page| PageImagesJs {
+            page_number: page.page_number,
+            images: page.images.into_iter().map(ImageInfoJs::from).collect(),
+        })
+        .collect())
+}
+
+/// Images found on a single page
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct PageImagesJs {
+    page_number: u32,
+    images: Vec<ImageInfoJs>,
+}
+
+#[wasm_bindgen]
+impl PageImagesJs {
+    /// Get the 1-based page number
+    #[wasm_bindgen(getter)]
+    pub fn page_number(&self) -> u32 {
+        self.page_number
+    }
+
+    /// Get the images on this page
+    #[wasm_bindgen(getter)]
+    pub fn images(&self) -> Vec<ImageInfoJs> {
+        self.images.clone()
+    }
+}
+
+/// Information about a single image in the PDF
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct ImageInfoJs {
+    object_id: String,
+    image_type: String,
+    width: u32,
+    height: u32,
+    color_space: String,
+    bits_per_component: u32,
+    filter: String,
+    size_bytes: usize,
+    dpi_x: Option<f32>,
+    dpi_y: Option<f32>,
+    skip_reason: Option<String>,
+    is_upscaled: bool,
+}
+
+impl From<crate::ImageInfo> for ImageInfoJs {
+    fn from(info: crate::ImageInfo) -> Self {
+        ImageInfoJs {
+            object_id: format!("{} {}", info.object_id.0, info.object_id.1),
+            image_type: info.image_type,
+            width: info.width,
+            height: info.height,
+            color_space: info.color_space,
+            bits_per_component: info.bits_per_component,
+            filter: info.filter,
+            size_bytes: info.size_bytes,
+            dpi_x: info.dpi_x,
+            dpi_y: info.dpi_y,
+            skip_reason: info.skip_reason,
+            is_upscaled: info.is_upscaled,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ImageInfoJs {
+    /// Get the object ID in "num gen" format
+    #[wasm_bindgen(getter)]
+    pub fn object_id(&self) -> String {
+        self.object_id.clone()
+    }
+
+    /// Get the image type ("image" or "smask")
+    #[wasm_bindgen(getter)]
+    pub fn image_type(&self) -> String {
+        self.image_type.clone()
+    }
+
+    /// Get the width in pixels
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Get the height in pixels
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Get the color space name
+    #[wasm_bindgen(getter)]
+    pub fn color_space(&self) -> String {
+        self.color_space.clone()
+    }
+
+    /// Get the bits per component
+    #[wasm_bindgen(getter)]
+    pub fn bits_per_component(&self) -> u32 {
+        self.bits_per_component
+    }
+
+    /// Get the filter/encoding name
+    #[wasm_bindgen(getter)]
+    pub fn filter(&self) -> String {
+        self.filter.clone()
+    }
+
+    /// Get the stream size in bytes
+    #[wasm_bindgen(getter)]
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+
+    /// Get the effective horizontal DPI (undefined if not displayed)
+    #[wasm_bindgen(getter)]
+    pub fn dpi_x(&self) -> Option<f32> {
+        self.dpi_x
+    }
+
+    /// Get the effective vertical DPI (undefined if not displayed)
+    #[wasm_bindgen(getter)]
+    pub fn dpi_y(&self) -> Option<f32> {
+        self.dpi_y
+    }
+
+    /// Get the reason the image cannot be resampled (undefined if supported)
+    #[wasm_bindgen(getter)]
+    pub fn skip_reason(&self) -> Option<String> {
+        self.skip_reason.clone()
+    }
+
+    /// Whether the image is displayed at such a low DPI that the source is too small
+    #[wasm_bindgen(getter)]
+    pub fn is_upscaled(&self) -> bool {
+        self.is_upscaled
+    }
+}
+
 /// Extract a single image from a PDF in its native format
 /// Returns JPEG for DCTDecode images, PNG for others
 /// object_id should be in format "num gen" e.g. "12 0"
@@ -66,6 +289,24 @@ impl ExtractedImageJs {
     }
 }
 
+/// Parse an optional chroma subsampling argument, defaulting to 4:2:0
+fn parse_chroma_subsampling(value: Option<String>) -> Result<ChromaSubsampling, JsError> {
+    value
+        .map(|v| v.parse().map_err(|e: String| JsError::new(&e)))
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// Wrap an optional JS callback as a cancellation check
+///
+/// The callback is called between images; a truthy return value cancels.
+/// A callback that throws is treated as not cancelling.
+fn cancel_check(callback: &Option<js_sys::Function>) -> Option<impl Fn() -> bool + '_> {
+    callback.as_ref().map(|f| {
+        move || f.call0(&JsValue::NULL).map(|v| v.is_truthy()).unwrap_or(false)
+    })
+}
+
 /// Resample images in a PDF to a target DPI
 ///
 /// # Arguments
@@ -74,6 +315,9 @@ impl ExtractedImageJs {
 /// * `quality` - JPEG quality 1-100 (default: 75)
 /// * `min_dpi` - Minimum DPI threshold - only resample images above this DPI (default: 0)
 /// * `compress_streams` - Compress PDF streams (default: true)
+/// * `chroma_subsampling` - "auto", "444", "422" or "420" (default: "420")
+/// * `progressive` - Write progressive JPEGs (default: false)
+/// * `should_cancel` - Called between images; return true to stop with a "cancelled" error
 ///
 /// # Returns
 /// The resampled PDF as a byte array, or throws an error
@@ -84,17 +328,25 @@ pub fn resample_pdf(
     quality: Option<u8>,
     min_dpi: Option<f32>,
     compress_streams: Option<bool>,
+    chroma_subsampling: Option<String>,
+    progressive: Option<bool>,
+    should_cancel: Option<js_sys::Function>,
 ) -> Result<Vec<u8>, JsError> {
     let options = ResampleOptions {
         target_dpi: target_dpi.unwrap_or(150.0),
         quality: quality.unwrap_or(75),
         min_dpi: min_dpi.unwrap_or(0.0),
         compress_streams: compress_streams.unwrap_or(true),
-        verbose: false,
+        chroma_subsampling: parse_chroma_subsampling(chroma_subsampling)?,
+        progressive_jpeg: progressive.unwrap_or(false),
+        ..ResampleOptions::default()
     };
 
-    let (output_bytes, _result) = resample_pdf_bytes(pdf_bytes, &options)
-        .map_err(|e| JsError::new(&e.to_string()))?;
+    let cancel = cancel_check(&should_cancel);
+    let cancel = cancel.as_ref().map(|c| c as _);
+    let (output_bytes, _result) =
+        resample_pdf_bytes_with_logger(pdf_bytes, &options, cancel, console_log)
+            .map_err(|e| JsError::new(&e.to_string()))?;
 
     Ok(output_bytes)
 }
@@ -107,6 +359,9 @@ pub fn resample_pdf(
 /// * `quality` - JPEG quality 1-100 (default: 75)
 /// * `min_dpi` - Minimum DPI threshold - only resample images above this DPI (default: 0)
 /// * `compress_streams` - Compress PDF streams (default: true)
+/// * `chroma_subsampling` - "auto", "444", "422" or "420" (default: "420")
+/// * `progressive` - Write progressive JPEGs (default: false)
+/// * `should_cancel` - Called between images; return true to stop with a "cancelled" error
 ///
 /// # Returns
 /// A `ResampleResultJs` object containing the resampled PDF and statistics
@@ -117,18 +372,207 @@ pub fn resample_pdf_with_info(
     quality: Option<u8>,
     min_dpi: Option<f32>,
     compress_streams: Option<bool>,
+    chroma_subsampling: Option<String>,
+    progressive: Option<bool>,
+    should_cancel: Option<js_sys::Function>,
 ) -> Result<ResampleResultJs, JsError> {
     let options = ResampleOptions {
         target_dpi: target_dpi.unwrap_or(150.0),
         quality: quality.unwrap_or(75),
         min_dpi: min_dpi.unwrap_or(0.0),
         compress_streams: compress_streams.unwrap_or(true),
-        verbose: false,
+        chroma_subsampling: parse_chroma_subsampling(chroma_subsampling)?,
+        progressive_jpeg: progressive.unwrap_or(false),
+        ..ResampleOptions::default()
     };
 
-    // Get image info from the output PDF
-    let (output_bytes, result) = resample_pdf_bytes(pdf_bytes, &options)
-        .map_err(|e| JsError::new(&e.to_string()))?;
+    resample_with_info(pdf_bytes, &options, &should_cancel)
+}
+
+/// Options accepted by [`resample_pdf_with_options`]; every field is optional
+/// and missing ones keep the `ResampleOptions` default
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ResampleOptionsJson {
+    target_dpi: Option<f32>,
+    gray_target_dpi: Option<f32>,
+    output_scale: Option<f32>,
+    quality: Option<u8>,
+    min_dpi: Option<f32>,
+    compress_streams: Option<bool>,
+    recompress_jpeg_below_quality: Option<u8>,
+    recompress_even_if_not_smaller: Option<bool>,
+    allow_upscaling: Option<bool>,
+    area_average_above_ratio: Option<f32>,
+    max_pixels: Option<u32>,
+    max_long_edge: Option<u32>,
+    on_unknown_dpi: Option<String>,
+    sanity_max_dpi: Option<f32>,
+    min_image_bytes: Option<usize>,
+    min_image_pixels: Option<u32>,
+    min_savings_ratio: Option<f32>,
+    adaptive_quality: Option<bool>,
+    gray_quality_floor: Option<u8>,
+    jpeg_source_quality_bonus: Option<u8>,
+    chroma_subsampling: Option<String>,
+    progressive_jpeg: Option<bool>,
+    optimize_huffman: Option<bool>,
+    embed_srgb: Option<bool>,
+    linearize: Option<bool>,
+    password: Option<String>,
+    use_object_streams: Option<bool>,
+    output_version: Option<String>,
+    split_multiuse_images: Option<bool>,
+    preserve_metadata: Option<bool>,
+    gc: Option<bool>,
+    thumbnails: Option<String>,
+    detect_grayscale: Option<bool>,
+    force_color_space: Option<String>,
+    drop_hidden_oc: Option<bool>,
+    flatten_alpha: Option<[u8; 3]>,
+    max_form_depth: Option<usize>,
+    max_decompressed_bytes: Option<usize>,
+    collect_per_image: Option<bool>,
+    verbose: Option<bool>,
+}
+
+impl ResampleOptionsJson {
+    fn into_options(self) -> Result<ResampleOptions, JsError> {
+        let defaults = ResampleOptions::default();
+        let on_unknown_dpi = match self.on_unknown_dpi {
+            Some(policy) => policy.parse::<UnknownDpiPolicy>().map_err(|e| JsError::new(&e))?,
+            None => defaults.on_unknown_dpi,
+        };
+        let thumbnails = match self.thumbnails {
+            Some(policy) => policy.parse::<ThumbPolicy>().map_err(|e| JsError::new(&e))?,
+            None => defaults.thumbnails,
+        };
+        let force_color_space = match self.force_color_space {
+            Some(cs) => Some(cs.parse::<ForcedCs>().map_err(|e| JsError::new(&e))?),
+            None => defaults.force_color_space,
+        };
+        Ok(ResampleOptions {
+            target_dpi: self.target_dpi.unwrap_or(defaults.target_dpi),
+            gray_target_dpi: self.gray_target_dpi.or(defaults.gray_target_dpi),
+            output_scale: self.output_scale.unwrap_or(defaults.output_scale),
+            quality: self.quality.unwrap_or(defaults.quality),
+            min_dpi: self.min_dpi.unwrap_or(defaults.min_dpi),
+            compress_streams: self.compress_streams.unwrap_or(defaults.compress_streams),
+            recompress_jpeg_below_quality: self
+                .recompress_jpeg_below_quality
+                .or(defaults.recompress_jpeg_below_quality),
+            recompress_even_if_not_smaller: self
+                .recompress_even_if_not_smaller
+                .unwrap_or(defaults.recompress_even_if_not_smaller),
+            allow_upscaling: self.allow_upscaling.unwrap_or(defaults.allow_upscaling),
+            // 0 disables the area-average pass, as on the command line
+            area_average_above_ratio: match self.area_average_above_ratio {
+                Some(ratio) => (ratio > 0.0).then_some(ratio),
+                None => defaults.area_average_above_ratio,
+            },
+            max_pixels: self.max_pixels.or(defaults.max_pixels),
+            max_long_edge: self.max_long_edge.or(defaults.max_long_edge),
+            on_unknown_dpi,
+            // 0 disables the check, as on the command line
+            sanity_max_dpi: match self.sanity_max_dpi {
+                Some(dpi) => (dpi > 0.0).then_some(dpi),
+                None => defaults.sanity_max_dpi,
+            },
+            min_image_bytes: self.min_image_bytes.or(defaults.min_image_bytes),
+            min_image_pixels: self.min_image_pixels.or(defaults.min_image_pixels),
+            min_savings_ratio: self.min_savings_ratio.unwrap_or(defaults.min_savings_ratio),
+            adaptive_quality: self.adaptive_quality.unwrap_or(defaults.adaptive_quality),
+            gray_quality_floor: self.gray_quality_floor.unwrap_or(defaults.gray_quality_floor),
+            jpeg_source_quality_bonus: self
+                .jpeg_source_quality_bonus
+                .unwrap_or(defaults.jpeg_source_quality_bonus),
+            chroma_subsampling: parse_chroma_subsampling(self.chroma_subsampling)?,
+            progressive_jpeg: self.progressive_jpeg.unwrap_or(defaults.progressive_jpeg),
+            optimize_huffman: self.optimize_huffman.unwrap_or(defaults.optimize_huffman),
+            embed_srgb: self.embed_srgb.unwrap_or(defaults.embed_srgb),
+            linearize: self.linearize.unwrap_or(defaults.linearize),
+            password: self.password.or(defaults.password),
+            use_object_streams: self.use_object_streams.unwrap_or(defaults.use_object_streams),
+            output_version: self.output_version.or(defaults.output_version),
+            split_multiuse_images: self
+                .split_multiuse_images
+                .unwrap_or(defaults.split_multiuse_images),
+            preserve_metadata: self.preserve_metadata.unwrap_or(defaults.preserve_metadata),
+            gc: self.gc.unwrap_or(defaults.gc),
+            thumbnails,
+            detect_grayscale: self.detect_grayscale.unwrap_or(defaults.detect_grayscale),
+            force_color_space,
+            drop_hidden_oc: self.drop_hidden_oc.unwrap_or(defaults.drop_hidden_oc),
+            flatten_alpha: self.flatten_alpha.or(defaults.flatten_alpha),
+            max_form_depth: self.max_form_depth.unwrap_or(defaults.max_form_depth),
+            max_decompressed_bytes: self
+                .max_decompressed_bytes
+                .unwrap_or(defaults.max_decompressed_bytes),
+            collect_per_image: self.collect_per_image.unwrap_or(defaults.collect_per_image),
+            verbose: self.verbose.unwrap_or(defaults.verbose),
+        })
+    }
+}
+
+/// Resample images in a PDF with options given as a JSON object
+///
+/// Unlike the positional functions, new options become available here without
+/// changing the signature.
+///
+/// # Arguments
+/// * `pdf_bytes` - The input PDF file as a byte array
+/// * `options_json` - JSON object with any of these fields; omitted fields keep
+///   their defaults and unknown fields are an error:
+///   - `target_dpi` (number, 150), `gray_target_dpi` (number, same as `target_dpi`)
+///   - `output_scale` (number, 1)
+///   - `quality` (1-100, 75), `min_dpi` (number, 0)
+///   - `compress_streams` (bool, true)
+///   - `recompress_jpeg_below_quality` (1-100, none), `recompress_even_if_not_smaller` (bool, false)
+///   - `allow_upscaling` (bool, false), `area_average_above_ratio` (number, 4; 0 disables)
+///   - `max_pixels` (integer, none), `max_long_edge` (integer, none)
+///   - `on_unknown_dpi` ("assume-low", "skip" or "assume-target"; "assume-low")
+///   - `sanity_max_dpi` (number, 9600; 0 disables), `max_form_depth` (integer, 32)
+///   - `max_decompressed_bytes` (integer, 536870912)
+///   - `min_image_bytes` (integer, none), `min_image_pixels` (integer, none)
+///   - `min_savings_ratio` (0-1, 0)
+///   - `adaptive_quality` (bool, false), `gray_quality_floor` (1-100, 60)
+///   - `jpeg_source_quality_bonus` (0-100, 0)
+///   - `chroma_subsampling` ("auto", "444", "422" or "420"; "420")
+///   - `progressive_jpeg`, `optimize_huffman`, `embed_srgb`, `linearize`, `use_object_streams`,
+///     `split_multiuse_images`, `detect_grayscale`, `drop_hidden_oc`, `verbose` (bool, false)
+///   - `collect_per_image` (bool, false), fills `per_image_json` in the result
+///   - `preserve_metadata`, `gc` (bool, true)
+///   - `thumbnails` ("keep", "remove" or "regenerate"; "keep")
+///   - `flatten_alpha` ([r, g, b] background, none)
+///   - `force_color_space` ("rgb" or "gray", none)
+///   - `output_version` ("1.0" to "1.7" or "2.0", none)
+///   - `password` (string, none)
+/// * `should_cancel` - Called between images; return true to stop with a "cancelled" error
+///
+/// # Returns
+/// A `ResampleResultJs` object containing the resampled PDF and statistics
+#[wasm_bindgen]
+pub fn resample_pdf_with_options(
+    pdf_bytes: &[u8],
+    options_json: &str,
+    should_cancel: Option<js_sys::Function>,
+) -> Result<ResampleResultJs, JsError> {
+    let options: ResampleOptionsJson = serde_json::from_str(options_json)
+        .map_err(|e| JsError::new(&format!("Invalid options: {}", e)))?;
+    resample_with_info(pdf_bytes, &options.into_options()?, &should_cancel)
+}
+
+/// Resample and collect statistics and output image info for JS
+fn resample_with_info(
+    pdf_bytes: &[u8],
+    options: &ResampleOptions,
+    should_cancel: &Option<js_sys::Function>,
+) -> Result<ResampleResultJs, JsError> {
+    let cancel = cancel_check(should_cancel);
+    let cancel = cancel.as_ref().map(|c| c as _);
+    let (output_bytes, result) =
+        resample_pdf_bytes_with_logger(pdf_bytes, options, cancel, console_log)
+            .map_err(|e| JsError::new(&e.to_string()))?;
 
     // Extract image info from the output PDF
     let page_images = extract_pdf_images_info(&output_bytes)
@@ -137,39 +581,23 @@ pub fn resample_pdf_with_info(
     // Convert to JS-friendly format
     let image_info_json = serde_json::to_string(&page_images_to_json(&page_images))
         .unwrap_or_else(|_| "[]".to_string());
+    let skip_reasons_json = skip_reasons_to_json(&result.skip_reasons).to_string();
+    let per_image_json =
+        serde_json::to_string(&result.per_image).unwrap_or_else(|_| "[]".to_string());
 
     Ok(ResampleResultJs {
         pdf_bytes: output_bytes,
         total_images: result.total_images,
         resampled_images: result.resampled_images,
         skipped_images: result.skipped_images,
+        failed_images: result.failed_images,
+        dropped_images: result.dropped_images,
         image_info_json,
+        skip_reasons_json,
+        per_image_json,
     })
 }
 
-/// Convert page images to a JSON-serializable structure
-fn page_images_to_json(pages: &[crate::PageImages]) -> Vec<serde_json::Value> {
-    pages.iter().map(|page| {
-        serde_json::json!({
-            "page": page.page_number,
-            "images": page.images.iter().map(|img| {
-                serde_json::json!({
-                    "objectId": format!("{} {}", img.object_id.0, img.object_id.1),
-                    "type": img.image_type,
-                    "width": img.width,
-                    "height": img.height,
-                    "colorSpace": img.color_space,
-                    "bpc": img.bits_per_component,
-                    "filter": img.filter,
-                    "size": img.size_bytes,
-                    "dpiX": img.dpi_x,
-                    "dpiY": img.dpi_y
-                })
-            }).collect::<Vec<_>>()
-        })
-    }).collect()
-}
-
 /// Result of PDF resampling operation with statistics
 #[wasm_bindgen]
 pub struct ResampleResultJs {
@@ -177,7 +605,11 @@ pub struct ResampleResultJs {
     total_images: usize,
     resampled_images: usize,
     skipped_images: usize,
+    failed_images: usize,
+    dropped_images: usize,
     image_info_json: String,
+    skip_reasons_json: String,
+    per_image_json: String,
 }
 
 #[wasm_bindgen]
@@ -206,9 +638,34 @@ impl ResampleResultJs {
         self.skipped_images
     }
 
+    /// Get the number of images left unchanged because re-encoding failed
+    #[wasm_bindgen(getter)]
+    pub fn failed_images(&self) -> usize {
+        self.failed_images
+    }
+
+    /// Get the number of images in hidden layers replaced by a placeholder
+    #[wasm_bindgen(getter)]
+    pub fn dropped_images(&self) -> usize {
+        self.dropped_images
+    }
+
     /// Get detailed image information as JSON string
     #[wasm_bindgen(getter)]
     pub fn image_info_json(&self) -> String {
         self.image_info_json.clone()
     }
+
+    /// Get the number of skipped images per reason as a JSON object string
+    #[wasm_bindgen(getter)]
+    pub fn skip_reasons_json(&self) -> String {
+        self.skip_reasons_json.clone()
+    }
+
+    /// Get what happened to each image as a JSON array string; empty unless
+    /// the `collect_per_image` option is set
+    #[wasm_bindgen(getter)]
+    pub fn per_image_json(&self) -> String {
+        self.per_image_json.clone()
+    }
 }